@@ -0,0 +1,209 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use rusqlite::types::Value;
+
+use super::filter::Expression;
+use super::processor::{quote_identifier, Processor, QueryResult};
+use super::{BYTES_SENT, STATUS_TYPE};
+
+// The metrics an alert can refer to. They are computed over the records that arrived since the
+// previous check.
+const METRICS: &[&str] = &[
+    "count",
+    "req_per_sec",
+    "avg_bytes_sent",
+    "2xx",
+    "3xx",
+    "4xx",
+    "5xx",
+    "2xx_rate",
+    "3xx_rate",
+    "4xx_rate",
+    "5xx_rate",
+];
+
+/// A rule such as `5xx_rate > 0.05` or `count < 10` that is checked each interval while tailing.
+/// It is written the same way as --where using the metrics above.
+#[derive(Debug)]
+pub struct Alert {
+    rule: String,
+    expression: Expression,
+}
+
+impl FromStr for Alert {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expression = s.parse::<Expression>()?;
+        for field in expression.fields() {
+            if !METRICS.contains(&field.to_ascii_lowercase().as_str()) {
+                return Err(anyhow!(
+                    "unknown metric {} in alert, expected one of {}",
+                    field,
+                    METRICS.join(", ")
+                ));
+            }
+        }
+
+        Ok(Alert {
+            rule: s.to_string(),
+            expression,
+        })
+    }
+}
+
+/// The fields that need to be stored to compute the metrics.
+pub(crate) const FIELDS: &[&str] = &[STATUS_TYPE, BYTES_SENT];
+
+// How many groups are listed as the top offenders of an alert.
+const TOP_OFFENDERS: u64 = 5;
+
+/// Checks the alerts against the records that were added since it last looked.
+pub(crate) struct Monitor<'a> {
+    alerts: &'a [Alert],
+    interval: u64,
+    first_row: i64,
+    last_row: i64,
+    firing: Vec<bool>,
+    started_firing: bool,
+}
+
+impl<'a> Monitor<'a> {
+    pub(crate) fn new(alerts: &'a [Alert], interval: u64) -> Monitor<'a> {
+        Monitor {
+            alerts,
+            interval,
+            first_row: 0,
+            last_row: 0,
+            firing: vec![false; alerts.len()],
+            started_firing: false,
+        }
+    }
+
+    /// Start again from the first record after the stored ones were removed, keeping which alerts
+    /// are firing.
+    pub(crate) fn reset(&mut self) {
+        self.first_row = 0;
+        self.last_row = 0;
+    }
+
+    /// Return a message for every alert that was triggered by the newest records.
+    pub(crate) fn check(&mut self, processor: &Processor) -> Result<Vec<String>> {
+        if self.alerts.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let query = format!(
+            "SELECT MAX(rowid) AS last_row,
+COUNT(1) AS count,
+COUNT(1) * 1.0 / {interval} AS req_per_sec,
+AVG({bytes_sent}) AS avg_bytes_sent,
+COUNT(CASE WHEN {status_type} = 2 THEN 1 END) AS '2xx',
+COUNT(CASE WHEN {status_type} = 3 THEN 1 END) AS '3xx',
+COUNT(CASE WHEN {status_type} = 4 THEN 1 END) AS '4xx',
+COUNT(CASE WHEN {status_type} = 5 THEN 1 END) AS '5xx',
+AVG({status_type} = 2) AS '2xx_rate',
+AVG({status_type} = 3) AS '3xx_rate',
+AVG({status_type} = 4) AS '4xx_rate',
+AVG({status_type} = 5) AS '5xx_rate'
+FROM log
+WHERE rowid > {last_row}",
+            interval = self.interval.max(1),
+            bytes_sent = BYTES_SENT,
+            status_type = STATUS_TYPE,
+            last_row = self.last_row
+        );
+        let result = processor.query(&query)?;
+        let row = match result.rows.first() {
+            Some(r) => r,
+            None => return Ok(Vec::new()),
+        };
+        self.first_row = self.last_row;
+        if let Some(Value::Integer(last_row)) = row.first() {
+            self.last_row = *last_row;
+        }
+
+        let lookup = |field: &str| {
+            let field = field.to_ascii_lowercase();
+            result
+                .columns
+                .iter()
+                .position(|c| *c == field)
+                .map_or(Value::Null, |i| row[i].clone())
+        };
+        let firing = self
+            .alerts
+            .iter()
+            .map(|a| a.expression.evaluate(&lookup))
+            .collect::<Vec<bool>>();
+        self.started_firing = firing
+            .iter()
+            .zip(&self.firing)
+            .any(|(now, before)| *now && !before);
+        self.firing = firing;
+
+        Ok(self
+            .alerts
+            .iter()
+            .zip(&self.firing)
+            .filter(|(_, firing)| **firing)
+            .map(|(a, _)| {
+                let values = a
+                    .expression
+                    .fields()
+                    .iter()
+                    .map(|f| format!("{} = {}", f, format_metric(&lookup(f))))
+                    .collect::<Vec<String>>();
+                format!("ALERT {} ({})", a.rule, values.join(", "))
+            })
+            .collect())
+    }
+
+    /// Whether an alert that was not triggered by the previous check was triggered by the last.
+    pub(crate) fn started_firing(&self) -> bool {
+        self.started_firing
+    }
+
+    /// The groups with the most requests among the records of the last check.
+    pub(crate) fn offenders(&self, processor: &Processor, group_by: &str) -> Result<QueryResult> {
+        let query = format!(
+            "SELECT {group_by},
+COUNT(1) AS count,
+COUNT(CASE WHEN {status_type} = 4 THEN 1 END) AS '4XX',
+COUNT(CASE WHEN {status_type} = 5 THEN 1 END) AS '5XX'
+FROM log
+WHERE rowid > {first_row} AND rowid <= {last_row}
+GROUP BY {group_by}
+ORDER BY count DESC
+LIMIT {limit}",
+            group_by = quote_identifier(group_by),
+            status_type = STATUS_TYPE,
+            first_row = self.first_row,
+            last_row = self.last_row,
+            limit = TOP_OFFENDERS
+        );
+        processor.query(&query)
+    }
+}
+
+fn format_metric(val: &Value) -> String {
+    match val {
+        Value::Integer(i) => i.to_string(),
+        Value::Real(r) => format!("{:.4}", r),
+        _ => String::from("null"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alerts_are_parsed() {
+        assert!("5xx_rate > 0.05".parse::<Alert>().is_ok());
+        assert!("count < 10 || 5XX >= 100".parse::<Alert>().is_ok());
+        assert!("request_path == '/'".parse::<Alert>().is_err());
+        assert!("count <".parse::<Alert>().is_err());
+    }
+}
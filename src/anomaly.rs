@@ -0,0 +1,166 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use rusqlite::types::Value;
+
+use super::processor::Processor;
+use super::STATUS_TYPE;
+
+/// The fields that need to be stored to compute the metrics.
+pub(crate) const FIELDS: &[&str] = &[STATUS_TYPE];
+
+// How many of the previous intervals make up the baseline, and how many are needed before
+// anything is reported.
+const HISTORY: usize = 30;
+const MIN_HISTORY: usize = 5;
+
+// The smallest spread an error rate baseline is given, so that a steady 0% does not make a single
+// failed request stand out.
+const MIN_ERROR_RATE_DEVIATION: f64 = 0.01;
+
+// The values of a metric over the last intervals.
+#[derive(Default)]
+struct Baseline {
+    values: VecDeque<f64>,
+}
+
+impl Baseline {
+    // How many standard deviations the value is away from the mean of the earlier values, or None
+    // while there are too few of them. The standard deviation is at least the given floor.
+    fn deviation(&self, value: f64, floor: f64) -> Option<(f64, f64)> {
+        let n = self.values.len();
+        if n < MIN_HISTORY {
+            return None;
+        }
+        let mean = self.values.iter().sum::<f64>() / n as f64;
+        let variance = self.values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+        Some((mean, (value - mean) / variance.sqrt().max(floor)))
+    }
+
+    fn push(&mut self, value: f64) {
+        if self.values.len() == HISTORY {
+            self.values.pop_front();
+        }
+        self.values.push_back(value);
+    }
+}
+
+/// Compares the request rate and the error rate of the records that arrived during each interval
+/// with those of the intervals before it, announcing the ones that are unusually far off.
+pub(crate) struct Detector {
+    sigmas: f64,
+    interval: u64,
+    last_row: i64,
+    rate: Baseline,
+    error_rate: Baseline,
+}
+
+impl Detector {
+    /// Report values more than the given number of standard deviations away from the baseline.
+    pub(crate) fn new(sigmas: f64, interval: u64) -> Detector {
+        Detector {
+            sigmas,
+            interval: interval.max(1),
+            last_row: 0,
+            rate: Baseline::default(),
+            error_rate: Baseline::default(),
+        }
+    }
+
+    /// Start again from the first record after the stored ones were removed, keeping the
+    /// baseline.
+    pub(crate) fn reset(&mut self) {
+        self.last_row = 0;
+    }
+
+    /// Return a message for each metric of the newest records that deviates from its baseline,
+    /// and then add them to it.
+    pub(crate) fn check(&mut self, processor: &Processor) -> Result<Vec<String>> {
+        let query = format!(
+            "SELECT MAX(rowid), COUNT(1), AVG({status_type} = 5)
+FROM log
+WHERE rowid > {last_row}",
+            status_type = STATUS_TYPE,
+            last_row = self.last_row
+        );
+        let result = processor.query(&query)?;
+        let row = match result.rows.first() {
+            Some(r) => r,
+            None => return Ok(Vec::new()),
+        };
+        if let Value::Integer(last_row) = row[0] {
+            self.last_row = last_row;
+        }
+
+        let sigmas = self.sigmas;
+        let mut anomalies = Vec::new();
+        let mut check = |name: &str, baseline: &mut Baseline, value: f64, floor: f64| {
+            if let Some((mean, deviation)) = baseline.deviation(value, floor) {
+                if deviation.abs() > sigmas {
+                    anomalies.push(format!(
+                        "ANOMALY {} is {:.4}, {:.1} standard deviations {} its baseline of {:.4}",
+                        name,
+                        value,
+                        deviation.abs(),
+                        if deviation > 0.0 { "above" } else { "below" },
+                        mean
+                    ));
+                }
+            }
+            baseline.push(value);
+        };
+
+        let count = match row[1] {
+            Value::Integer(c) => c,
+            _ => 0,
+        };
+        // A single request more or less in an interval is never unusual.
+        let rate = count as f64 / self.interval as f64;
+        check(
+            "req_per_sec",
+            &mut self.rate,
+            rate,
+            1.0 / self.interval as f64,
+        );
+        // There is no error rate without any requests.
+        if let Value::Real(error_rate) = row[2] {
+            check(
+                "error_rate",
+                &mut self.error_rate,
+                error_rate,
+                MIN_ERROR_RATE_DEVIATION,
+            );
+        }
+
+        Ok(anomalies)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deviations_from_the_baseline() {
+        let mut baseline = Baseline::default();
+        for v in [10.0, 12.0, 11.0, 9.0] {
+            assert_eq!(baseline.deviation(100.0, 1.0), None);
+            baseline.push(v);
+        }
+        baseline.push(10.0);
+
+        let (mean, deviation) = baseline.deviation(10.5, 1.0).unwrap();
+        assert_eq!(mean, 10.4);
+        assert!(deviation.abs() < 1.0);
+        assert!(baseline.deviation(30.0, 1.0).unwrap().1 > 10.0);
+        assert!(baseline.deviation(0.0, 1.0).unwrap().1 < -5.0);
+
+        // A flat baseline uses the floor as its spread.
+        let mut flat = Baseline::default();
+        for _ in 0..HISTORY + 5 {
+            flat.push(0.0);
+        }
+        assert_eq!(flat.values.len(), HISTORY);
+        assert_eq!(flat.deviation(0.005, 0.01).unwrap().1, 0.5);
+    }
+}
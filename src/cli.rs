@@ -0,0 +1,3112 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{bounded, never, select, tick, unbounded, Receiver, Sender};
+use crossterm::cursor::SavePosition;
+use crossterm::execute;
+use crossterm::style::Stylize;
+use crossterm::terminal::{Clear, ClearType};
+use log::{debug, info};
+use rayon::prelude::*;
+use rusqlite::types::{ToSql, ToSqlOutput, Value};
+
+use super::alert::Monitor;
+use super::anomaly::Detector;
+use super::dashboard::{Action, Dashboard};
+use super::delta::Deltas;
+use super::enrich::open_geoip_databases;
+use super::export::Export;
+use super::input::{expand_access_logs, input_source, lossy_line, lossy_lines};
+use super::nginx::{
+    available_variables, detect_format, log_formats_from_conf, LogParser, Variables, AUTO,
+    ERROR_LOG, REGEX_PREFIX,
+};
+use super::processor::{
+    check_having, format_value, generate_processor, has_stored_records, quote_identifier,
+    scaled_count, status_counts, OutputFormat, Processor, QueryResult,
+};
+use super::progress::Progress;
+use super::quantile::{self, Quantiles};
+use super::record::{field_value, keep};
+use super::sketch::SpaceSaving;
+use super::statsd::Statsd;
+use super::trend::Trends;
+use super::{clickhouse, journal, repl, s3, syslog, time, webhook};
+use super::{
+    ClickHouse, Options, Ship, SubCommand, Time, ASN, AS_ORG, BOT_NAME, BYTES_SENT, CITY, COUNTRY,
+    DEFAULT_FORMAT, HOST, IS_BOT, REFERER_DOMAIN, REQUEST_PATH, REQUEST_PROTOCOL, SCAN_TYPE,
+    SOURCE_FILE, STATUS_TYPE, STDIN, TIMESTAMP, TOOL_NAME, UA_NAME,
+};
+
+// Follow a single log file sending each new line along with the file it came from.
+fn tail_reader(
+    access_log: String,
+    tx: Sender<(String, String)>,
+    stop_rx: Receiver<()>,
+) -> Result<thread::JoinHandle<Result<()>>> {
+    const SLEEP: u64 = 100;
+
+    let f = File::open(&access_log)?;
+    let stat = f.metadata()?;
+    let mut len = stat.len();
+    let mut tail_reader = BufReader::new(f);
+    tail_reader.seek(SeekFrom::Start(len))?;
+
+    Ok(thread::spawn(move || -> Result<()> {
+        loop {
+            select! {
+                recv(stop_rx) -> _ => { return Ok(()); }
+                default => {
+                    let mut bytes = Vec::new();
+                    let n_read = tail_reader.read_until(b'\n', &mut bytes)?;
+
+                    if n_read > 0 {
+                        len += n_read as u64;
+                        tail_reader.seek(SeekFrom::Start(len))?;
+                        let line = lossy_line(&bytes);
+                        debug!("tail read from {}: {}", access_log, line);
+                        tx.send((access_log.clone(), line))?;
+                    } else if tail_reader.get_ref().metadata()?.len() < len {
+                        // The file was truncated in place, as logrotate does with copytruncate, so
+                        // the new lines start at the beginning again.
+                        info!("{} was truncated, reading it from the start", access_log);
+                        len = 0;
+                        tail_reader.seek(SeekFrom::Start(len))?;
+                    } else {
+                        debug!("tail sleeping for {} milliseconds", SLEEP);
+                        thread::sleep(Duration::from_millis(SLEEP));
+                    }
+                }
+            }
+        }
+    }))
+}
+
+// The values of the fields of a single record keyed by their placeholders.
+type Record = Vec<(String, Box<dyn ToSql + Send + Sync>)>;
+
+// The fewest counters kept for approximate counting, which is also at least ten times the limit.
+const APPROXIMATE_COUNTERS: usize = 1000;
+
+// Count the --group-by value of a line towards the approximate top values, returning whether the
+// line matched the format.
+fn count_approximately(
+    opts: &Options,
+    line: &str,
+    source: &str,
+    parser: &LogParser,
+    heavy: &mut SpaceSaving,
+) -> bool {
+    let vars = match parse_line(opts, parser, line) {
+        Some(v) => v,
+        None => return false,
+    };
+    if keep(opts, &vars, source) {
+        let value = match field_value(opts, &opts.group_by, &vars, source).to_sql() {
+            Ok(ToSqlOutput::Borrowed(v)) => v.into(),
+            Ok(ToSqlOutput::Owned(v)) => v,
+            _ => Value::Null,
+        };
+        match value {
+            Value::Text(t) => heavy.insert(&t),
+            Value::Integer(i) => heavy.insert(&i.to_string()),
+            Value::Real(r) => heavy.insert(&r.to_string()),
+            _ => heavy.insert("null"),
+        }
+    }
+    true
+}
+
+// The most frequent groups counted approximately as a result that can be written like a query's.
+fn approximate_result(opts: &Options, heavy: &SpaceSaving) -> QueryResult {
+    QueryResult {
+        columns: vec![
+            opts.group_by.clone(),
+            String::from("count"),
+            String::from("max_error"),
+        ],
+        rows: heavy
+            .top(opts.limit as usize)
+            .into_iter()
+            .map(|(value, count, error)| {
+                vec![
+                    Value::Text(value.to_string()),
+                    Value::Integer(count as i64),
+                    Value::Integer(error as i64),
+                ]
+            })
+            .collect(),
+    }
+}
+
+fn tail(
+    opts: &Options,
+    access_logs: &[String],
+    fields: Option<Vec<String>>,
+    queries: Option<Vec<String>>,
+) -> Result<()> {
+    check_fields(opts, fields.as_deref())?;
+
+    // A format described by a header is read from the start of the first file.
+    let mut parser = LogParser::new(&opts.format)?;
+    if let Some(access_log) = access_logs.iter().find(|l| journal::unit(l).is_none()) {
+        let header = lossy_lines(BufReader::new(File::open(access_log)?))
+            .take_while(|l| l.as_ref().map_or(true, |l| l.starts_with('#')))
+            .collect::<io::Result<Vec<String>>>()?;
+        if let Some(p) = parser.with_header(&header) {
+            parser = p;
+        }
+    }
+
+    // Tables are drawn in an interactive dashboard when writing to a terminal. By default it
+    // stores every known field so that the grouping can be changed on the fly.
+    let mut dashboard = None;
+    if opts.output == OutputFormat::Table
+        && atty::is(atty::Stream::Stdout)
+        && !opts.approximate
+        && opts.output_file.is_none()
+    {
+        dashboard = Some(Dashboard::new(opts, queries.clone())?);
+    } else if opts.output_file.is_none() && opts.output != OutputFormat::Influx {
+        // Save our cursor position.
+        execute!(io::stdout(), SavePosition)?;
+    }
+    let keys = dashboard.as_ref().map_or_else(never, Dashboard::keys);
+    let fields = match (&dashboard, fields, parser.columns()) {
+        (Some(_), None, Some(columns)) => {
+            let mut fields = vec![
+                String::from(STATUS_TYPE),
+                String::from(BYTES_SENT),
+                opts.group_by.clone(),
+            ];
+            for c in columns {
+                if !fields.contains(&c) {
+                    fields.push(c);
+                }
+            }
+            if opts.syslog && !fields.iter().any(|f| f == "hostname") {
+                fields.push(String::from("hostname"));
+            }
+            Some(fields)
+        }
+        (_, fields, _) => fields,
+    };
+
+    // The groups of the default detailed query get a trend column when shown as a table, and
+    // the rows of both default queries get the counts of the last interval.
+    let (mut trends, mut deltas) = match (&queries, opts.output) {
+        (None, OutputFormat::Table | OutputFormat::Vertical) if !opts.approximate => {
+            (Some(Trends::default()), Some(Deltas::default()))
+        }
+        _ => (None, None),
+    };
+    // As do the percentiles of their request times when the format logs them.
+    let mut quantiles = match &dashboard {
+        None if quantile::enabled(opts, queries.is_some())? => Some(Quantiles::default()),
+        _ => None,
+    };
+    let mut processor = generate_processor(opts, fields, queries)?;
+    let (tx, rx) = unbounded();
+    let ticker = tick(Duration::from_secs(opts.interval));
+
+    // The interrupt handling plumbing. Dropping the sending half of the stop channel tells every
+    // reading thread to finish.
+    let (stop_tx, stop_rx) = bounded::<()>(0);
+    let running = Arc::new(AtomicBool::new(true));
+    let handler_r = Arc::clone(&running);
+
+    ctrlc::set_handler(move || {
+        handler_r.store(false, Ordering::SeqCst);
+    })?;
+
+    // Spawn a reading thread for each of the files.
+    let mut reader_handles = access_logs
+        .iter()
+        .map(|l| match journal::unit(l) {
+            Some(unit) => journal::follow(unit, tx.clone(), stop_rx.clone()),
+            None => tail_reader(l.clone(), tx.clone(), stop_rx.clone()),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(addr) = opts.listen_syslog {
+        reader_handles.extend(syslog::listen(addr, tx.clone(), stop_rx.clone())?);
+    }
+    drop(tx);
+
+    let mut monitor = Monitor::new(&opts.alert, opts.interval);
+    let mut detector = opts
+        .anomaly
+        .map(|sigmas| Detector::new(sigmas, opts.interval));
+    let mut statsd = match &opts.statsd {
+        Some(addr) => Some(Statsd::connect(
+            addr,
+            &opts.statsd_prefix,
+            processor.fields.iter().any(|f| f == "request_time"),
+        )?),
+        None => None,
+    };
+    let mut failure = None;
+    let mut notifications = Vec::new();
+    let mut lines = Vec::new();
+    let mut heavy = opts
+        .approximate
+        .then(|| SpaceSaving::new(APPROXIMATE_COUNTERS.max(opts.limit as usize * 10)));
+    while running.load(Ordering::SeqCst) {
+        select! {
+            recv(rx) -> line => {
+                // Every reader has finished, for example because journalctl exited, and any
+                // error it had is returned when it is joined below.
+                let (access_log, line) = match line {
+                    Ok(l) => l,
+                    Err(_) => break,
+                };
+                lines.push(line);
+                let unmatched = match heavy.as_mut() {
+                    Some(h) => !count_approximately(opts, &lines[0], &access_log, &parser, h),
+                    None => !parse_input(opts, &lines, &access_log, &parser, &processor)?.is_empty(),
+                };
+                if unmatched && opts.strict {
+                    failure = Some(unmatched_error(&access_log, None, &lines[0], &parser));
+                    break;
+                } else if unmatched {
+                    debug!("{} did not match the format: {}", access_log, lines[0]);
+                }
+                lines.clear();
+            }
+            recv(ticker) -> _ => {
+                processor.spill_to_disk()?;
+                if let Some(window) = opts.window {
+                    let cutoff = SystemTime::now().duration_since(UNIX_EPOCH)? - window;
+                    let deleted = processor.delete_before(cutoff.as_secs() as i64)?;
+                    debug!("removed {} records outside of the window", deleted);
+                    if deleted > 0 {
+                        if let Some(q) = quantiles.as_mut() {
+                            q.reset();
+                        }
+                        if let Some(d) = dashboard.as_mut() {
+                            d.records_removed();
+                        }
+                        restart_checks(&processor, &mut monitor, statsd.as_mut(), detector.as_mut())?;
+                    }
+                }
+                let mut alerts = monitor.check(&processor)?;
+                let firing = !alerts.is_empty();
+                if let Some(d) = detector.as_mut() {
+                    alerts.extend(d.check(&processor)?);
+                }
+                if let Some(s) = statsd.as_mut() {
+                    s.send(&processor)?;
+                }
+                if let Some(url) = opts.alert_webhook.as_deref().filter(|_| monitor.started_firing()) {
+                    let offenders = monitor.offenders(&processor, &opts.group_by)?;
+                    notifications.push(webhook::notify(url, &alerts, &offenders));
+                }
+                match dashboard.as_mut() {
+                    Some(d) => {
+                        d.set_alerts(alerts.clone());
+                        d.draw(opts, &processor, true)?;
+                    }
+                    None => {
+                        if opts.output_file.is_none() && opts.output != OutputFormat::Influx {
+                            execute!(io::stdout(), Clear(ClearType::All))?;
+                        }
+                        // Alerts go to standard error so that they do not end up in JSON output.
+                        for alert in &alerts {
+                            eprintln!("{}", alert.as_str().red().bold());
+                        }
+                        let mut results = match heavy.as_ref() {
+                            Some(h) => vec![approximate_result(opts, h)],
+                            None => processor.results()?,
+                        };
+                        if let Some(d) = deltas.as_mut() {
+                            d.add_columns(&mut results, true);
+                        }
+                        if let (Some(q), Some(last)) = (quantiles.as_mut(), results.last_mut()) {
+                            q.add_columns(&processor, last)?;
+                        }
+                        if let (Some(t), Some(last)) = (trends.as_mut(), results.last_mut()) {
+                            t.add_column(last, true);
+                        }
+                        processor.write(&results, opts.follow)?;
+                    }
+                }
+                if opts.alert_exit && firing {
+                    failure = Some(anyhow!("{}", alerts.join("\n")));
+                    break;
+                }
+            }
+            recv(keys) -> key => {
+                if let Some(d) = dashboard.as_mut() {
+                    match d.handle_key(key?, &processor.fields) {
+                        Action::Quit => break,
+                        Action::Redraw => d.draw(opts, &processor, false)?,
+                        Action::Reset => {
+                            let deleted = processor.clear()?;
+                            debug!("reset the dashboard, removing {} records", deleted);
+                            // Alerts, anomalies and StatsD only look at rows numbered after
+                            // the last one they saw.
+                            monitor = Monitor::new(&opts.alert, opts.interval);
+                            if let Some(s) = statsd.as_mut() {
+                                s.reset();
+                            }
+                            if let Some(d) = detector.as_mut() {
+                                d.reset();
+                            }
+                            d.draw(opts, &processor, false)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Give the terminal back before anything else is printed.
+    drop(dashboard);
+
+    // We got an interrupt, so stop the reading threads.
+    drop(stop_tx);
+
+    // The join will panic if a thread panics but otherwise it will propagate the return value up
+    // to the main thread.
+    for handle in reader_handles {
+        handle
+            .join()
+            .expect("the file reading thread should not have panicked")?;
+    }
+
+    // Let any webhook requests that are still being made finish.
+    for handle in notifications {
+        handle
+            .join()
+            .expect("the webhook thread should not have panicked");
+    }
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+// Alerts, anomalies and StatsD only look at the rows numbered after the last one they saw, so they
+// start over from the first row once every record was removed, since SQLite numbers the rows of an
+// empty table from one again.
+fn restart_checks(
+    processor: &Processor,
+    monitor: &mut Monitor,
+    statsd: Option<&mut Statsd>,
+    detector: Option<&mut Detector>,
+) -> Result<()> {
+    if !processor.is_empty()? {
+        return Ok(());
+    }
+    monitor.reset();
+    if let Some(s) = statsd {
+        s.reset();
+    }
+    if let Some(d) = detector {
+        d.reset();
+    }
+    Ok(())
+}
+
+// Show the results of parsing the sources again every interval until interrupted.
+fn watch(
+    opts: &Options,
+    access_logs: &[String],
+    fields: Option<Vec<String>>,
+    queries: Option<Vec<String>>,
+) -> Result<()> {
+    if access_logs.iter().any(|l| l == STDIN) {
+        return Err(anyhow!("cannot watch STDIN"));
+    }
+    // Parsing into the database again would store every record once more.
+    if opts.db.is_some() && !access_logs.is_empty() {
+        return Err(anyhow!(
+            "cannot watch access logs stored in --db, leave out the access logs to query the database"
+        ));
+    }
+
+    let (stop_tx, stop_rx) = bounded(1);
+    ctrlc::set_handler(move || {
+        let _ = stop_tx.try_send(());
+    })?;
+    let ticker = tick(Duration::from_secs(opts.interval));
+
+    let terminal = opts.output_file.is_none() && opts.output != OutputFormat::Influx;
+    if terminal {
+        execute!(io::stdout(), SavePosition)?;
+    }
+    loop {
+        let processor = load(opts, access_logs, fields.clone(), queries.clone())?;
+        if terminal {
+            execute!(io::stdout(), Clear(ClearType::All))?;
+        }
+        processor.report(true)?;
+
+        select! {
+            recv(stop_rx) -> _ => return Ok(()),
+            recv(ticker) -> _ => {}
+        }
+    }
+}
+
+// Gather every source of log lines that was asked for.
+fn access_logs(opts: &Options) -> Result<Vec<String>> {
+    let mut access_logs = if opts.access_log.is_empty() {
+        let stored = match &opts.db {
+            Some(db) if !opts.db_append && has_stored_records(db)? => Some(db),
+            _ => None,
+        };
+        if opts.listen_syslog.is_some() || opts.journal_unit.is_some() || !opts.s3.is_empty() {
+            vec![]
+        } else if let Some(db) = stored {
+            // Query the records a previous run stored without parsing anything new.
+            info!("only querying the records in {}", db.display());
+            vec![]
+        } else if atty::isnt(atty::Stream::Stdin) {
+            vec![String::from(STDIN)]
+        } else if let Some(db) = opts.db.as_ref().filter(|db| !db.exists()) {
+            return Err(anyhow!("the database {} does not exist", db.display()));
+        } else {
+            return Err(anyhow!("STDIN is a TTY"));
+        }
+    } else {
+        expand_access_logs(&opts.access_log)?
+    };
+    if let Some(unit) = &opts.journal_unit {
+        access_logs.push(journal::source(unit));
+    }
+    for url in &opts.s3 {
+        access_logs.append(&mut s3::list(url)?);
+    }
+    info!("access logs: {}", access_logs.join(", "));
+    info!("access log format: {}", opts.format);
+
+    Ok(access_logs)
+}
+
+fn run(opts: &Options, fields: Option<Vec<String>>, queries: Option<Vec<String>>) -> Result<()> {
+    let access_logs = access_logs(opts)?;
+
+    // We cannot tail STDIN.
+    if opts.follow && access_logs.iter().any(|l| l == STDIN) {
+        return Err(anyhow!("cannot tail STDIN"));
+    }
+    if opts.follow && access_logs.iter().any(|l| s3::is_object(l)) {
+        return Err(anyhow!("cannot tail S3 objects"));
+    }
+
+    // We need to tail the log files.
+    if opts.follow {
+        return tail(opts, &access_logs, fields, queries);
+    }
+    if opts.watch {
+        return watch(opts, &access_logs, fields, queries);
+    }
+
+    load(opts, &access_logs, fields, queries)?.report(opts.follow)
+}
+
+// The records parsed from a batch of lines of a source along with the lines that did not match.
+struct Batch {
+    lines: usize,
+    records: Vec<Record>,
+    rejected: Vec<String>,
+}
+
+// Read a source in batches, parsing each one and sending it along to be stored.
+fn read_source(
+    opts: &Options,
+    access_log: &str,
+    parser: &LogParser,
+    fields: &[String],
+    progress: &Progress,
+    tx: &Sender<Result<Batch>>,
+) -> Result<()> {
+    // How many bytes of a source are parsed at once, which bounds the memory that is used.
+    const BATCH_BYTES: u64 = 16 << 20;
+
+    // The source is read in large chunks that end at a line boundary and the lines of each chunk
+    // are parsed in parallel where they are, rather than allocating every line on its own. Files
+    // are not memory mapped instead, since that takes unsafe code and a log that is truncated
+    // while it is read, as logrotate's copytruncate does, would crash with SIGBUS rather than end
+    // early. Compressed files, STDIN, the journal and S3 need to be read this way anyhow.
+    let mut input = input_source(access_log, progress)?;
+    let mut chunk = Vec::new();
+    let mut header_parser = None;
+    let mut read = 0;
+    loop {
+        let n = input.by_ref().take(BATCH_BYTES).read_to_end(&mut chunk)?;
+        let rest = match chunk.iter().rposition(|b| *b == b'\n') {
+            _ if n == 0 => Vec::new(),
+            Some(i) => chunk.split_off(i + 1),
+            // A line longer than a chunk continues into the next one.
+            None => continue,
+        };
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        let text = String::from_utf8_lossy(&chunk);
+        let batch = text.lines().collect::<Vec<&str>>();
+
+        // A header describing the format comes at the start of the source.
+        if header_parser.is_none() {
+            let header = batch
+                .iter()
+                .take_while(|l| l.starts_with('#'))
+                .map(|l| l.to_string())
+                .collect::<Vec<String>>();
+            header_parser = Some(parser.with_header(&header));
+        }
+        let parser = header_parser
+            .as_ref()
+            .and_then(Option::as_ref)
+            .unwrap_or(parser);
+        let (records, rejected) = parse_lines(opts, &batch, access_log, parser, fields);
+        if let (true, Some(line)) = (opts.strict, rejected.first()) {
+            let number = batch.iter().position(|l| std::ptr::eq(*l, *line));
+            let number = read + number.unwrap_or(0) + 1;
+            return Err(unmatched_error(access_log, Some(number), line, parser));
+        }
+        read += batch.len();
+
+        let batch = Batch {
+            lines: batch.len(),
+            records,
+            rejected: rejected.into_iter().map(String::from).collect(),
+        };
+        // The receiver is only gone when storing the records already failed.
+        if tx.send(Ok(batch)).is_err() {
+            return Ok(());
+        }
+        chunk = rest;
+    }
+}
+
+// Make sure the fields that are stored are variables of the format, or at least plain names when
+// the variables are only known from the lines themselves, so that a typo does not end up as an
+// obscure SQL error.
+fn check_fields(opts: &Options, fields: Option<&[String]>) -> Result<()> {
+    let mut checked = fields.map_or_else(Vec::new, |f| f.iter().collect::<Vec<&String>>());
+    if fields.is_none() || !opts.alert.is_empty() {
+        checked.push(&opts.group_by);
+    }
+
+    let parser = LogParser::new(&opts.format)?;
+    let mut available = match parser.columns() {
+        Some(columns) => columns,
+        None => {
+            let invalid = checked.iter().find(|f| {
+                f.is_empty()
+                    || f.starts_with(|c: char| c.is_ascii_digit())
+                    || !f.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            });
+            return match invalid {
+                Some(f) => Err(anyhow!("invalid field name {:?}", f)),
+                None => Ok(()),
+            };
+        }
+    };
+    // The raw variables can be stored as well as the fields derived from them.
+    for variable in parser.variables().unwrap_or_default() {
+        if !available.contains(&variable) {
+            available.push(variable);
+        }
+    }
+    if !opts.geoip_db.is_empty() {
+        available.extend([COUNTRY, CITY, ASN, AS_ORG].iter().map(|f| f.to_string()));
+    }
+    if opts.syslog {
+        available.push(String::from("hostname"));
+    }
+    available.extend(opts.derive.iter().map(|d| d.name.clone()));
+
+    match checked.iter().find(|f| !available.contains(f)) {
+        Some(f) => Err(anyhow!(
+            "unknown field {}, available: {}",
+            f,
+            available.join(", ")
+        )),
+        None => Ok(()),
+    }
+}
+
+// Parse every line of the given sources returning the processor holding the records.
+fn load(
+    opts: &Options,
+    access_logs: &[String],
+    fields: Option<Vec<String>>,
+    queries: Option<Vec<String>>,
+) -> Result<Processor> {
+    // Parsing the same access logs into the database again would count every record twice.
+    if let Some(db) = &opts.db {
+        if !access_logs.is_empty() && !opts.db_append && has_stored_records(db)? {
+            return Err(anyhow!(
+                "{} already holds the records of a previous run, give --db-append to add those of the access logs to them or leave out the access logs to only query them",
+                db.display()
+            ));
+        }
+    }
+
+    // Only the records of a previous run are queried without any access logs.
+    if opts.db.is_none() || !access_logs.is_empty() {
+        check_fields(opts, fields.as_deref())?;
+    }
+
+    let parser = LogParser::new(&opts.format)?;
+    if opts.last.is_some()
+        && parser
+            .columns()
+            .is_some_and(|c| !c.iter().any(|f| f == TIMESTAMP))
+    {
+        return Err(anyhow!(
+            "the log format does not have a time variable such as $time_local"
+        ));
+    }
+    let mut processor = generate_processor(opts, fields, queries)?;
+    let progress = Progress::new(access_logs);
+    let mut dump = match &opts.dump_unmatched {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+    let (mut total, mut unmatched) = (0, 0);
+
+    // The sources are read and parsed in parallel while the records are all stored from here
+    // since there is a single connection to the database.
+    let (tx, rx) = bounded::<Result<Batch>>(rayon::current_num_threads());
+    let fields = processor.fields.clone();
+    let stored = thread::scope(|s| -> Result<()> {
+        s.spawn(|| {
+            access_logs.par_iter().for_each_with(tx, |tx, access_log| {
+                if let Err(e) = read_source(opts, access_log, &parser, &fields, &progress, tx) {
+                    let _ = tx.send(Err(e));
+                }
+            })
+        });
+
+        // Returning early drops the receiver which stops the readers.
+        for batch in rx {
+            let batch = batch?;
+            processor.process(batch.records)?;
+            processor.spill_to_disk()?;
+            if let Some(d) = dump.as_mut() {
+                for line in &batch.rejected {
+                    writeln!(d, "{}", line)?;
+                }
+            }
+            total += batch.lines;
+            unmatched += batch.rejected.len();
+            progress.parsed(batch.lines);
+        }
+        Ok(())
+    });
+    progress.finish();
+    stored?;
+    if let Some(mut d) = dump {
+        d.flush()?;
+    }
+
+    if unmatched > 0 {
+        eprintln!(
+            "{} of {} lines did not match the format",
+            thousands(unmatched),
+            thousands(total)
+        );
+    }
+    if let Some(last) = opts.last {
+        let deleted = processor.keep_last(last)?;
+        debug!(
+            "removed {} records from before the last {:?}",
+            deleted, last
+        );
+    }
+
+    Ok(processor)
+}
+
+// Parse a single line, first removing the syslog header if there is one.
+fn parse_line<'a>(opts: &Options, parser: &LogParser, line: &'a str) -> Option<Variables<'a>> {
+    if opts.syslog {
+        if let Some((hostname, message)) = syslog::strip_header(line) {
+            return parser
+                .parse(message)
+                .map(|v| Variables::Syslog(hostname, Box::new(v)));
+        }
+    }
+    parser.parse(line)
+}
+
+// Describe a line that did not match the format along with what it was expected to look like.
+fn unmatched_error(
+    source: &str,
+    number: Option<usize>,
+    line: &str,
+    parser: &LogParser,
+) -> anyhow::Error {
+    let location = match number {
+        Some(n) => format!("line {} of {}", n, source),
+        None => format!("a line of {}", source),
+    };
+    anyhow!(
+        "{} did not match the format:\n{}\nit was expected to match {}",
+        location,
+        line,
+        parser
+    )
+}
+
+// Format a count with thousands separators such as 1,234,567.
+fn thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut formatted = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(c);
+    }
+    formatted
+}
+
+// Parse the lines of a source into the records of the lines that are kept, also returning the
+// lines that did not match the format. Blank lines and the comments of a W3C log are not counted
+// as unmatched.
+fn parse_lines<'a, S: AsRef<str> + Sync>(
+    opts: &Options,
+    lines: &'a [S],
+    source: &str,
+    parser: &LogParser,
+    fields: &[String],
+) -> (Vec<Record>, Vec<&'a str>) {
+    let comments = matches!(parser, LogParser::W3c(_));
+    let parsed: Vec<_> = lines
+        .par_iter()
+        .map(AsRef::as_ref)
+        .filter(|line| !line.is_empty() && (!comments || !line.starts_with('#')))
+        .filter(|_| opts.sample.is_none_or(|rate| rand::random::<f64>() < rate))
+        .map(|line| match parse_line(opts, parser, line) {
+            Some(c) if !keep(opts, &c, source) => Ok(None),
+            None => Err(line),
+            Some(c) => {
+                let mut record: Record = vec![];
+
+                for field in fields {
+                    record.push((format!(":{}", field), field_value(opts, field, &c, source)));
+                }
+
+                Ok(Some(record))
+            }
+        })
+        .collect();
+
+    let mut records = Vec::with_capacity(parsed.len());
+    let mut unmatched = Vec::new();
+    for p in parsed {
+        match p {
+            Ok(Some(record)) => records.push(record),
+            Ok(None) => {}
+            Err(line) => unmatched.push(line),
+        }
+    }
+
+    (records, unmatched)
+}
+
+// Store the records of the lines that are kept returning the lines that did not match the format.
+fn parse_input<'a>(
+    opts: &Options,
+    lines: &'a [String],
+    source: &str,
+    parser: &LogParser,
+    processor: &Processor,
+) -> Result<Vec<&'a str>> {
+    let (records, unmatched) = parse_lines(opts, lines, source, parser, &processor.fields);
+    processor.process(records)?;
+
+    Ok(unmatched)
+}
+
+// The time of each record as the wall clock time in the zone given with --timezone, so that time
+// buckets start on its hours and days and datetime() shows the time there.
+fn local_ts(opts: &Options) -> String {
+    match opts.timezone {
+        Some(_) => format!("({ts} + utc_offset({ts}))", ts = TIMESTAMP),
+        None => String::from(TIMESTAMP),
+    }
+}
+
+// The width of a time bucket in whole seconds.
+fn bucket_seconds(bucket: Duration) -> Result<u64> {
+    match bucket.as_secs() {
+        0 => Err(anyhow!("the bucket must be at least one second")),
+        seconds => Ok(seconds),
+    }
+}
+
+// Summarize every bucket of time that has records with the given columns following the start of
+// the bucket. The query is not terminated so that it can be wrapped in another one.
+fn bucketed_query(opts: &Options, bucket: u64, columns: &str) -> String {
+    format!(
+        "SELECT datetime(({ts} / {bucket}) * {bucket}, 'unixepoch') AS bucket,
+{columns}
+FROM log
+WHERE {ts} IS NOT NULL
+GROUP BY {ts} / {bucket}
+ORDER BY bucket",
+        ts = local_ts(opts),
+        bucket = bucket,
+        columns = columns
+    )
+}
+
+fn apdex_subcommand(opts: &Options, target: f64) -> Result<()> {
+    let (fields, queries) = apdex_queries(opts, target)?;
+    run(opts, Some(fields), Some(queries))
+}
+
+// The fields and queries rating the requests against the target request time, overall and for
+// each group.
+fn apdex_queries(opts: &Options, target: f64) -> Result<(Vec<String>, Vec<String>)> {
+    if !target.is_finite() || target <= 0.0 {
+        return Err(anyhow!("the target must be a positive number of seconds"));
+    }
+    let columns = LogParser::new(&opts.format)?.columns();
+    let has = |c: &str| columns.as_ref().is_none_or(|cs| cs.iter().any(|f| f == c));
+    if !has("request_time") {
+        return Err(anyhow!("the log format does not have $request_time"));
+    }
+
+    let mut fields = vec![String::from("request_time")];
+    if opts.group_by != "request_time" {
+        fields.push(opts.group_by.clone());
+    }
+    let failed = if has(STATUS_TYPE) {
+        if opts.group_by != STATUS_TYPE {
+            fields.push(String::from(STATUS_TYPE));
+        }
+        "status_type = 5 OR "
+    } else {
+        ""
+    };
+    // Each request is rated as satisfied (0), tolerating (1) or frustrated (2), and the score is
+    // the satisfied requests plus half of the tolerating ones over all of them.
+    let rated = format!(
+        "WITH rated AS (
+SELECT {group_by},
+CASE WHEN {failed}request_time > {frustrated} THEN 2 WHEN request_time > {target} THEN 1 ELSE 0 END AS rating
+FROM log
+WHERE request_time IS NOT NULL
+)",
+        group_by = quote_identifier(&opts.group_by),
+        failed = failed,
+        frustrated = target * 4.0,
+        target = target
+    );
+    let scores = "COUNT(1) AS count,
+COUNT(CASE WHEN rating = 0 THEN 1 END) AS satisfied,
+COUNT(CASE WHEN rating = 1 THEN 1 END) AS tolerating,
+COUNT(CASE WHEN rating = 2 THEN 1 END) AS frustrated,
+ROUND((COUNT(CASE WHEN rating = 0 THEN 1 END) + COUNT(CASE WHEN rating = 1 THEN 1 END) / 2.0) / COUNT(1), 3) AS apdex";
+    let overall_query = format!(
+        "{rated}
+SELECT {scores}
+FROM rated;",
+        rated = rated,
+        scores = scores
+    );
+    let groups_query = format!(
+        "{rated}
+SELECT {group_by},
+{scores}
+FROM rated
+GROUP BY {group_by}
+ORDER BY count DESC
+LIMIT {limit};",
+        rated = rated,
+        group_by = quote_identifier(&opts.group_by),
+        scores = scores,
+        limit = opts.limit
+    );
+    debug!(
+        "apdex sub command queries: {} {}",
+        overall_query, groups_query
+    );
+    Ok((fields, vec![overall_query, groups_query]))
+}
+
+fn avg_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
+    let avg_fields: Vec<String> = fields
+        .iter()
+        .map(|f| {
+            format!(
+                "AVG({f}) AS {name}",
+                f = quote_identifier(f),
+                name = quote_identifier(&format!("AVG({})", f))
+            )
+        })
+        .collect();
+    let selections = avg_fields.join(", ");
+    let query = format!("SELECT {selections} FROM log", selections = selections);
+    debug!("average sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn bandwidth_subcommand(opts: &Options, bucket: Duration, raw: bool) -> Result<()> {
+    let (fields, queries) = bandwidth_queries(opts, bucket, raw)?;
+    run(opts, Some(fields), Some(queries))
+}
+
+// The fields and queries reporting the bytes sent overall, in each bucket of time and for each
+// group, as raw numbers or as human readable sizes.
+fn bandwidth_queries(
+    opts: &Options,
+    bucket: Duration,
+    raw: bool,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let bucket = bucket_seconds(bucket)?;
+    let columns = LogParser::new(&opts.format)?.columns();
+    let has = |c: &str| columns.as_ref().is_none_or(|cs| cs.iter().any(|f| f == c));
+    let bytes = |expr: &str| {
+        if raw {
+            expr.to_string()
+        } else {
+            format!("human_bytes({})", expr)
+        }
+    };
+
+    let mut fields = vec![String::from(BYTES_SENT), opts.group_by.clone()];
+    let mut queries = vec![format!(
+        "SELECT COUNT(1) AS count,
+{total} AS bytes_sent,
+{avg} AS avg_bytes_sent
+FROM log;",
+        total = bytes("IFNULL(SUM(bytes_sent), 0)"),
+        avg = bytes("AVG(bytes_sent)")
+    )];
+    if has(TIMESTAMP) {
+        fields.push(String::from(TIMESTAMP));
+        let columns = format!(
+            "COUNT(1) AS count,
+{total} AS bytes_sent,
+{rate} AS bytes_per_sec",
+            total = bytes("IFNULL(SUM(bytes_sent), 0)"),
+            rate = bytes(&format!("TOTAL(bytes_sent) / {}", bucket))
+        );
+        queries.push(format!("{};", bucketed_query(opts, bucket, &columns)));
+    }
+    queries.push(format!(
+        "SELECT {group_by},
+COUNT(1) AS count,
+{total} AS bytes_sent,
+{avg} AS avg_bytes_sent,
+ROUND(100.0 * TOTAL(bytes_sent) / (SELECT TOTAL(bytes_sent) FROM log), 2) AS pct_of_bytes
+FROM log
+GROUP BY {group_by}
+ORDER BY TOTAL(bytes_sent) DESC
+LIMIT {limit};",
+        group_by = quote_identifier(&opts.group_by),
+        total = bytes("IFNULL(SUM(bytes_sent), 0)"),
+        avg = bytes("AVG(bytes_sent)"),
+        limit = opts.limit
+    ));
+    debug!("bandwidth sub command queries: {}", queries.join(" "));
+
+    Ok((fields, queries))
+}
+
+fn bots_subcommand(opts: &Options) -> Result<()> {
+    let summary_query = format!(
+        "SELECT CASE WHEN {is_bot} THEN 'bot' WHEN {tool_name} IS NOT NULL THEN 'tool' ELSE 'human' END AS traffic,
+COUNT(1) AS count,
+ROUND(100.0 * COUNT(1) / (SELECT COUNT(1) FROM log), 2) AS percent,
+SUM(bytes_sent) AS bytes_sent
+FROM log
+GROUP BY traffic
+ORDER BY count DESC;",
+        is_bot = IS_BOT,
+        tool_name = TOOL_NAME
+    );
+    let crawlers_query = format!(
+        "SELECT {bot_name}, COUNT(1) AS count, SUM(bytes_sent) AS bytes_sent
+FROM log
+WHERE {is_bot}
+GROUP BY {bot_name}
+ORDER BY count DESC
+LIMIT {limit};",
+        bot_name = BOT_NAME,
+        is_bot = IS_BOT,
+        limit = opts.limit
+    );
+    let tools_query = format!(
+        "SELECT {tool_name}, COUNT(1) AS count, SUM(bytes_sent) AS bytes_sent
+FROM log
+WHERE NOT {is_bot} AND {tool_name} IS NOT NULL
+GROUP BY {tool_name}
+ORDER BY count DESC
+LIMIT {limit};",
+        tool_name = TOOL_NAME,
+        is_bot = IS_BOT,
+        limit = opts.limit
+    );
+    debug!(
+        "bots sub command queries: {} {} {}",
+        summary_query, crawlers_query, tools_query
+    );
+
+    let fields = vec![
+        String::from(IS_BOT),
+        String::from(BOT_NAME),
+        String::from(TOOL_NAME),
+        String::from(BYTES_SENT),
+    ];
+    run(
+        opts,
+        Some(fields),
+        Some(vec![summary_query, crawlers_query, tools_query]),
+    )
+}
+
+// The cache statuses NGINX answers from the cache with. The others went to the upstream.
+const CACHE_SERVED: &str = "('HIT', 'STALE', 'UPDATING', 'REVALIDATED')";
+
+fn cache_subcommand(opts: &Options) -> Result<()> {
+    let (fields, queries) = cache_queries(opts)?;
+    run(opts, Some(fields), Some(queries))
+}
+
+// The fields and queries breaking the proxied requests down by their cache status.
+fn cache_queries(opts: &Options) -> Result<(Vec<String>, Vec<String>)> {
+    let columns = LogParser::new(&opts.format)?.columns();
+    if columns.is_some_and(|cs| !cs.iter().any(|c| c == "upstream_cache_status")) {
+        return Err(anyhow!(
+            "the log format does not have $upstream_cache_status"
+        ));
+    }
+
+    // Requests that were not proxied have no cache status.
+    let cached = "upstream_cache_status IS NOT NULL AND upstream_cache_status NOT IN ('', '-')";
+    let summary_query = format!(
+        "SELECT COUNT(1) AS count,
+ROUND(100.0 * AVG(upstream_cache_status IN {served}), 2) AS hit_percent,
+IFNULL(SUM(CASE WHEN upstream_cache_status IN {served} THEN bytes_sent END), 0) AS cache_bytes,
+IFNULL(SUM(CASE WHEN upstream_cache_status NOT IN {served} THEN bytes_sent END), 0) AS upstream_bytes
+FROM log
+WHERE {cached};",
+        served = CACHE_SERVED,
+        cached = cached
+    );
+    let statuses_query = format!(
+        "SELECT upstream_cache_status,
+COUNT(1) AS count,
+ROUND(100.0 * COUNT(1) / (SELECT COUNT(1) FROM log WHERE {cached}), 2) AS percent,
+SUM(bytes_sent) AS bytes_sent
+FROM log
+WHERE {cached}
+GROUP BY upstream_cache_status
+ORDER BY count DESC;",
+        cached = cached
+    );
+    let paths_query = format!(
+        "SELECT {request_path},
+COUNT(1) AS count,
+ROUND(100.0 * AVG(upstream_cache_status IN {served}), 2) AS hit_percent,
+COUNT(CASE WHEN upstream_cache_status = 'HIT' THEN 1 END) AS hit,
+COUNT(CASE WHEN upstream_cache_status = 'MISS' THEN 1 END) AS miss,
+COUNT(CASE WHEN upstream_cache_status = 'BYPASS' THEN 1 END) AS bypass,
+COUNT(CASE WHEN upstream_cache_status = 'EXPIRED' THEN 1 END) AS expired,
+IFNULL(SUM(CASE WHEN upstream_cache_status NOT IN {served} THEN bytes_sent END), 0) AS upstream_bytes
+FROM log
+WHERE {cached}
+GROUP BY {request_path}
+ORDER BY count DESC
+LIMIT {limit};",
+        request_path = REQUEST_PATH,
+        served = CACHE_SERVED,
+        cached = cached,
+        limit = opts.limit
+    );
+    debug!(
+        "cache sub command queries: {} {} {}",
+        summary_query, statuses_query, paths_query
+    );
+
+    let fields = vec![
+        String::from("upstream_cache_status"),
+        String::from(REQUEST_PATH),
+        String::from(BYTES_SENT),
+    ];
+    Ok((fields, vec![summary_query, statuses_query, paths_query]))
+}
+
+fn errors_subcommand(opts: &Options, bucket: Duration) -> Result<()> {
+    let bucket = bucket_seconds(bucket)?;
+
+    let messages_query = format!(
+        "SELECT level, message, COUNT(1) AS count
+FROM log
+GROUP BY level, message
+ORDER BY count DESC
+LIMIT {limit};",
+        limit = opts.limit
+    );
+    let upstreams_query = format!(
+        "SELECT upstream, COUNT(1) AS count
+FROM log
+WHERE upstream != ''
+GROUP BY upstream
+ORDER BY count DESC
+LIMIT {limit};",
+        limit = opts.limit
+    );
+    let columns = format!(
+        "COUNT(1) AS count,
+COUNT(CASE WHEN level = 'warn' THEN 1 END) AS warn,
+COUNT(CASE WHEN level = 'error' THEN 1 END) AS error,
+COUNT(CASE WHEN level IN ('crit', 'alert', 'emerg') THEN 1 END) AS critical,
+ROUND(COUNT(1) * 1.0 / {bucket}, 2) AS per_sec",
+        bucket = bucket
+    );
+    let rate_query = format!("{};", bucketed_query(opts, bucket, &columns));
+    debug!(
+        "errors sub command queries: {} {} {}",
+        messages_query, upstreams_query, rate_query
+    );
+
+    let fields = vec![
+        String::from("level"),
+        String::from("message"),
+        String::from("upstream"),
+        String::from(TIMESTAMP),
+    ];
+    run(
+        opts,
+        Some(fields),
+        Some(vec![messages_query, upstreams_query, rate_query]),
+    )
+}
+
+fn diff_subcommand(opts: &Options, other: Option<String>, at: Option<i64>) -> Result<()> {
+    if opts.follow {
+        return Err(anyhow!("cannot diff while tailing"));
+    }
+
+    let mut access_logs = access_logs(opts)?;
+    let (fields, queries) = diff_queries(opts, other.as_deref(), at);
+    access_logs.extend(other);
+
+    load(opts, &access_logs, Some(fields), Some(queries))?.report(false)
+}
+
+// The fields and queries comparing the records of the other access log, or else those from the
+// given time on, with the rest.
+fn diff_queries(
+    opts: &Options,
+    other: Option<&str>,
+    at: Option<i64>,
+) -> (Vec<String>, Vec<String>) {
+    let mut fields = vec![
+        String::from(STATUS_TYPE),
+        String::from(BYTES_SENT),
+        opts.group_by.clone(),
+    ];
+    // Decide which side of the comparison each record belongs to.
+    let after = match (other, at) {
+        (Some(other), _) => {
+            fields.push(String::from(SOURCE_FILE));
+            format!("{} = '{}'", SOURCE_FILE, other.replace('\'', "''"))
+        }
+        (None, Some(at)) => {
+            fields.push(String::from(TIMESTAMP));
+            format!("{} >= {}", TIMESTAMP, at)
+        }
+        (None, None) => unreachable!("structopt requires an access log or --at"),
+    };
+
+    let query = format!(
+        "WITH sides AS (
+SELECT {group_by} AS grp,
+COUNT(CASE WHEN NOT ({after}) THEN 1 END) AS count_before,
+COUNT(CASE WHEN {after} THEN 1 END) AS count_after,
+AVG(CASE WHEN NOT ({after}) THEN status_type = 5 END) AS error_rate_before,
+AVG(CASE WHEN {after} THEN status_type = 5 END) AS error_rate_after,
+AVG(CASE WHEN NOT ({after}) THEN bytes_sent END) AS avg_bytes_sent_before,
+AVG(CASE WHEN {after} THEN bytes_sent END) AS avg_bytes_sent_after
+FROM log
+GROUP BY {group_by}
+)
+SELECT grp AS {group_by},
+count_after AS count,
+count_after - count_before AS count_delta,
+ROUND(error_rate_after, 4) AS error_rate,
+ROUND(IFNULL(error_rate_after, 0) - IFNULL(error_rate_before, 0), 4) AS error_rate_delta,
+ROUND(avg_bytes_sent_after, 2) AS avg_bytes_sent,
+ROUND(IFNULL(avg_bytes_sent_after, 0) - IFNULL(avg_bytes_sent_before, 0), 2) AS avg_bytes_sent_delta
+FROM sides
+ORDER BY ABS(count_delta) DESC
+LIMIT {limit};",
+        group_by = quote_identifier(&opts.group_by),
+        after = after,
+        limit = opts.limit
+    );
+    debug!("diff sub command query: {}", query);
+
+    (fields, vec![query])
+}
+
+fn export_subcommand(opts: &Options, path: &Path, fields: Vec<String>) -> Result<()> {
+    // The page size matches the row groups of Parquet files.
+    const PAGE_ROWS: u64 = 100_000;
+
+    if opts.follow {
+        return Err(anyhow!("cannot export while tailing"));
+    }
+
+    let fields = if fields.is_empty() {
+        LogParser::new(&opts.format)?.columns().ok_or_else(|| {
+            anyhow!("the fields of this format are not known ahead of time so give them explicitly")
+        })?
+    } else {
+        fields
+    };
+
+    let access_logs = access_logs(opts)?;
+    let processor = load(opts, &access_logs, Some(fields.clone()), Some(vec![]))?;
+    let mut export = Export::create(path, &fields)?;
+    let exported = for_each_page(&processor, &fields, PAGE_ROWS, |page| export.write(&page))?;
+    debug!("exported {} records to {}", exported, path.display());
+    export.finish()
+}
+
+// Page through the given fields of the records by rowid, so that only a page of them is held at a
+// time, returning how many records there were.
+fn for_each_page<F>(processor: &Processor, fields: &[String], rows: u64, mut f: F) -> Result<usize>
+where
+    F: FnMut(QueryResult) -> Result<()>,
+{
+    let selections = fields
+        .iter()
+        .map(|f| quote_identifier(f))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let mut last_row = 0;
+    let mut total = 0;
+    loop {
+        let query = format!(
+            "SELECT rowid, {selections} FROM log WHERE rowid > {last_row} ORDER BY rowid LIMIT {limit}",
+            selections = selections,
+            last_row = last_row,
+            limit = rows
+        );
+        let mut page = processor.query(&query)?;
+        if page.rows.is_empty() {
+            return Ok(total);
+        }
+        for row in &mut page.rows {
+            if let Value::Integer(rowid) = row.remove(0) {
+                last_row = rowid;
+            }
+        }
+        page.columns.remove(0);
+        total += page.rows.len();
+        f(page)?;
+    }
+}
+
+fn hist_subcommand(opts: &Options, field: String, buckets: Vec<f64>) -> Result<()> {
+    // The widest bar that is drawn, which is the one for the largest bucket.
+    const BAR_WIDTH: usize = 40;
+
+    if buckets.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(anyhow!("the bucket boundaries must be increasing"));
+    }
+
+    // Each bucket covers [lower, upper) with the first and last ones being unbounded.
+    let mut bounds = vec![(None, buckets.first().copied())];
+    bounds.extend(buckets.windows(2).map(|w| (Some(w[0]), Some(w[1]))));
+    bounds.push((buckets.last().copied(), None));
+    let values = bounds
+        .iter()
+        .enumerate()
+        .map(|(i, bound)| {
+            let (label, lower, upper) = match bound {
+                (None, Some(u)) => (format!("< {}", u), String::from("NULL"), u.to_string()),
+                (Some(l), None) => (format!(">= {}", l), l.to_string(), String::from("NULL")),
+                (Some(l), Some(u)) => (format!("{} - {}", l, u), l.to_string(), u.to_string()),
+                (None, None) => unreachable!("there is at least one boundary"),
+            };
+            format!("({}, '{}', {}, {})", i, label, lower, upper)
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    let query = format!(
+        "WITH buckets (idx, label, lower, upper) AS (VALUES {values}),
+counts AS (
+SELECT idx, label, COUNT(value) AS count
+FROM buckets
+LEFT JOIN (
+SELECT CAST({field} AS REAL) AS value
+FROM log
+WHERE {field} IS NOT NULL AND {field} != '' AND {field} != '-'
+) ON (lower IS NULL OR value >= lower) AND (upper IS NULL OR value < upper)
+GROUP BY idx
+)
+SELECT label AS bucket,
+count,
+ROUND(100.0 * count / MAX(SUM(count) OVER (), 1), 2) AS percent,
+SUBSTR('{bar}', 1, CAST(ROUND({width}.0 * count / MAX(MAX(count) OVER (), 1)) AS INTEGER)) AS bar
+FROM counts
+ORDER BY idx;",
+        values = values,
+        field = quote_identifier(&field),
+        bar = "█".repeat(BAR_WIDTH),
+        width = BAR_WIDTH
+    );
+    debug!("hist sub command query: {}", query);
+
+    run(opts, Some(vec![field]), Some(vec![query]))
+}
+
+fn info_subcommand(opts: &Options) -> Result<()> {
+    let access_logs = if opts.access_log.is_empty() {
+        vec![String::from(STDIN)]
+    } else {
+        expand_access_logs(&opts.access_log)?
+    };
+    println!("access log file: {}", access_logs.join(", "));
+    println!("access log format: {}", opts.format);
+    let mut variables = available_variables(&opts.format)?;
+    if !opts.geoip_db.is_empty() {
+        variables.push_str(&format!(", {}, {}, {}, {}", COUNTRY, CITY, ASN, AS_ORG));
+    }
+    if opts.syslog {
+        variables.push_str(", hostname");
+    }
+    println!("available variables to query: {}", variables);
+
+    Ok(())
+}
+
+fn max_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
+    let max_fields: Vec<String> = fields
+        .iter()
+        .map(|f| {
+            format!(
+                "MAX({f}) AS {name}",
+                f = quote_identifier(f),
+                name = quote_identifier(&format!("MAX({})", f))
+            )
+        })
+        .collect();
+    let selections = max_fields.join(", ");
+    let query = format!("SELECT {selections} FROM log", selections = selections);
+    debug!("max sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn min_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
+    let min_fields: Vec<String> = fields
+        .iter()
+        .map(|f| {
+            format!(
+                "MIN({f}) AS {name}",
+                f = quote_identifier(f),
+                name = quote_identifier(&format!("MIN({})", f))
+            )
+        })
+        .collect();
+    let selections = min_fields.join(", ");
+    let query = format!("SELECT {selections} FROM log", selections = selections);
+    debug!("min sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn notfound_subcommand(opts: &Options) -> Result<()> {
+    let (fields, queries) = notfound_queries(opts)?;
+    run(opts, Some(fields), Some(queries))
+}
+
+// The fields and queries finding the missing paths, the pages linking to them and the clients
+// asking for them.
+fn notfound_queries(opts: &Options) -> Result<(Vec<String>, Vec<String>)> {
+    let columns = LogParser::new(&opts.format)?.columns();
+    let has = |c: &str| columns.as_ref().is_none_or(|cs| cs.iter().any(|f| f == c));
+    if !has(STATUS_TYPE) || !has(REQUEST_PATH) {
+        return Err(anyhow!(
+            "the log format does not have both $status and $request"
+        ));
+    }
+
+    let mut fields = vec![String::from("status"), String::from(REQUEST_PATH)];
+    let mut queries = vec![format!(
+        "SELECT {request_path},
+COUNT(1) AS count,
+ROUND(100.0 * COUNT(1) / (SELECT COUNT(1) FROM log WHERE status = 404), 2) AS percent
+FROM log
+WHERE status = 404
+GROUP BY {request_path}
+ORDER BY count DESC
+LIMIT {limit};",
+        request_path = REQUEST_PATH,
+        limit = opts.limit
+    )];
+    // The pages with broken links, leaving out the requests that were typed in or bookmarked.
+    if has("http_referer") {
+        fields.push(String::from("http_referer"));
+        queries.push(format!(
+            "SELECT http_referer, {request_path}, COUNT(1) AS count
+FROM log
+WHERE status = 404 AND http_referer NOT IN ('', '-')
+GROUP BY http_referer, {request_path}
+ORDER BY count DESC
+LIMIT {limit};",
+            request_path = REQUEST_PATH,
+            limit = opts.limit
+        ));
+    }
+    if has("remote_addr") {
+        fields.push(String::from("remote_addr"));
+        queries.push(format!(
+            "SELECT remote_addr, COUNT(1) AS count, COUNT(DISTINCT {request_path}) AS paths
+FROM log
+WHERE status = 404
+GROUP BY remote_addr
+ORDER BY count DESC
+LIMIT {limit};",
+            request_path = REQUEST_PATH,
+            limit = opts.limit
+        ));
+    }
+    debug!("notfound sub command queries: {:?}", queries);
+    Ok((fields, queries))
+}
+
+fn percentile_subcommand(opts: &Options, field: String, percentiles: Vec<f64>) -> Result<()> {
+    if let Some(p) = percentiles.iter().find(|p| **p <= 0.0 || **p > 100.0) {
+        return Err(anyhow!("percentiles must be within (0, 100], got {}", p));
+    }
+
+    // Use the nearest rank method: the smallest value whose rank is at least p% of the group.
+    let selections = percentiles
+        .iter()
+        .map(|p| {
+            format!(
+                "MIN(CASE WHEN rank * 100 >= total * {p} THEN value END) AS 'p{p}'",
+                p = p
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(",\n");
+    let query = format!(
+        "WITH ranked AS (
+SELECT {group_by}, CAST({field} AS REAL) AS value,
+ROW_NUMBER() OVER (PARTITION BY {group_by} ORDER BY CAST({field} AS REAL)) AS rank,
+COUNT(1) OVER (PARTITION BY {group_by}) AS total
+FROM log
+WHERE {field} IS NOT NULL AND {field} != '' AND {field} != '-'
+)
+SELECT {group_by},
+COUNT(1) AS count,
+{selections}
+FROM ranked
+GROUP BY {group_by}
+ORDER BY count DESC
+LIMIT {limit};",
+        group_by = quote_identifier(&opts.group_by),
+        field = quote_identifier(&field),
+        selections = selections,
+        limit = opts.limit
+    );
+    debug!("percentile sub command query: {}", query);
+
+    let mut fields = vec![opts.group_by.clone()];
+    if field != opts.group_by {
+        fields.push(field);
+    }
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+// How many values of the pivoted field get a column of their own. The rest are counted together.
+const PIVOT_COLUMNS: usize = 20;
+
+fn pivot_subcommand(opts: &Options, rows: String, cols: String) -> Result<()> {
+    if opts.follow {
+        return Err(anyhow!("cannot pivot while tailing"));
+    }
+    if rows == cols {
+        return Err(anyhow!("the rows and columns must be different fields"));
+    }
+
+    let fields = vec![rows.clone(), cols.clone()];
+    let processor = load(opts, &access_logs(opts)?, Some(fields), Some(vec![]))?;
+    let values = processor.query(&format!(
+        "SELECT {cols} FROM log GROUP BY {cols} ORDER BY COUNT(1) DESC LIMIT {limit};",
+        cols = quote_identifier(&cols),
+        limit = PIVOT_COLUMNS + 1
+    ))?;
+    let mut values = values
+        .rows
+        .into_iter()
+        .map(|mut r| r.remove(0))
+        .collect::<Vec<Value>>();
+    let other = values.len() > PIVOT_COLUMNS;
+    values.truncate(PIVOT_COLUMNS);
+
+    let query = pivot_query(opts, &rows, &cols, &values, other);
+    processor.write(&[processor.query(&query)?], false)
+}
+
+// The query counting the records of each group of rows with each of the given values of the
+// pivoted field, and those with any other value when there are more.
+fn pivot_query(opts: &Options, rows: &str, cols: &str, values: &[Value], other: bool) -> String {
+    // A column counting the records with each value, named after it. The classes of status get
+    // the names the other reports use.
+    let name = |value: &Value| match value {
+        Value::Integer(class) if cols == STATUS_TYPE => format!("{}XX", class),
+        v => format_value(v),
+    };
+    let condition = |value: &Value| match sql_literal(value).as_str() {
+        "NULL" => format!("{} IS NULL", quote_identifier(cols)),
+        literal => format!("{} = {}", quote_identifier(cols), literal),
+    };
+    let mut selections = values
+        .iter()
+        .map(|v| {
+            format!(
+                "COUNT(CASE WHEN {} THEN 1 END) AS {}",
+                condition(v),
+                quote_identifier(&name(v))
+            )
+        })
+        .collect::<Vec<String>>();
+    if other {
+        // Comparing a null value gives null rather than false.
+        let known = values.iter().map(condition).collect::<Vec<String>>();
+        selections.push(format!(
+            "COUNT(CASE WHEN NOT IFNULL({}, 0) THEN 1 END) AS other",
+            known.join(" OR ")
+        ));
+    }
+    let query = format!(
+        "SELECT {rows},
+{selections},
+COUNT(1) AS total
+FROM log
+GROUP BY {rows}
+ORDER BY total DESC
+LIMIT {limit};",
+        rows = quote_identifier(rows),
+        selections = selections.join(",\n"),
+        limit = opts.limit
+    );
+    debug!("pivot sub command query: {}", query);
+    query
+}
+
+// Write a value the way it would appear in a query. SQLite has no literals for the infinities, but
+// reads numbers too large for a double as them, and stores NaN as NULL.
+fn sql_literal(value: &Value) -> String {
+    match value {
+        Value::Null => String::from("NULL"),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(r) if r.is_nan() => String::from("NULL"),
+        Value::Real(r) if r.is_infinite() => {
+            String::from(if *r > 0.0 { "9e999" } else { "-9e999" })
+        }
+        Value::Real(r) => format!("{:?}", r),
+        Value::Text(t) => format!("'{}'", t.replace('\'', "''")),
+        Value::Blob(b) => format!(
+            "X'{}'",
+            b.iter().map(|b| format!("{:02X}", b)).collect::<String>()
+        ),
+    }
+}
+
+fn print_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
+    let selections = fields
+        .iter()
+        .map(|f| quote_identifier(f))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let query = format!(
+        "SELECT {selections} FROM log GROUP BY {selections}",
+        selections = selections
+    );
+    debug!("print sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn protocols_subcommand(opts: &Options) -> Result<()> {
+    let columns = LogParser::new(&opts.format)?.columns();
+    let has = |c: &str| columns.as_ref().is_none_or(|cs| cs.iter().any(|f| f == c));
+    if !has(REQUEST_PROTOCOL) {
+        return Err(anyhow!(
+            "the log format does not have $request or $server_protocol"
+        ));
+    }
+
+    let mut fields = vec![
+        String::from(REQUEST_PROTOCOL),
+        String::from(STATUS_TYPE),
+        String::from(BYTES_SENT),
+    ];
+    let mut selections = vec![
+        String::from("COUNT(1) AS count"),
+        String::from("ROUND(100.0 * COUNT(1) / (SELECT COUNT(1) FROM log), 2) AS percent"),
+        format!(
+            "ROUND(COUNT(CASE WHEN {} = 5 THEN 1 END) * 1.0 / COUNT(1), 4) AS error_rate",
+            STATUS_TYPE
+        ),
+        String::from("AVG(bytes_sent) AS avg_bytes_sent"),
+    ];
+    if has("request_time") {
+        fields.push(String::from("request_time"));
+        selections.push(String::from("AVG(request_time) AS avg_request_time"));
+    }
+
+    let query = format!(
+        "SELECT {protocol},
+{selections}
+FROM log
+GROUP BY {protocol}
+ORDER BY count DESC;",
+        protocol = REQUEST_PROTOCOL,
+        selections = selections.join(",\n")
+    );
+    debug!("protocols sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn query_subcommand(opts: &Options, fields: Vec<String>, query: String) -> Result<()> {
+    debug!("custom query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn repl_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
+    if opts.follow {
+        return Err(anyhow!("cannot start a repl while tailing"));
+    }
+    let access_logs = access_logs(opts)?;
+    if access_logs.iter().any(|l| l == STDIN) {
+        return Err(anyhow!(
+            "the statements are read from STDIN so give the access logs with -a"
+        ));
+    }
+
+    let fields = if fields.is_empty() {
+        LogParser::new(&opts.format)?.columns().ok_or_else(|| {
+            anyhow!("the fields of this format are not known ahead of time so give them explicitly")
+        })?
+    } else {
+        fields
+    };
+    let processor = load(opts, &access_logs, Some(fields), Some(vec![]))?;
+    repl::run(&processor)
+}
+
+fn rate_subcommand(opts: &Options, bucket: Duration) -> Result<()> {
+    let bucket = bucket_seconds(bucket)?;
+    let columns = format!(
+        "COUNT(1) AS count,
+ROUND(COUNT(1) * 1.0 / {bucket}, 2) AS req_per_sec,
+ROUND(TOTAL(bytes_sent) / {bucket}, 2) AS bytes_per_sec",
+        bucket = bucket
+    );
+    let query = format!("{};", bucketed_query(opts, bucket, &columns));
+    debug!("rate sub command query: {}", query);
+
+    let fields = vec![String::from(TIMESTAMP), String::from(BYTES_SENT)];
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn referrers_subcommand(opts: &Options, sites: Vec<String>, pages: u64) -> Result<()> {
+    let mut fields = vec![String::from(REFERER_DOMAIN), String::from(REQUEST_PATH)];
+
+    // Referrals from the site itself are not interesting.
+    let mut external = vec![format!("{} IS NOT NULL", REFERER_DOMAIN)];
+    for site in &sites {
+        let site = site.to_ascii_lowercase().replace('\'', "''");
+        external.push(format!(
+            "{domain} != '{site}' AND {domain} NOT LIKE '%.{site}'",
+            domain = REFERER_DOMAIN,
+            site = site
+        ));
+    }
+    let columns = LogParser::new(&opts.format)?.columns().unwrap_or_default();
+    if let Some(host) = ["host", "http_host"]
+        .iter()
+        .find(|h| columns.iter().any(|c| c == *h))
+    {
+        fields.push(host.to_string());
+        external.push(format!("{} != LOWER({})", REFERER_DOMAIN, host));
+    }
+    let external = external.join(" AND ");
+
+    let referrers_query = format!(
+        "SELECT {domain},
+COUNT(1) AS count,
+COUNT(DISTINCT {path}) AS landing_pages
+FROM log
+WHERE {external}
+GROUP BY {domain}
+ORDER BY count DESC
+LIMIT {limit};",
+        domain = REFERER_DOMAIN,
+        path = REQUEST_PATH,
+        external = external,
+        limit = opts.limit
+    );
+    let landing_query = format!(
+        "SELECT {domain}, {path}, count
+FROM (
+SELECT {domain}, {path},
+COUNT(1) AS count,
+ROW_NUMBER() OVER (PARTITION BY {domain} ORDER BY COUNT(1) DESC) AS rank,
+SUM(COUNT(1)) OVER (PARTITION BY {domain}) AS total
+FROM log
+WHERE {external}
+GROUP BY {domain}, {path}
+)
+WHERE rank <= {pages}
+AND {domain} IN (
+SELECT {domain} FROM log WHERE {external} GROUP BY {domain} ORDER BY COUNT(1) DESC LIMIT {limit}
+)
+ORDER BY total DESC, {domain}, count DESC;",
+        domain = REFERER_DOMAIN,
+        path = REQUEST_PATH,
+        external = external,
+        pages = pages,
+        limit = opts.limit
+    );
+    debug!(
+        "referrers sub command queries: {} {}",
+        referrers_query, landing_query
+    );
+
+    run(
+        opts,
+        Some(fields),
+        Some(vec![referrers_query, landing_query]),
+    )
+}
+
+fn sessions_subcommand(opts: &Options, timeout: Duration) -> Result<()> {
+    let (fields, queries) = sessions_queries(opts, timeout);
+    run(opts, Some(fields), Some(queries))
+}
+
+// The fields and queries splitting the requests of each client into sessions.
+fn sessions_queries(opts: &Options, timeout: Duration) -> (Vec<String>, Vec<String>) {
+    // Number the sessions of each client, starting a new one whenever a request comes more than
+    // the timeout after the previous one, and find the first and last path of each.
+    let sessions = format!(
+        "WITH starts AS (
+SELECT remote_addr, {ts}, request_path, rowid AS id,
+CASE WHEN {ts} - LAG({ts}) OVER (PARTITION BY remote_addr ORDER BY {ts}, rowid) <= {timeout}
+THEN 0 ELSE 1 END AS new_session
+FROM log
+WHERE {ts} IS NOT NULL
+),
+numbered AS (
+SELECT *, SUM(new_session) OVER (PARTITION BY remote_addr ORDER BY {ts}, id) AS session
+FROM starts
+),
+requests AS (
+SELECT remote_addr, session, {ts},
+FIRST_VALUE(request_path) OVER w AS entry_path,
+LAST_VALUE(request_path) OVER w AS exit_path
+FROM numbered
+WINDOW w AS (PARTITION BY remote_addr, session ORDER BY {ts}, id
+ROWS BETWEEN UNBOUNDED PRECEDING AND UNBOUNDED FOLLOWING)
+),
+sessions AS (
+SELECT remote_addr, session, entry_path, exit_path,
+COUNT(1) AS requests,
+MAX({ts}) - MIN({ts}) AS duration
+FROM requests
+GROUP BY remote_addr, session
+)",
+        ts = TIMESTAMP,
+        timeout = timeout.as_secs()
+    );
+
+    let summary_query = format!(
+        "{sessions}
+SELECT COUNT(1) AS sessions,
+COUNT(DISTINCT remote_addr) AS clients,
+ROUND(AVG(requests), 2) AS avg_requests,
+ROUND(AVG(duration), 2) AS avg_duration,
+MAX(duration) AS max_duration
+FROM sessions;",
+        sessions = sessions
+    );
+    let clients_query = format!(
+        "{sessions}
+SELECT remote_addr,
+COUNT(1) AS sessions,
+ROUND(AVG(requests), 2) AS avg_requests,
+ROUND(AVG(duration), 2) AS avg_duration
+FROM sessions
+GROUP BY remote_addr
+ORDER BY sessions DESC
+LIMIT {limit};",
+        sessions = sessions,
+        limit = opts.limit
+    );
+    let paths_queries = ["entry_path", "exit_path"].iter().map(|path| {
+        format!(
+            "{sessions}
+SELECT {path},
+COUNT(1) AS sessions,
+ROUND(AVG(requests), 2) AS avg_requests
+FROM sessions
+GROUP BY {path}
+ORDER BY sessions DESC
+LIMIT {limit};",
+            sessions = sessions,
+            path = path,
+            limit = opts.limit
+        )
+    });
+
+    let mut queries = vec![summary_query, clients_query];
+    queries.extend(paths_queries);
+    debug!("sessions sub command queries: {}", queries.join(" "));
+
+    let fields = vec![
+        String::from("remote_addr"),
+        String::from(TIMESTAMP),
+        String::from(REQUEST_PATH),
+    ];
+    (fields, queries)
+}
+
+fn ship_subcommand(opts: &Options, ship: &Ship) -> Result<()> {
+    if opts.follow {
+        return Err(anyhow!("cannot ship records while tailing"));
+    }
+
+    match ship {
+        Ship::Clickhouse(c) => ship_clickhouse(opts, c),
+    }
+}
+
+fn ship_clickhouse(opts: &Options, c: &ClickHouse) -> Result<()> {
+    if c.batch_size == 0 {
+        return Err(anyhow!("the batch size must be at least one"));
+    }
+    let fields = if c.fields.is_empty() {
+        LogParser::new(&opts.format)?.columns().ok_or_else(|| {
+            anyhow!("the fields of this format are not known ahead of time so give them explicitly")
+        })?
+    } else {
+        c.fields.clone()
+    };
+
+    let access_logs = access_logs(opts)?;
+    let processor = load(opts, &access_logs, Some(fields.clone()), Some(vec![]))?;
+    let table = clickhouse::Table::new(&c.url, &c.table, c.user.as_deref(), c.password.as_deref());
+    if c.create {
+        table.create(&fields)?;
+    }
+
+    let shipped = for_each_page(&processor, &fields, c.batch_size, |batch| {
+        debug!("inserting {} rows into {}", batch.rows.len(), c.table);
+        table.insert(&batch)
+    })?;
+    eprintln!("inserted {} rows into {}", thousands(shipped), c.table);
+
+    Ok(())
+}
+
+fn slo_subcommand(opts: &Options, target: f64, window: Duration) -> Result<()> {
+    let (fields, queries) = slo_queries(opts, target, window)?;
+    run(opts, Some(fields), Some(queries))
+}
+
+// The fields and queries reporting how much of the error budget was used up over the window, and
+// by which groups.
+fn slo_queries(
+    opts: &Options,
+    target: f64,
+    window: Duration,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let columns = LogParser::new(&opts.format)?.columns();
+    let has = |c: &str| columns.as_ref().is_none_or(|cs| cs.iter().any(|f| f == c));
+    if !has(TIMESTAMP) || !has(STATUS_TYPE) {
+        return Err(anyhow!(
+            "the log format does not have both a time and $status"
+        ));
+    }
+
+    // The burn rate is how many times faster than allowed the budget is being used up, so at 1
+    // it runs out right at the end of the window. The one of the last hour shows whether that is
+    // still going on.
+    let windowed = format!(
+        "WITH windowed AS (
+SELECT {group_by}, {ts} AS ts, {local_ts} AS local_ts, status_type = 5 AS failed
+FROM log
+WHERE {ts} > (SELECT MAX({ts}) FROM log) - {window}
+)",
+        group_by = quote_identifier(&opts.group_by),
+        ts = TIMESTAMP,
+        local_ts = local_ts(opts),
+        window = window.as_secs()
+    );
+    let allowed = (100.0 - target) / 100.0;
+    // Without any failures nothing of the budget is used, even without requests or a budget. With
+    // them and a target of 100% the budget is exceeded without bound, which is left empty.
+    let burn = |failure_rate: &str| {
+        format!(
+            "CASE WHEN IFNULL({rate}, 0) = 0 THEN 0.0 ELSE {rate} / {allowed} END",
+            rate = failure_rate,
+            allowed = allowed
+        )
+    };
+    let budget_query = format!(
+        "{windowed}
+SELECT datetime(MIN(local_ts), 'unixepoch') AS since,
+datetime(MAX(local_ts), 'unixepoch') AS until,
+COUNT(1) AS requests,
+IFNULL(SUM(failed), 0) AS failed,
+ROUND(100.0 * (COUNT(1) - SUM(failed)) / COUNT(1), 4) AS success_pct,
+{target:?} AS target_pct,
+ROUND(COUNT(1) * {allowed:?}, 2) AS budget,
+ROUND(100.0 * (1 - {burn}), 2) AS budget_remaining_pct,
+ROUND({burn}, 2) AS burn_rate,
+ROUND({burn_1h}, 2) AS burn_rate_1h
+FROM windowed;",
+        windowed = windowed,
+        target = target,
+        allowed = allowed,
+        burn = burn("AVG(failed)"),
+        burn_1h = burn("AVG(CASE WHEN ts > (SELECT MAX(ts) FROM windowed) - 3600 THEN failed END)")
+    );
+    // The groups that used up the most of the budget.
+    let groups_query = format!(
+        "{windowed}
+SELECT {group_by},
+COUNT(1) AS requests,
+SUM(failed) AS failed,
+ROUND(100.0 * SUM(failed) / ((SELECT COUNT(1) FROM windowed) * {allowed:?}), 2) AS budget_used_pct
+FROM windowed
+GROUP BY {group_by}
+HAVING SUM(failed) > 0
+ORDER BY failed DESC
+LIMIT {limit};",
+        windowed = windowed,
+        group_by = quote_identifier(&opts.group_by),
+        allowed = allowed,
+        limit = opts.limit
+    );
+    debug!("slo sub command queries: {} {}", budget_query, groups_query);
+
+    let mut fields = vec![String::from(TIMESTAMP), String::from(STATUS_TYPE)];
+    if !fields.contains(&opts.group_by) {
+        fields.push(opts.group_by.clone());
+    }
+    Ok((fields, vec![budget_query, groups_query]))
+}
+
+fn slowest_subcommand(opts: &Options) -> Result<()> {
+    let columns = LogParser::new(&opts.format)?.columns();
+    let has = |c: &str| columns.as_ref().is_none_or(|cs| cs.iter().any(|f| f == c));
+    if !has("request_time") {
+        return Err(anyhow!("the log format does not have $request_time"));
+    }
+
+    // Show whatever identifies the requests among the fields the format has.
+    let mut fields = vec![];
+    let mut selections = vec![];
+    if has(TIMESTAMP) {
+        fields.push(String::from(TIMESTAMP));
+        selections.push(format!("datetime({}, 'unixepoch') AS time", local_ts(opts)));
+    }
+    for field in &["remote_addr", REQUEST_PATH] {
+        if has(field) {
+            fields.push(field.to_string());
+            selections.push(field.to_string());
+        }
+    }
+    if has(STATUS_TYPE) {
+        fields.push(String::from("status"));
+        selections.push(String::from("status"));
+    }
+    fields.push(String::from("request_time"));
+    selections.push(String::from("request_time"));
+
+    let query = format!(
+        "SELECT {selections}
+FROM log
+WHERE request_time IS NOT NULL
+ORDER BY request_time DESC
+LIMIT {limit};",
+        selections = selections.join(", "),
+        limit = opts.limit
+    );
+    debug!("slowest sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn stats_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
+    let selections = fields
+        .iter()
+        .map(|f| {
+            format!(
+                "SELECT '{name}' AS field,
+COUNT({f}) AS count,
+AVG({f}) AS mean,
+stddev({f}) AS stddev,
+MIN({f}) AS min,
+MAX({f}) AS max
+FROM log",
+                name = f.replace('\'', "''"),
+                f = quote_identifier(f)
+            )
+        })
+        .collect::<Vec<String>>();
+    let query = selections.join("\nUNION ALL\n");
+    debug!("stats sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn sum_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
+    let sum_fields: Vec<String> = fields
+        .iter()
+        .map(|f| {
+            format!(
+                "SUM({f}) AS {name}",
+                f = quote_identifier(f),
+                name = quote_identifier(&format!("SUM({})", f))
+            )
+        })
+        .collect();
+    let selections = sum_fields.join(", ");
+    let query = format!("SELECT {selections} FROM log", selections = selections);
+    debug!("sum sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn timeseries_subcommand(opts: &Options, bucket: Duration, smooth: Option<usize>) -> Result<()> {
+    let (fields, queries) = timeseries_queries(opts, bucket, smooth)?;
+    run(opts, Some(fields), Some(queries))
+}
+
+// The fields and queries counting the requests, bytes and errors of every bucket of time, with
+// moving averages over the given number of buckets.
+fn timeseries_queries(
+    opts: &Options,
+    bucket: Duration,
+    smooth: Option<usize>,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let bucket = bucket_seconds(bucket)?;
+    if smooth == Some(0) {
+        return Err(anyhow!("the moving averages need at least one bucket"));
+    }
+
+    let mut query = bucketed_query(
+        opts,
+        bucket,
+        "COUNT(1) AS count,
+SUM(bytes_sent) AS bytes_sent,
+COUNT(CASE WHEN status_type = 4 THEN 1 END) AS '4XX',
+COUNT(CASE WHEN status_type = 5 THEN 1 END) AS '5XX'",
+    );
+    // The 5XX rate is averaged over all of the requests in the window, so that a quiet bucket
+    // with a single failure does not count as much as a busy one.
+    if let Some(n) = smooth {
+        query = format!(
+            "SELECT *,
+ROUND(AVG(count) OVER w, 2) AS count_avg,
+ROUND(1.0 * SUM(\"5XX\") OVER w / SUM(count) OVER w, 4) AS \"5xx_rate_avg\"
+FROM ({query})
+WINDOW w AS (ORDER BY bucket ROWS BETWEEN {preceding} PRECEDING AND CURRENT ROW)
+ORDER BY bucket",
+            query = query,
+            preceding = n - 1
+        );
+    }
+    query.push(';');
+    debug!("timeseries sub command query: {}", query);
+
+    let fields = vec![
+        String::from(TIMESTAMP),
+        String::from(BYTES_SENT),
+        String::from(STATUS_TYPE),
+    ];
+    Ok((fields, vec![query]))
+}
+
+// The protocol versions that current browsers refuse to use.
+const LEGACY_TLS: &str = "('SSLv2', 'SSLv3', 'TLSv1', 'TLSv1.1')";
+
+fn suspicious_subcommand(opts: &Options) -> Result<()> {
+    let types_query = format!(
+        "SELECT {scan_type}, COUNT(1) AS count, COUNT(DISTINCT remote_addr) AS clients
+FROM log
+WHERE {scan_type} IS NOT NULL
+GROUP BY {scan_type}
+ORDER BY count DESC;",
+        scan_type = SCAN_TYPE
+    );
+    // Probes that were answered with a 2XX are the ones worth looking into first.
+    let clients_query = format!(
+        "SELECT remote_addr,
+COUNT(1) AS hits,
+COUNT(DISTINCT {request_path}) AS paths,
+GROUP_CONCAT(DISTINCT {scan_type}) AS scan_types,
+{status_counts}
+FROM log
+WHERE {scan_type} IS NOT NULL
+GROUP BY remote_addr
+ORDER BY hits DESC
+LIMIT {limit};",
+        request_path = REQUEST_PATH,
+        scan_type = SCAN_TYPE,
+        status_counts = status_counts(opts),
+        limit = opts.limit
+    );
+    debug!(
+        "suspicious sub command queries: {} {}",
+        types_query, clients_query
+    );
+
+    let fields = vec![
+        String::from(SCAN_TYPE),
+        String::from("remote_addr"),
+        String::from(REQUEST_PATH),
+        String::from(STATUS_TYPE),
+    ];
+    run(opts, Some(fields), Some(vec![types_query, clients_query]))
+}
+
+fn tls_subcommand(opts: &Options) -> Result<()> {
+    let (fields, queries) = tls_queries(opts)?;
+    run(opts, Some(fields), Some(queries))
+}
+
+// The fields and queries breaking the requests over TLS down by protocol and cipher, and finding
+// the clients of the legacy protocols.
+fn tls_queries(opts: &Options) -> Result<(Vec<String>, Vec<String>)> {
+    let columns = LogParser::new(&opts.format)?.columns();
+    let has = |c: &str| columns.as_ref().is_none_or(|cs| cs.iter().any(|f| f == c));
+    if !has("ssl_protocol") {
+        return Err(anyhow!("the log format does not have $ssl_protocol"));
+    }
+
+    // Plain HTTP requests have no protocol.
+    let tls = "ssl_protocol IS NOT NULL AND ssl_protocol NOT IN ('', '-')";
+    let mut fields = vec![String::from("ssl_protocol")];
+    let mut queries = vec![format!(
+        "SELECT ssl_protocol,
+COUNT(1) AS count,
+ROUND(100.0 * COUNT(1) / (SELECT COUNT(1) FROM log WHERE {tls}), 2) AS percent,
+CASE WHEN ssl_protocol IN {legacy} THEN 'yes' ELSE 'no' END AS legacy
+FROM log
+WHERE {tls}
+GROUP BY ssl_protocol
+ORDER BY count DESC;",
+        tls = tls,
+        legacy = LEGACY_TLS
+    )];
+    if has("ssl_cipher") {
+        fields.push(String::from("ssl_cipher"));
+        queries.push(format!(
+            "SELECT ssl_cipher, ssl_protocol,
+COUNT(1) AS count,
+ROUND(100.0 * COUNT(1) / (SELECT COUNT(1) FROM log WHERE {tls}), 2) AS percent
+FROM log
+WHERE {tls}
+GROUP BY ssl_cipher, ssl_protocol
+ORDER BY count DESC
+LIMIT {limit};",
+            tls = tls,
+            limit = opts.limit
+        ));
+    }
+    // The clients that still connect with a legacy protocol and would break without it.
+    if has("remote_addr") {
+        fields.push(String::from("remote_addr"));
+        let mut selections = vec!["remote_addr", "ssl_protocol", "COUNT(1) AS count"];
+        if has("http_user_agent") {
+            fields.push(String::from("http_user_agent"));
+            selections.push("MAX(http_user_agent) AS http_user_agent");
+        }
+        queries.push(format!(
+            "SELECT {selections}
+FROM log
+WHERE ssl_protocol IN {legacy}
+GROUP BY remote_addr, ssl_protocol
+ORDER BY count DESC
+LIMIT {limit};",
+            selections = selections.join(", "),
+            legacy = LEGACY_TLS,
+            limit = opts.limit
+        ));
+    }
+    debug!("tls sub command queries: {}", queries.join(" "));
+
+    Ok((fields, queries))
+}
+
+fn top_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
+    let mut queries = Vec::with_capacity(fields.len());
+
+    for f in &fields {
+        let query = format!(
+            "SELECT {field}, {count} AS count FROM log \
+            GROUP BY {field} ORDER BY count DESC LIMIT {limit}",
+            field = quote_identifier(f),
+            count = scaled_count(opts, "COUNT(1)"),
+            limit = opts.limit
+        );
+        debug!("top sub command query: {}", query);
+        queries.push(query);
+    }
+
+    run(opts, Some(fields), Some(queries))
+}
+
+fn vhosts_subcommand(opts: &Options) -> Result<()> {
+    let (fields, queries) = vhosts_queries(opts)?;
+    run(opts, Some(fields), Some(queries))
+}
+
+// The fields and queries breaking the requests down by the site they were for.
+fn vhosts_queries(opts: &Options) -> Result<(Vec<String>, Vec<String>)> {
+    let columns = LogParser::new(&opts.format)?.columns();
+    if columns.is_some_and(|cs| !cs.iter().any(|c| c == HOST)) {
+        return Err(anyhow!(
+            "the log format does not have $host, $server_name or $http_host"
+        ));
+    }
+
+    let query = format!(
+        "SELECT {host},
+COUNT(1) AS count,
+ROUND(100.0 * COUNT(1) / (SELECT COUNT(1) FROM log), 2) AS percent,
+SUM(bytes_sent) AS bytes_sent,
+AVG(bytes_sent) AS avg_bytes_sent,
+COUNT(CASE WHEN {status_type} = 4 THEN 1 END) AS '4XX',
+COUNT(CASE WHEN {status_type} = 5 THEN 1 END) AS '5XX',
+ROUND(COUNT(CASE WHEN {status_type} = 5 THEN 1 END) * 1.0 / COUNT(1), 4) AS error_rate
+FROM log
+GROUP BY {host}
+ORDER BY count DESC
+LIMIT {limit};",
+        host = HOST,
+        status_type = STATUS_TYPE,
+        limit = opts.limit
+    );
+    debug!("vhosts sub command query: {}", query);
+
+    let fields = vec![
+        String::from(HOST),
+        String::from(STATUS_TYPE),
+        String::from(BYTES_SENT),
+    ];
+    Ok((fields, vec![query]))
+}
+
+fn ua_subcommand(opts: &Options) -> Result<()> {
+    let query = format!(
+        "SELECT {ua_name} AS user_agent,
+COUNT(1) AS count,
+ROUND(100.0 * COUNT(1) / (SELECT COUNT(1) FROM log), 2) AS percent,
+COUNT(DISTINCT http_user_agent) AS variants,
+SUM(bytes_sent) AS bytes_sent
+FROM log
+GROUP BY {ua_name}
+ORDER BY count DESC
+LIMIT {limit};",
+        ua_name = UA_NAME,
+        limit = opts.limit
+    );
+    debug!("ua sub command query: {}", query);
+
+    let fields = vec![
+        String::from(UA_NAME),
+        String::from("http_user_agent"),
+        String::from(BYTES_SENT),
+    ];
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn uniques_subcommand(opts: &Options, field: String) -> Result<()> {
+    let query = format!(
+        "SELECT {group_by},
+COUNT(1) AS count,
+approx_distinct({field}) AS uniques
+FROM log
+GROUP BY {group_by}
+ORDER BY uniques DESC
+LIMIT {limit};",
+        group_by = quote_identifier(&opts.group_by),
+        field = quote_identifier(&field),
+        limit = opts.limit
+    );
+    debug!("uniques sub command query: {}", query);
+
+    let mut fields = vec![opts.group_by.clone()];
+    if field != opts.group_by {
+        fields.push(field);
+    }
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+// Up to how many records the visitors are counted exactly. Beyond that every address would be
+// kept in a temporary index, so they are estimated with HyperLogLog instead.
+const EXACT_VISITORS: i64 = 1_000_000;
+
+fn visitors_subcommand(opts: &Options) -> Result<()> {
+    if opts.follow {
+        return Err(anyhow!("cannot count visitors while tailing"));
+    }
+    let columns = LogParser::new(&opts.format)?.columns();
+    let has = |c: &str| columns.as_ref().is_none_or(|cs| cs.iter().any(|f| f == c));
+    if !has(TIMESTAMP) || !has("remote_addr") {
+        return Err(anyhow!(
+            "the log format does not have both a time and $remote_addr"
+        ));
+    }
+
+    let fields = vec![String::from(TIMESTAMP), String::from("remote_addr")];
+    let processor = load(opts, &access_logs(opts)?, Some(fields), Some(vec![]))?;
+    let records = match processor.query("SELECT COUNT(1) FROM log")?.rows.first() {
+        Some(row) => match row[0] {
+            Value::Integer(n) => n,
+            _ => 0,
+        },
+        None => 0,
+    };
+    let queries = visitors_queries(opts, records > EXACT_VISITORS);
+    let results = queries
+        .iter()
+        .map(|q| processor.query(q))
+        .collect::<Result<Vec<QueryResult>>>()?;
+    processor.write(&results, false)
+}
+
+// The queries counting the requests and distinct addresses overall and for every day and hour,
+// estimating the addresses when there are too many to count exactly.
+fn visitors_queries(opts: &Options, approximate: bool) -> Vec<String> {
+    // The column says when the counts are estimates.
+    let visitors = if approximate {
+        "approx_distinct(remote_addr) AS approx_visitors"
+    } else {
+        "COUNT(DISTINCT remote_addr) AS visitors"
+    };
+
+    let mut queries = vec![format!(
+        "SELECT COUNT(1) AS requests, {visitors}
+FROM log;",
+        visitors = visitors
+    )];
+    for (name, bucket) in &[("day", "%Y-%m-%d"), ("hour", "%Y-%m-%d %H:00")] {
+        queries.push(format!(
+            "SELECT strftime('{bucket}', {ts}, 'unixepoch') AS {name},
+COUNT(1) AS requests,
+{visitors}
+FROM log
+WHERE {ts} IS NOT NULL
+GROUP BY {name}
+ORDER BY {name};",
+            bucket = bucket,
+            ts = local_ts(opts),
+            name = name,
+            visitors = visitors
+        ));
+    }
+    debug!("visitors sub command queries: {:?}", queries);
+    queries
+}
+
+// How many lines at the start of the first access log are used to detect its format.
+const DETECT_LINES: usize = 100;
+
+// Guess the format of the access logs from the first lines of the first one.
+fn auto_format(opts: &Options) -> Result<String> {
+    let access_log = match access_logs(opts)?.into_iter().next() {
+        Some(l) if l != STDIN => l,
+        _ => {
+            return Err(anyhow!(
+                "the format can only be detected from an access log file"
+            ))
+        }
+    };
+    let lines = lossy_lines(input_source(&access_log, &Progress::hidden())?)
+        .take(DETECT_LINES)
+        .collect::<io::Result<Vec<String>>>()?;
+
+    match detect_format(&lines) {
+        Some((format, rate)) => {
+            eprintln!(
+                "detected the {} format in {} ({:.0}% of the sampled lines matched)",
+                format,
+                access_log,
+                rate * 100.0
+            );
+            Ok(format)
+        }
+        None => Err(anyhow!(
+            "could not detect the format of {}, try passing it with --format",
+            access_log
+        )),
+    }
+}
+
+/// Run topngx with the given options as the command line interface does.
+pub fn execute(mut opts: Options) -> Result<()> {
+    debug!("options: {:?}", opts);
+
+    if let Some(zone) = &opts.timezone {
+        time::set_zone(zone.clone());
+    }
+    // Fix the relative times now that the zone they are in is known.
+    opts.since = opts.since.map(|t| Time::At(t.timestamp()));
+    opts.until = opts.until.map(|t| Time::At(t.timestamp()));
+
+    if !opts.geoip_db.is_empty() {
+        open_geoip_databases(&opts.geoip_db)?;
+    }
+
+    // Swap a named log format for its definition in the NGINX configuration.
+    if let Some(conf) = &opts.nginx_conf {
+        let mut formats = log_formats_from_conf(conf)?;
+        if let Some(format) = formats.remove(&opts.format) {
+            info!("using log_format {} from {}", opts.format, conf.display());
+            opts.format = format;
+        }
+    }
+
+    if opts.influx_url.is_some() {
+        opts.output = OutputFormat::Influx;
+    }
+
+    // Lines received as a syslog server are always new and have a syslog header.
+    if opts.listen_syslog.is_some() {
+        opts.follow = true;
+        opts.syslog = true;
+    }
+    if opts
+        .sample
+        .is_some_and(|rate| rate.is_nan() || rate <= 0.0 || rate > 1.0)
+    {
+        return Err(anyhow!(
+            "the sample rate must be greater than 0 and at most 1"
+        ));
+    }
+    if opts.sample.is_some() && opts.follow {
+        return Err(anyhow!("cannot sample while tailing"));
+    }
+    // The other subcommands would count only the sampled records.
+    if opts.sample.is_some()
+        && !matches!(
+            opts.subcommand,
+            None | Some(SubCommand::Top(_))
+                | Some(SubCommand::Avg(_))
+                | Some(SubCommand::Max(_))
+                | Some(SubCommand::Min(_))
+                | Some(SubCommand::Percentile(_))
+        )
+    {
+        return Err(anyhow!(
+            "only the default report, top, avg, max, min and percentile can be run on a sample"
+        ));
+    }
+    if opts.approximate && (!opts.follow || opts.subcommand.is_some()) {
+        return Err(anyhow!(
+            "approximate counting is only done for the default report when tailing with --follow"
+        ));
+    }
+    if !opts.alert.is_empty() && !opts.follow {
+        return Err(anyhow!(
+            "alerts are only checked when tailing with --follow"
+        ));
+    }
+    if opts.anomaly.is_some_and(|sigmas| sigmas <= 0.0) {
+        return Err(anyhow!(
+            "the number of standard deviations must be greater than 0"
+        ));
+    }
+    if opts.anomaly.is_some() && !opts.follow {
+        return Err(anyhow!(
+            "anomalies are only looked for when tailing with --follow"
+        ));
+    }
+    if opts.last.is_some() && opts.follow {
+        return Err(anyhow!(
+            "--last cannot be used when tailing, use --window instead"
+        ));
+    }
+    if opts.statsd.is_some() && !opts.follow {
+        return Err(anyhow!(
+            "metrics are only sent to StatsD when tailing with --follow"
+        ));
+    }
+
+    // The errors subcommand always reads the error log, so any other format would be ignored.
+    if let Some(SubCommand::Errors(_)) = opts.subcommand {
+        if opts.format_regex.is_some()
+            || ![DEFAULT_FORMAT, ERROR_LOG].contains(&opts.format.as_str())
+        {
+            return Err(anyhow!(
+                "the errors subcommand reads the NGINX error log and cannot be given another format"
+            ));
+        }
+        opts.format = String::from(ERROR_LOG);
+    } else if let Some(pattern) = &opts.format_regex {
+        opts.format = format!("{}{}", REGEX_PREFIX, pattern);
+    } else if opts.format == AUTO {
+        opts.format = auto_format(&opts)?;
+    }
+    // Which columns --having can refer to depends on the format that is finally used.
+    check_having(&opts)?;
+
+    if let Some(sc) = &opts.subcommand {
+        match sc {
+            SubCommand::Apdex(a) => apdex_subcommand(&opts, a.target)?,
+            SubCommand::Avg(f) => avg_subcommand(&opts, f.fields.clone())?,
+            SubCommand::Bandwidth(b) => bandwidth_subcommand(&opts, b.bucket, b.bytes)?,
+            SubCommand::Bots => bots_subcommand(&opts)?,
+            SubCommand::Cache => cache_subcommand(&opts)?,
+            SubCommand::Diff(d) => {
+                diff_subcommand(&opts, d.access_log.clone(), d.at.map(|t| t.timestamp()))?
+            }
+            SubCommand::Errors(b) => errors_subcommand(&opts, b.bucket)?,
+            SubCommand::Export(e) => export_subcommand(&opts, &e.path, e.fields.clone())?,
+            SubCommand::Hist(h) => hist_subcommand(&opts, h.field.clone(), h.buckets.clone())?,
+            SubCommand::Info => info_subcommand(&opts)?,
+            SubCommand::Max(f) => max_subcommand(&opts, f.fields.clone())?,
+            SubCommand::Min(f) => min_subcommand(&opts, f.fields.clone())?,
+            SubCommand::Notfound => notfound_subcommand(&opts)?,
+            SubCommand::Percentile(p) => {
+                percentile_subcommand(&opts, p.field.clone(), p.percentiles.clone())?
+            }
+            SubCommand::Pivot(p) => pivot_subcommand(&opts, p.rows.clone(), p.cols.clone())?,
+            SubCommand::Print(f) => print_subcommand(&opts, f.fields.clone())?,
+            SubCommand::Protocols => protocols_subcommand(&opts)?,
+            SubCommand::Query(q) => query_subcommand(&opts, q.fields.clone(), q.query.clone())?,
+            SubCommand::Rate(b) => rate_subcommand(&opts, b.bucket)?,
+            SubCommand::Referrers(r) => referrers_subcommand(&opts, r.site.clone(), r.pages)?,
+            SubCommand::Repl(f) => repl_subcommand(&opts, f.fields.clone())?,
+            SubCommand::Sessions(s) => sessions_subcommand(&opts, s.timeout)?,
+            SubCommand::Ship(s) => ship_subcommand(&opts, s)?,
+            SubCommand::Slo(s) => slo_subcommand(&opts, s.target, s.window)?,
+            SubCommand::Slowest => slowest_subcommand(&opts)?,
+            SubCommand::Stats(f) => stats_subcommand(&opts, f.fields.clone())?,
+            SubCommand::Sum(f) => sum_subcommand(&opts, f.fields.clone())?,
+            SubCommand::Suspicious => suspicious_subcommand(&opts)?,
+            SubCommand::Timeseries(t) => timeseries_subcommand(&opts, t.bucket, t.smooth)?,
+            SubCommand::Tls => tls_subcommand(&opts)?,
+            SubCommand::Top(f) => top_subcommand(&opts, f.fields.clone())?,
+            SubCommand::Ua => ua_subcommand(&opts)?,
+            SubCommand::Uniques(u) => uniques_subcommand(&opts, u.field.clone())?,
+            SubCommand::Vhosts => vhosts_subcommand(&opts)?,
+            SubCommand::Visitors => visitors_subcommand(&opts)?,
+        }
+        return Ok(());
+    }
+
+    run(&opts, None, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, iter, process};
+
+    use structopt::StructOpt;
+
+    use super::super::alert::{self, Alert};
+    use super::*;
+
+    // Requests from two clients in the combined format, a minute apart until the last one.
+    const LINES: &[&str] = &[
+        r#"1.1.1.1 - - [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 200 100 "-" "curl/7.68.0""#,
+        r#"1.1.1.1 - - [06/Jun/2020:23:17:43 +0000] "GET /about HTTP/1.1" 200 300 "-" "curl/7.68.0""#,
+        r#"2.2.2.2 - - [06/Jun/2020:23:18:43 +0000] "GET / HTTP/1.1" 500 0 "-" "curl/7.68.0""#,
+        r#"2.2.2.2 - - [06/Jun/2020:23:50:43 +0000] "GET /missing HTTP/1.1" 404 50 "https://example.com/" "curl/7.68.0""#,
+    ];
+
+    fn options(args: &[&str]) -> Options {
+        Options::from_iter(iter::once("topngx").chain(args.iter().copied()))
+    }
+
+    // Store the lines of each source in an in-memory database holding the given fields, returning
+    // the processor running the given queries.
+    fn processor(
+        opts: &Options,
+        sources: &[(&str, &[&str])],
+        fields: Option<Vec<String>>,
+        queries: Option<Vec<String>>,
+    ) -> Processor {
+        let parser = LogParser::new(&opts.format).unwrap();
+        let processor = generate_processor(opts, fields, queries).unwrap();
+        for (source, lines) in sources {
+            let lines = lines.iter().map(|l| l.to_string()).collect::<Vec<String>>();
+            let unmatched = parse_input(opts, &lines, source, &parser, &processor).unwrap();
+            assert!(unmatched.is_empty(), "{:?} did not match", unmatched);
+        }
+        processor
+    }
+
+    // Run the queries of a subcommand over the lines, with the values formatted as in a table.
+    fn results(
+        opts: &Options,
+        lines: &[&str],
+        (fields, queries): (Vec<String>, Vec<String>),
+    ) -> Vec<Vec<Vec<String>>> {
+        processor(opts, &[("test", lines)], Some(fields), Some(queries))
+            .results()
+            .unwrap()
+            .iter()
+            .map(rows)
+            .collect()
+    }
+
+    fn rows(result: &QueryResult) -> Vec<Vec<String>> {
+        result
+            .rows
+            .iter()
+            .map(|r| r.iter().map(format_value).collect())
+            .collect()
+    }
+
+    #[test]
+    fn default_report_is_written() {
+        let path = env::temp_dir().join(format!("topngx-report-{}.json", process::id()));
+        let opts = options(&["--output", "json", "--output-file", path.to_str().unwrap()]);
+        processor(&opts, &[("test", LINES)], None, None)
+            .report(false)
+            .unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let reports = written
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<serde_json::Value>>();
+        assert_eq!(reports[0][0]["count"], 4);
+        assert_eq!(reports[0][0]["5XX"], 1);
+        assert_eq!(reports[1][0]["request_path"], "/");
+        assert_eq!(reports[1][0]["count"], 2);
+        assert_eq!(reports[1][0]["error_rate"], 0.5);
+    }
+
+    #[test]
+    fn diff_compares_both_sides() {
+        let opts = options(&[]);
+        let at = 1_591_485_523; // 06/Jun/2020:23:18:43
+        let mut by_time = results(&opts, LINES, diff_queries(&opts, None, Some(at))).remove(0);
+        by_time.sort();
+        assert_eq!(
+            by_time,
+            vec![
+                vec!["/", "1", "0", "1.00", "1.00", "0.00", "-100.00"],
+                vec!["/about", "0", "-1", "null", "0.00", "null", "-300.00"],
+                vec!["/missing", "1", "1", "0.00", "0.00", "50.00", "50.00"],
+            ]
+        );
+
+        // Comparing with another access log splits the records by the file they came from.
+        let queries = diff_queries(&opts, Some("after.log"), None);
+        let sources: &[(&str, &[&str])] =
+            &[("before.log", &LINES[..2]), ("after.log", &LINES[2..])];
+        let mut by_file = processor(&opts, sources, Some(queries.0), Some(queries.1))
+            .results()
+            .unwrap()
+            .iter()
+            .map(rows)
+            .next()
+            .unwrap();
+        by_file.sort();
+        assert_eq!(by_file, by_time);
+    }
+
+    #[test]
+    fn cache_statuses_are_counted() {
+        let opts = options(&[
+            "--format",
+            r#"$remote_addr [$time_local] "$request" $status $body_bytes_sent $upstream_cache_status"#,
+        ]);
+        let lines = &[
+            r#"1.1.1.1 [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 200 100 HIT"#,
+            r#"1.1.1.1 [06/Jun/2020:23:16:44 +0000] "GET / HTTP/1.1" 200 300 MISS"#,
+            r#"1.1.1.1 [06/Jun/2020:23:16:45 +0000] "GET /a HTTP/1.1" 200 200 STALE"#,
+            r#"1.1.1.1 [06/Jun/2020:23:16:46 +0000] "GET /b HTTP/1.1" 200 50 -"#,
+        ];
+        let mut results = results(&opts, lines, cache_queries(&opts).unwrap());
+        results[1].sort();
+        assert_eq!(
+            results,
+            vec![
+                vec![vec!["3", "66.67", "300", "300"]],
+                vec![
+                    vec!["HIT", "1", "33.33", "100"],
+                    vec!["MISS", "1", "33.33", "300"],
+                    vec!["STALE", "1", "33.33", "200"],
+                ],
+                vec![
+                    vec!["/", "2", "50.00", "1", "1", "0", "0", "300"],
+                    vec!["/a", "1", "100.00", "0", "0", "0", "0", "0"],
+                ],
+            ]
+        );
+
+        let opts = options(&[]);
+        assert!(cache_queries(&opts).is_err());
+    }
+
+    #[test]
+    fn sessions_are_split_by_the_timeout() {
+        let opts = options(&[]);
+        let queries = sessions_queries(&opts, Duration::from_secs(600));
+        let mut results = results(&opts, LINES, queries);
+        for result in &mut results[2..] {
+            result.sort();
+        }
+        assert_eq!(
+            results,
+            vec![
+                vec![vec!["3", "2", "1.33", "20.00", "60"]],
+                vec![
+                    vec!["2.2.2.2", "2", "1.00", "0.00"],
+                    vec!["1.1.1.1", "1", "2.00", "60.00"],
+                ],
+                vec![vec!["/", "2", "1.50"], vec!["/missing", "1", "1.00"]],
+                vec![
+                    vec!["/", "1", "1.00"],
+                    vec!["/about", "1", "2.00"],
+                    vec!["/missing", "1", "1.00"],
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_tls_clients_are_found() {
+        let opts = options(&[
+            "--format",
+            r#"$remote_addr [$time_local] "$request" $status $body_bytes_sent "$http_user_agent" $ssl_protocol $ssl_cipher"#,
+        ]);
+        let lines = &[
+            r#"1.1.1.1 [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 200 100 "curl" TLSv1.3 TLS_AES_128_GCM_SHA256"#,
+            r#"1.1.1.1 [06/Jun/2020:23:16:44 +0000] "GET / HTTP/1.1" 200 100 "curl" TLSv1.3 TLS_AES_128_GCM_SHA256"#,
+            r#"2.2.2.2 [06/Jun/2020:23:16:45 +0000] "GET / HTTP/1.1" 200 100 "old" TLSv1 AES128-SHA"#,
+            r#"3.3.3.3 [06/Jun/2020:23:16:46 +0000] "GET / HTTP/1.1" 200 100 "plain" - -"#,
+        ];
+        let results = results(&opts, lines, tls_queries(&opts).unwrap());
+        assert_eq!(
+            results,
+            vec![
+                vec![
+                    vec!["TLSv1.3", "2", "66.67", "no"],
+                    vec!["TLSv1", "1", "33.33", "yes"],
+                ],
+                vec![
+                    vec!["TLS_AES_128_GCM_SHA256", "TLSv1.3", "2", "66.67"],
+                    vec!["AES128-SHA", "TLSv1", "1", "33.33"],
+                ],
+                vec![vec!["2.2.2.2", "TLSv1", "1", "old"]],
+            ]
+        );
+
+        let opts = options(&[]);
+        assert!(tls_queries(&opts).is_err());
+    }
+
+    #[test]
+    fn vhosts_fall_back_to_the_host_header() {
+        let opts = options(&[
+            "--format",
+            r#"$remote_addr [$time_local] "$request" $status $body_bytes_sent "$http_host" $server_name"#,
+        ]);
+        let lines = &[
+            r#"1.1.1.1 [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 200 100 "Example.com:8080" default"#,
+            r#"1.1.1.1 [06/Jun/2020:23:16:44 +0000] "GET / HTTP/1.1" 500 300 "example.com" default"#,
+            r#"2.2.2.2 [06/Jun/2020:23:16:45 +0000] "GET / HTTP/1.1" 404 0 "-" default"#,
+        ];
+        let results = results(&opts, lines, vhosts_queries(&opts).unwrap());
+        assert_eq!(
+            results,
+            vec![vec![
+                vec![
+                    "example.com",
+                    "2",
+                    "66.67",
+                    "400",
+                    "200.00",
+                    "0",
+                    "1",
+                    "0.50"
+                ],
+                vec!["default", "1", "33.33", "0", "0.00", "1", "0", "0.00"],
+            ]]
+        );
+
+        // Grouping the default queries by host works the same way.
+        let opts = options(&["--format", opts.format.as_str(), "--group-by", "host"]);
+        let processor = processor(&opts, &[("test", lines)], None, None);
+        let groups = rows(&processor.results().unwrap()[1]);
+        assert_eq!(groups[0][..2], ["example.com", "2"]);
+
+        let opts = options(&[]);
+        assert!(vhosts_queries(&opts).is_err());
+    }
+
+    #[test]
+    fn bandwidth_is_summed() {
+        let opts = options(&[]);
+        let half_hour = Duration::from_secs(1800);
+        let raw = results(
+            &opts,
+            LINES,
+            bandwidth_queries(&opts, half_hour, true).unwrap(),
+        );
+        assert_eq!(
+            raw,
+            vec![
+                vec![vec!["4", "450", "112.50"]],
+                vec![
+                    vec!["2020-06-06 23:00:00", "3", "400", "0.22"],
+                    vec!["2020-06-06 23:30:00", "1", "50", "0.03"],
+                ],
+                vec![
+                    vec!["/about", "1", "300", "300.00", "66.67"],
+                    vec!["/", "2", "100", "50.00", "22.22"],
+                    vec!["/missing", "1", "50", "50.00", "11.11"],
+                ],
+            ]
+        );
+
+        let queries = bandwidth_queries(&opts, half_hour, false).unwrap();
+        assert_eq!(
+            results(&opts, LINES, queries)[0],
+            vec![vec!["4", "450 B", "113 B"]]
+        );
+        assert!(bandwidth_queries(&opts, Duration::from_millis(10), true).is_err());
+    }
+
+    #[test]
+    fn timeseries_are_smoothed() {
+        let opts = options(&[]);
+        let half_hour = Duration::from_secs(1800);
+        let queries = timeseries_queries(&opts, half_hour, Some(2)).unwrap();
+        assert_eq!(
+            results(&opts, LINES, queries),
+            vec![vec![
+                vec!["2020-06-06 23:00:00", "3", "400", "0", "1", "3.00", "0.33"],
+                vec!["2020-06-06 23:30:00", "1", "50", "1", "0", "2.00", "0.25"],
+            ]]
+        );
+        assert!(timeseries_queries(&opts, half_hour, Some(0)).is_err());
+    }
+
+    #[test]
+    fn missing_paths_are_found() {
+        let opts = options(&[]);
+        let mut lines = LINES.to_vec();
+        lines.push(
+            r#"3.3.3.3 - - [06/Jun/2020:23:51:43 +0000] "GET /missing HTTP/1.1" 404 50 "-" "curl/7.68.0""#,
+        );
+        let mut results = results(&opts, &lines, notfound_queries(&opts).unwrap());
+        results[2].sort();
+        assert_eq!(
+            results,
+            vec![
+                vec![vec!["/missing", "2", "100.00"]],
+                vec![vec!["https://example.com/", "/missing", "1"]],
+                vec![vec!["2.2.2.2", "1", "1"], vec!["3.3.3.3", "1", "1"]],
+            ]
+        );
+    }
+
+    #[test]
+    fn apdex_rates_at_the_boundaries() {
+        let opts = options(&[
+            "--format",
+            r#"$remote_addr [$time_local] "$request" $status $body_bytes_sent $request_time"#,
+        ]);
+        // Up to the target is satisfied and up to four times it tolerating. Server errors are
+        // frustrated however fast they were.
+        let lines = &[
+            r#"1.1.1.1 [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 200 100 0.500"#,
+            r#"1.1.1.1 [06/Jun/2020:23:16:44 +0000] "GET / HTTP/1.1" 200 100 0.501"#,
+            r#"1.1.1.1 [06/Jun/2020:23:16:45 +0000] "GET /a HTTP/1.1" 200 100 2.000"#,
+            r#"1.1.1.1 [06/Jun/2020:23:16:46 +0000] "GET /a HTTP/1.1" 200 100 2.001"#,
+            r#"1.1.1.1 [06/Jun/2020:23:16:47 +0000] "GET /b HTTP/1.1" 500 100 0.010"#,
+        ];
+        let mut results = results(&opts, lines, apdex_queries(&opts, 0.5).unwrap());
+        results[1].sort();
+        assert_eq!(
+            results,
+            vec![
+                vec![vec!["5", "1", "2", "2", "0.40"]],
+                vec![
+                    vec!["/", "2", "1", "1", "0", "0.75"],
+                    vec!["/a", "2", "0", "1", "1", "0.25"],
+                    vec!["/b", "1", "0", "0", "1", "0.00"],
+                ],
+            ]
+        );
+
+        assert!(apdex_queries(&opts, 0.0).is_err());
+        assert!(apdex_queries(&opts, f64::NAN).is_err());
+    }
+
+    #[test]
+    fn error_budget_is_used_up() {
+        let opts = options(&[]);
+        let month = Duration::from_secs(30 * 86400);
+        assert_eq!(
+            results(&opts, LINES, slo_queries(&opts, 99.0, month).unwrap()),
+            vec![
+                vec![vec![
+                    "2020-06-06 23:16:43",
+                    "2020-06-06 23:50:43",
+                    "4",
+                    "1",
+                    "75.00",
+                    "99.00",
+                    "0.04",
+                    "-2400.00",
+                    "25.00",
+                    "25.00",
+                ]],
+                vec![vec!["/", "2", "1", "2500.00"]],
+            ]
+        );
+
+        // A target of 100% leaves no budget, which is only used up by a failure.
+        let queries = slo_queries(&opts, 100.0, month).unwrap();
+        let budget = results(&opts, LINES, queries.clone()).remove(0).remove(0);
+        assert_eq!(budget[6..], ["0.00", "null", "null", "null"]);
+        let budget = results(&opts, &LINES[..2], queries.clone())
+            .remove(0)
+            .remove(0);
+        assert_eq!(budget[6..], ["0.00", "100.00", "0.00", "0.00"]);
+
+        // Without any requests nothing is used either.
+        let budget = results(&opts, &[], queries).remove(0).remove(0);
+        assert_eq!(
+            budget,
+            ["null", "null", "0", "0", "null", "100.00", "0.00", "100.00", "0.00", "0.00"]
+        );
+    }
+
+    #[test]
+    fn visitors_are_counted() {
+        let opts = options(&[]);
+        let fields = vec![String::from(TIMESTAMP), String::from("remote_addr")];
+        let expected = vec![
+            vec![vec!["4", "2"]],
+            vec![vec!["2020-06-06", "4", "2"]],
+            vec![vec!["2020-06-06 23:00", "4", "2"]],
+        ];
+        assert_eq!(
+            results(
+                &opts,
+                LINES,
+                (fields.clone(), visitors_queries(&opts, false))
+            ),
+            expected
+        );
+        assert_eq!(
+            results(&opts, LINES, (fields, visitors_queries(&opts, true))),
+            expected
+        );
+    }
+
+    #[test]
+    fn values_are_pivoted() {
+        let opts = options(&[]);
+        let fields = vec![String::from("remote_addr"), String::from("status")];
+        let processor = processor(&opts, &[("test", LINES)], Some(fields), Some(vec![]));
+        let values = [Value::Integer(200), Value::Integer(404)];
+        let result = processor
+            .query(&pivot_query(&opts, "remote_addr", "status", &values, true))
+            .unwrap();
+        assert_eq!(
+            result.columns,
+            ["remote_addr", "200", "404", "other", "total"]
+        );
+        let mut pivoted = rows(&result);
+        pivoted.sort();
+        assert_eq!(
+            pivoted,
+            vec![
+                vec!["1.1.1.1", "2", "0", "0", "2"],
+                vec!["2.2.2.2", "0", "1", "1", "2"],
+            ]
+        );
+
+        // SQLite stores NaN as NULL, so that is what it is compared as.
+        let values = [Value::Integer(200), Value::Real(f64::NAN)];
+        let result = processor
+            .query(&pivot_query(&opts, "status", "remote_addr", &values, true))
+            .unwrap();
+        assert!(result.rows.iter().all(|r| r[2] == Value::Integer(0)));
+    }
+
+    #[test]
+    fn values_are_written_as_literals() {
+        assert_eq!(sql_literal(&Value::Null), "NULL");
+        assert_eq!(sql_literal(&Value::Integer(-3)), "-3");
+        assert_eq!(sql_literal(&Value::Real(1.0)), "1.0");
+        assert_eq!(sql_literal(&Value::Real(f64::NAN)), "NULL");
+        assert_eq!(sql_literal(&Value::Real(f64::INFINITY)), "9e999");
+        assert_eq!(sql_literal(&Value::Real(f64::NEG_INFINITY)), "-9e999");
+        assert_eq!(sql_literal(&Value::Text(String::from("it's"))), "'it''s'");
+        assert_eq!(sql_literal(&Value::Blob(vec![0, 255])), "X'00FF'");
+
+        // The literals read back as the values they were written from.
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        for value in &[
+            Value::Real(f64::INFINITY),
+            Value::Real(f64::NEG_INFINITY),
+            Value::Real(0.1),
+            Value::Text(String::from("it's")),
+        ] {
+            let read: Value = db
+                .query_row(&format!("SELECT {};", sql_literal(value)), [], |r| r.get(0))
+                .unwrap();
+            assert_eq!(&read, value);
+        }
+    }
+
+    #[test]
+    fn checks_restart_once_the_window_is_empty() {
+        let opts = options(&[]);
+        let mut fields = alert::FIELDS
+            .iter()
+            .map(|f| f.to_string())
+            .collect::<Vec<String>>();
+        fields.push(String::from(TIMESTAMP));
+        let processor = processor(&opts, &[("test", LINES)], Some(fields), Some(vec![]));
+        let alerts = ["count > 0".parse::<Alert>().unwrap()];
+        let mut monitor = Monitor::new(&alerts, 1);
+        assert_eq!(monitor.check(&processor).unwrap().len(), 1);
+        assert!(monitor.check(&processor).unwrap().is_empty());
+
+        // Removing only some of the records leaves the numbering alone.
+        assert_eq!(processor.delete_before(1591485450).unwrap(), 1);
+        restart_checks(&processor, &mut monitor, None, None).unwrap();
+        assert!(monitor.check(&processor).unwrap().is_empty());
+
+        // The new rows are numbered from one again once the window is empty.
+        assert_eq!(processor.delete_before(i64::MAX).unwrap(), 3);
+        restart_checks(&processor, &mut monitor, None, None).unwrap();
+        let parser = LogParser::new(&opts.format).unwrap();
+        let lines = vec![LINES[0].to_string()];
+        parse_input(&opts, &lines, "test", &parser, &processor).unwrap();
+        assert_eq!(
+            monitor.check(&processor).unwrap(),
+            ["ALERT count > 0 (count = 1)"]
+        );
+    }
+}
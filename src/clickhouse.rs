@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use rusqlite::types::Value;
+
+use super::nginx::ColumnType;
+use super::processor::QueryResult;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// Quote a name for ClickHouse, which uses backticks.
+fn quote(name: &str) -> String {
+    format!("`{}`", name.replace('\\', "\\\\").replace('`', "\\`"))
+}
+
+/// A ClickHouse table that records are inserted into through the HTTP interface.
+pub(crate) struct Table<'a> {
+    url: &'a str,
+    name: &'a str,
+    user: Option<&'a str>,
+    password: Option<&'a str>,
+}
+
+impl<'a> Table<'a> {
+    pub(crate) fn new(
+        url: &'a str,
+        name: &'a str,
+        user: Option<&'a str>,
+        password: Option<&'a str>,
+    ) -> Table<'a> {
+        Table {
+            url,
+            name,
+            user,
+            password,
+        }
+    }
+
+    /// Create the table if it does not exist with a column for each field, typed the same way as
+    /// the database columns. Numeric columns are nullable since NGINX logs a dash for a missing
+    /// value.
+    pub(crate) fn create(&self, fields: &[String]) -> Result<()> {
+        let columns = fields
+            .iter()
+            .map(|f| {
+                let column_type = match ColumnType::of(f) {
+                    ColumnType::Integer => "Nullable(Int64)",
+                    ColumnType::Real => "Nullable(Float64)",
+                    ColumnType::Text => "String",
+                };
+                format!("{} {}", quote(f), column_type)
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+        let query = format!(
+            "CREATE TABLE IF NOT EXISTS {} ({}) ENGINE = MergeTree ORDER BY tuple()",
+            self.quoted_name(),
+            columns
+        );
+        self.send(&query, "")
+    }
+
+    /// Insert the rows as JSON with one object per line. A value that does not fit the type of
+    /// its column, such as several upstream times, is sent as null instead of failing the whole
+    /// insert.
+    pub(crate) fn insert(&self, result: &QueryResult) -> Result<()> {
+        let types = result
+            .columns
+            .iter()
+            .map(|c| ColumnType::of(c))
+            .collect::<Vec<ColumnType>>();
+        let mut body = String::new();
+        for row in &result.rows {
+            let object = result
+                .columns
+                .iter()
+                .zip(&types)
+                .zip(row)
+                .map(|((c, t), v)| {
+                    let value = match (t, v) {
+                        (_, Value::Integer(i)) => serde_json::Value::from(*i),
+                        (_, Value::Real(r)) => serde_json::Value::from(*r),
+                        (ColumnType::Text, Value::Text(s)) => serde_json::Value::from(s.as_str()),
+                        (ColumnType::Text, Value::Null) => serde_json::Value::from(""),
+                        _ => serde_json::Value::Null,
+                    };
+                    (c.clone(), value)
+                })
+                .collect::<serde_json::Map<String, serde_json::Value>>();
+            body.push_str(&serde_json::Value::Object(object).to_string());
+            body.push('\n');
+        }
+
+        let query = format!("INSERT INTO {} FORMAT JSONEachRow", self.quoted_name());
+        self.send(&query, &body)
+    }
+
+    // The name of the table with its database, if given, each quoted.
+    fn quoted_name(&self) -> String {
+        self.name
+            .split('.')
+            .map(quote)
+            .collect::<Vec<String>>()
+            .join(".")
+    }
+
+    // Run a query whose data, if any, is in the body of the request.
+    fn send(&self, query: &str, body: &str) -> Result<()> {
+        debug!("clickhouse query: {}", query);
+        let mut request = ureq::post(self.url).timeout(TIMEOUT).query("query", query);
+        if let Some(user) = self.user {
+            request = request.set("X-ClickHouse-User", user);
+        }
+        if let Some(password) = self.password {
+            request = request.set("X-ClickHouse-Key", password);
+        }
+
+        match request.send_string(body) {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(code, response)) => {
+                let message = response.into_string().unwrap_or_default();
+                Err(anyhow!("ClickHouse returned {}: {}", code, message.trim()))
+            }
+            Err(e) => Err(anyhow!("could not reach ClickHouse at {}: {}", self.url, e)),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use log::debug;
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use super::{Options, SubCommand};
+
+// Where the configuration is read from relative to the configuration directory.
+const CONFIG_PATH: &str = "topngx/config.toml";
+
+// A value that can be given either once or as a list.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(v) => vec![v],
+            OneOrMany::Many(v) => v,
+        }
+    }
+}
+
+// Parse each of the values of a list.
+fn parse_all<T>(values: OneOrMany) -> Result<Vec<T>>
+where
+    T: FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    values
+        .into_vec()
+        .iter()
+        .map(|v| v.parse::<T>().map_err(Into::into))
+        .collect()
+}
+
+/// The defaults that can be set in the configuration file. Each of these is only used when the
+/// respective option was not given on the command line.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    access_log: Option<OneOrMany>,
+    format: Option<String>,
+    group_by: Option<String>,
+    limit: Option<u64>,
+    interval: Option<u64>,
+    output: Option<String>,
+    exclude_path: Option<OneOrMany>,
+    exclude_ip: Option<OneOrMany>,
+    exclude_ua: Option<OneOrMany>,
+}
+
+// The default location of the configuration file, following the XDG base directory convention.
+fn default_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".config")))
+        .map(|d| d.join(CONFIG_PATH))
+}
+
+fn read_config(path: &Path) -> Result<Config> {
+    let contents = fs::read_to_string(path)?;
+    Ok(toml::from_str(&contents)?)
+}
+
+impl Options {
+    /// Parse the command line arguments, filling in any options that were not given from the
+    /// configuration file.
+    pub fn load() -> Result<Options> {
+        let matches = Options::clap().get_matches();
+        let mut opts = Options::from_clap(&matches);
+
+        // A configuration file given explicitly must exist.
+        let path = match &opts.config {
+            Some(path) => path.clone(),
+            None => match default_path().filter(|p| p.exists()) {
+                Some(path) => path,
+                None => return Ok(opts),
+            },
+        };
+        let config = read_config(&path)
+            .with_context(|| format!("failed to read the configuration {}", path.display()))?;
+        debug!("configuration: {:?}", config);
+
+        let given = |name: &str| matches.occurrences_of(name) > 0;
+        // The configured access logs are only a fallback for when no other source was given, so
+        // they are not read along with those nor added to a database again. Logs piped in are
+        // read with -a STDIN, since STDIN is not a terminal under cron or systemd either.
+        let reads_elsewhere = given("access-log")
+            || given("s3")
+            || given("journal-unit")
+            || given("listen-syslog")
+            || opts.db.is_some();
+        if let Some(access_log) = config.access_log.filter(|_| !reads_elsewhere) {
+            opts.access_log = access_log.into_vec();
+        }
+        // The errors subcommand reads the error log whatever format the access logs have.
+        let reads_errors = matches!(opts.subcommand, Some(SubCommand::Errors(_)));
+        if let Some(format) = config.format.filter(|_| !given("format") && !reads_errors) {
+            opts.format = format;
+        }
+        if let Some(group_by) = config.group_by.filter(|_| !given("group-by")) {
+            opts.group_by = group_by;
+        }
+        if let Some(limit) = config.limit.filter(|_| !given("limit")) {
+            opts.limit = limit;
+        }
+        if let Some(interval) = config.interval.filter(|_| !given("interval")) {
+            opts.interval = interval;
+        }
+        if let Some(output) = config.output.filter(|_| !given("output")) {
+            opts.output = output.parse()?;
+        }
+        if let Some(paths) = config.exclude_path.filter(|_| !given("exclude-path")) {
+            opts.exclude_path = parse_all(paths)?;
+        }
+        if let Some(ips) = config.exclude_ip.filter(|_| !given("exclude-ip")) {
+            opts.exclude_ip = parse_all(ips)?;
+        }
+        if let Some(uas) = config.exclude_ua.filter(|_| !given("exclude-ua")) {
+            opts.exclude_ua = parse_all(uas)?;
+        }
+
+        Ok(opts)
+    }
+}
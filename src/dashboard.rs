@@ -0,0 +1,344 @@
+use std::io::{self, Stdout};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use log::debug;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::widgets::{Block, Borders, Paragraph, Row, Table, TableState};
+use ratatui::{Frame, Terminal};
+
+use super::delta::{self, Deltas};
+use super::processor::{
+    detailed_query, format_value, summary_query, OrderBy, Processor, QueryResult,
+};
+use super::quantile::{self, Quantiles};
+use super::trend::{Trends, TREND};
+use super::{Options, BYTES_SENT, TIMESTAMP};
+
+const HELP: &str =
+    "q: quit  space: pause  r: reset  up/down: scroll  left/right: sort column  g: next group by";
+
+/// What the caller should do after a key press has been handled.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Action {
+    Redraw,
+    /// Remove the records seen so far and start counting again.
+    Reset,
+    Quit,
+}
+
+/// An interactive terminal dashboard used while tailing log files.
+pub(crate) struct Dashboard {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    keys: Receiver<KeyEvent>,
+    // Dropping this stops the thread reading key presses.
+    _keys_stop: Sender<()>,
+    custom_queries: Option<Vec<String>>,
+    group_by: String,
+    order_by: OrderBy,
+    columns: Vec<String>,
+    state: TableState,
+    alerts: Vec<String>,
+    trends: Trends,
+    deltas: Deltas,
+    quantiles: Option<Quantiles>,
+    // The results that are shown. While paused they are only queried again when what is shown
+    // changes, which is what requery is for.
+    results: Vec<QueryResult>,
+    paused: bool,
+    requery: bool,
+}
+
+impl Dashboard {
+    /// Take over the terminal. When custom queries are given they are shown as is, otherwise the
+    /// default summary and detailed queries are shown and can be regrouped and sorted.
+    pub(crate) fn new(opts: &Options, custom_queries: Option<Vec<String>>) -> Result<Dashboard> {
+        enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+        let terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+        let (keys, _keys_stop) = key_events();
+        let quantiles = quantile::enabled(opts, custom_queries.is_some())?.then(Quantiles::default);
+
+        Ok(Dashboard {
+            terminal,
+            keys,
+            _keys_stop,
+            custom_queries,
+            group_by: opts.group_by.clone(),
+            order_by: opts.order_by.clone(),
+            columns: Vec::new(),
+            state: TableState::default(),
+            alerts: Vec::new(),
+            trends: Trends::default(),
+            deltas: Deltas::default(),
+            quantiles,
+            results: Vec::new(),
+            paused: false,
+            requery: false,
+        })
+    }
+
+    /// The key presses made while the dashboard is shown.
+    pub(crate) fn keys(&self) -> Receiver<KeyEvent> {
+        self.keys.clone()
+    }
+
+    /// Build the percentiles again from the stored records after some of them were removed.
+    pub(crate) fn records_removed(&mut self) {
+        if let Some(q) = self.quantiles.as_mut() {
+            q.reset();
+        }
+    }
+
+    /// Show these alerts in place of the help line until they are replaced.
+    pub(crate) fn set_alerts(&mut self, alerts: Vec<String>) {
+        self.alerts = alerts;
+    }
+
+    /// Run the queries and draw their results. When a new interval has passed the trend of each
+    /// group is updated. While paused the results that are already shown are drawn again instead,
+    /// unless the grouping or sorting changed.
+    pub(crate) fn draw(&mut self, opts: &Options, processor: &Processor, tick: bool) -> Result<()> {
+        if !self.paused || self.requery {
+            let mut results = match &self.custom_queries {
+                Some(queries) => queries
+                    .iter()
+                    .map(|q| processor.query(q))
+                    .collect::<Result<Vec<QueryResult>>>()?,
+                None => vec![
+                    processor.query(&summary_query(opts))?,
+                    processor.query(&detailed_query(opts, &self.group_by, &self.order_by)?)?,
+                ],
+            };
+
+            if self.custom_queries.is_none() {
+                self.deltas.add_columns(&mut results, tick);
+                if let Some(last) = results.last_mut() {
+                    if let Some(q) = self.quantiles.as_mut() {
+                        q.add_columns(processor, last)?;
+                    }
+                    self.trends.add_column(last, tick);
+                }
+            }
+            self.results = results;
+            self.requery = false;
+        }
+
+        // Keep the selected row within the rows that were returned.
+        if let Some(last) = self.results.last() {
+            self.columns = last.columns.clone();
+            match (self.state.selected(), last.rows.len()) {
+                (_, 0) => self.state.select(None),
+                (None, _) => self.state.select(Some(0)),
+                (Some(s), n) if s >= n => self.state.select(Some(n - 1)),
+                _ => {}
+            }
+        }
+
+        let sort_column = match self.custom_queries {
+            Some(_) => None,
+            None => Some(self.order_by.first()),
+        };
+        let results = &self.results;
+        let state = &mut self.state;
+        let alerts = &self.alerts;
+        let paused = self.paused;
+        self.terminal
+            .draw(|f| render(f, results, sort_column, state, alerts, paused))?;
+
+        Ok(())
+    }
+
+    /// React to a key press.
+    pub(crate) fn handle_key(&mut self, key: KeyEvent, fields: &[String]) -> Action {
+        if key.kind != KeyEventKind::Press {
+            return Action::Redraw;
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Action::Quit,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Action::Quit
+            }
+            // The records keep being stored while paused.
+            KeyCode::Char(' ') => {
+                self.paused = !self.paused;
+                debug!("dashboard paused: {}", self.paused);
+            }
+            KeyCode::Char('r') => {
+                self.trends = Trends::default();
+                self.deltas = Deltas::default();
+                if let Some(q) = self.quantiles.as_mut() {
+                    q.reset();
+                }
+                self.requery = true;
+                return Action::Reset;
+            }
+            KeyCode::Up => {
+                let selected = self.state.selected().unwrap_or(0);
+                self.state.select(Some(selected.saturating_sub(1)));
+            }
+            KeyCode::Down => {
+                let selected = self.state.selected().unwrap_or(0);
+                self.state.select(Some(selected + 1));
+            }
+            KeyCode::Left | KeyCode::Right if self.custom_queries.is_none() => {
+                // The grouped field itself is not something to sort by.
+                let sortable = self
+                    .columns
+                    .iter()
+                    .skip(1)
+                    .filter(|c| {
+                        *c != TREND
+                            && !quantile::COLUMNS.contains(&c.as_str())
+                            && !delta::COLUMNS.contains(&c.as_str())
+                    })
+                    .collect::<Vec<&String>>();
+                if !sortable.is_empty() {
+                    let current = sortable.iter().position(|c| *c == self.order_by.first());
+                    let next = match (key.code, current) {
+                        (KeyCode::Right, Some(i)) => (i + 1) % sortable.len(),
+                        (KeyCode::Left, Some(i)) => (i + sortable.len() - 1) % sortable.len(),
+                        _ => 0,
+                    };
+                    self.order_by = OrderBy::column(sortable[next]);
+                    self.requery = true;
+                    debug!("dashboard sorting by {}", self.order_by);
+                }
+            }
+            KeyCode::Char('g') if self.custom_queries.is_none() => {
+                let groupable = fields
+                    .iter()
+                    .filter(|f| *f != BYTES_SENT && *f != TIMESTAMP)
+                    .collect::<Vec<&String>>();
+                if !groupable.is_empty() {
+                    let next = groupable
+                        .iter()
+                        .position(|f| **f == self.group_by)
+                        .map_or(0, |i| (i + 1) % groupable.len());
+                    self.group_by = groupable[next].clone();
+                    self.state.select(Some(0));
+                    self.requery = true;
+                    debug!("dashboard grouping by {}", self.group_by);
+                }
+            }
+            _ => {}
+        }
+
+        Action::Redraw
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        // There is nothing more we can do if restoring the terminal fails.
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+    }
+}
+
+fn result_table<'a>(
+    result: &'a QueryResult,
+    title: &'a str,
+    sort_column: Option<&str>,
+) -> Table<'a> {
+    let header = Row::new(result.columns.iter().map(|c| {
+        if sort_column == Some(c.as_str()) {
+            format!("{} ▼", c)
+        } else {
+            c.clone()
+        }
+    }))
+    .style(Style::default().add_modifier(Modifier::BOLD));
+    let rows = result
+        .rows
+        .iter()
+        .map(|r| Row::new(r.iter().map(format_value)));
+
+    // Give the first column, usually the grouped field, the most room.
+    let widths = (0..result.columns.len()).map(|i| {
+        if i == 0 && result.columns.len() > 1 {
+            Constraint::Min(20)
+        } else {
+            Constraint::Length(14)
+        }
+    });
+
+    Table::new(rows, widths)
+        .header(header)
+        .block(Block::default().borders(Borders::ALL).title(title))
+}
+
+fn render(
+    f: &mut Frame,
+    results: &[QueryResult],
+    sort_column: Option<&str>,
+    state: &mut TableState,
+    alerts: &[String],
+    paused: bool,
+) {
+    let (last, rest) = match results.split_last() {
+        Some(r) => r,
+        None => return,
+    };
+
+    // Every result but the last gets exactly the room it needs and the last one, which can be
+    // scrolled through, gets whatever is left over.
+    let mut constraints = rest
+        .iter()
+        .map(|r| Constraint::Length(r.rows.len() as u16 + 3))
+        .collect::<Vec<Constraint>>();
+    constraints.push(Constraint::Min(3));
+    constraints.push(Constraint::Length(alerts.len().max(1) as u16));
+    let areas = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(constraints)
+        .split(f.size());
+
+    for (result, area) in rest.iter().zip(areas.iter()) {
+        f.render_widget(result_table(result, "summary", None), *area);
+    }
+
+    let title = if paused { "detail (paused)" } else { "detail" };
+    let table = result_table(last, title, sort_column)
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    f.render_stateful_widget(table, areas[areas.len() - 2], state);
+
+    let help_area: Rect = areas[areas.len() - 1];
+    if alerts.is_empty() {
+        f.render_widget(Paragraph::new(HELP), help_area);
+    } else {
+        let style = Style::default().fg(Color::Red).add_modifier(Modifier::BOLD);
+        f.render_widget(Paragraph::new(alerts.join("\n")).style(style), help_area);
+    }
+}
+
+// Read key presses on a separate thread until the returned stop channel is dropped.
+fn key_events() -> (Receiver<KeyEvent>, Sender<()>) {
+    let (key_tx, key_rx) = unbounded();
+    let (stop_tx, stop_rx) = unbounded::<()>();
+
+    thread::spawn(move || -> Result<()> {
+        loop {
+            if let Err(TryRecvError::Disconnected) = stop_rx.try_recv() {
+                return Ok(());
+            }
+            if event::poll(Duration::from_millis(100))? {
+                if let Event::Key(key) = event::read()? {
+                    key_tx.send(key)?;
+                }
+            }
+        }
+    });
+
+    (key_rx, stop_tx)
+}
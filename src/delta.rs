@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use rusqlite::types::Value;
+
+use super::processor::QueryResult;
+
+/// The columns holding how many requests and 5XX responses each row got during the last
+/// interval, and how the request count changed since the interval before it.
+pub(crate) const COLUMNS: &[&str] = &["interval_count", "interval_5xx", "change"];
+
+// The counts of a row when it was last recorded, and what they grew by during that interval.
+#[derive(Default)]
+struct Counts {
+    total: i64,
+    errors: i64,
+    interval: i64,
+    interval_errors: i64,
+    change: Option<f64>,
+}
+
+// The counts of each row of one result, by the value of its first column unless that is the
+// count itself, as in the summary.
+#[derive(Default)]
+struct ResultDeltas {
+    first_column: String,
+    rows: HashMap<String, Counts>,
+}
+
+/// Remembers the counts of each row of the default queries while tailing so that what was added
+/// during the last interval can be shown next to the totals.
+#[derive(Default)]
+pub(crate) struct Deltas {
+    results: Vec<ResultDeltas>,
+}
+
+impl Deltas {
+    /// Add the interval columns to every result with count and 5XX columns, first recording the
+    /// latest counts when a new interval has passed.
+    pub(crate) fn add_columns(&mut self, results: &mut [QueryResult], record: bool) {
+        self.results
+            .resize_with(results.len(), ResultDeltas::default);
+        for (deltas, result) in self.results.iter_mut().zip(results.iter_mut()) {
+            deltas.add_columns(result, record);
+        }
+    }
+}
+
+impl ResultDeltas {
+    fn add_columns(&mut self, result: &mut QueryResult, record: bool) {
+        let position = |name: &str| result.columns.iter().position(|c| c == name);
+        let (count, errors) = match (position("count"), position("5XX")) {
+            (Some(c), Some(e)) => (c, e),
+            _ => return,
+        };
+        // Start over when the groups change, such as when regrouping in the dashboard.
+        let first_column = result.columns.first().cloned().unwrap_or_default();
+        if first_column != self.first_column {
+            self.first_column = first_column;
+            self.rows.clear();
+        }
+        let grouped = count != 0;
+
+        result.columns.extend(COLUMNS.iter().map(|c| c.to_string()));
+        for row in &mut result.rows {
+            let group = match &row[0] {
+                _ if !grouped => String::new(),
+                Value::Text(t) => t.clone(),
+                Value::Integer(i) => i.to_string(),
+                Value::Real(r) => r.to_string(),
+                _ => String::new(),
+            };
+            let integer = |v: &Value| match v {
+                Value::Integer(i) => *i,
+                _ => 0,
+            };
+            let (total, total_errors) = (integer(&row[count]), integer(&row[errors]));
+
+            // The counts only ever cover everything seen so far, so the change since the last
+            // interval is what was added. It can shrink when records fall out of the window.
+            let counts = self.rows.entry(group).or_default();
+            if record {
+                let interval = (total - counts.total).max(0);
+                let previous = counts.interval;
+                *counts = Counts {
+                    total,
+                    errors: total_errors,
+                    interval,
+                    interval_errors: (total_errors - counts.errors).max(0),
+                    change: (previous > 0)
+                        .then(|| 100.0 * (interval - previous) as f64 / previous as f64),
+                };
+            }
+            row.push(Value::Integer(counts.interval));
+            row.push(Value::Integer(counts.interval_errors));
+            row.push(
+                counts
+                    .change
+                    .map_or(Value::Null, |c| Value::Text(change(c))),
+            );
+        }
+    }
+}
+
+fn change(percent: f64) -> String {
+    let rounded = percent.round();
+    if rounded > 0.0 {
+        format!("▲ {}%", rounded)
+    } else if rounded < 0.0 {
+        format!("▼ {}%", -rounded)
+    } else {
+        String::from("0%")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(rows: &[(&str, i64, i64)]) -> QueryResult {
+        QueryResult {
+            columns: vec![
+                String::from("request_path"),
+                String::from("count"),
+                String::from("5XX"),
+            ],
+            rows: rows
+                .iter()
+                .map(|(p, c, e)| {
+                    vec![
+                        Value::Text(p.to_string()),
+                        Value::Integer(*c),
+                        Value::Integer(*e),
+                    ]
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn intervals_are_compared() {
+        let mut deltas = Deltas::default();
+        let mut results = [result(&[("/a", 10, 1)])];
+        deltas.add_columns(&mut results, true);
+        assert_eq!(results[0].columns.len(), 6);
+        assert_eq!(
+            results[0].rows[0][3..],
+            [Value::Integer(10), Value::Integer(1), Value::Null]
+        );
+
+        let mut results = [result(&[("/a", 25, 1), ("/b", 4, 0)])];
+        deltas.add_columns(&mut results, true);
+        let change = |r: &QueryResult, i: usize| r.rows[i][5].clone();
+        assert_eq!(results[0].rows[0][3], Value::Integer(15));
+        assert_eq!(results[0].rows[0][4], Value::Integer(0));
+        assert_eq!(change(&results[0], 0), Value::Text(String::from("▲ 50%")));
+        assert_eq!(change(&results[0], 1), Value::Null);
+
+        // Drawing again without a new interval shows the same values.
+        let mut results = [result(&[("/a", 30, 2)])];
+        deltas.add_columns(&mut results, false);
+        assert_eq!(change(&results[0], 0), Value::Text(String::from("▲ 50%")));
+
+        let mut results = [result(&[("/a", 30, 2)])];
+        deltas.add_columns(&mut results, true);
+        assert_eq!(results[0].rows[0][3], Value::Integer(5));
+        assert_eq!(change(&results[0], 0), Value::Text(String::from("▼ 67%")));
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Sender};
+use log::debug;
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use rusqlite::types::Value;
+
+use super::processor::QueryResult;
+
+// How long a report waits for the lookups, how many are made at once, and how many addresses can
+// wait for one. Addresses that do not fit are queued again by the next report.
+const TIMEOUT: Duration = Duration::from_secs(2);
+const LOOKUPS: usize = 16;
+const QUEUED: usize = 1024;
+
+// How many of the names found are kept, dropping the least recently shown ones first so that they
+// do not pile up while tailing.
+const KEPT: usize = 10_000;
+
+// The names found so far, or None for addresses that do not have one, and the addresses waiting
+// for a lookup. Lookups that are still going when a report stops waiting fill this in for the
+// next one.
+struct Names {
+    found: LruCache<IpAddr, Option<String>>,
+    queued: HashSet<IpAddr>,
+}
+
+// The names along with the condition signalled whenever a lookup finishes.
+static NAMES: Lazy<(Mutex<Names>, Condvar)> = Lazy::new(|| {
+    let names = Names {
+        found: LruCache::new(NonZeroUsize::new(KEPT).unwrap()),
+        queued: HashSet::new(),
+    };
+    (Mutex::new(names), Condvar::new())
+});
+
+// The queue of the lookup threads, which are started the first time it is used. A lookup can take
+// as long as the resolver keeps retrying, so the same threads serve every report rather than
+// piling up more of them while tailing.
+static QUEUE: Lazy<Sender<IpAddr>> = Lazy::new(|| {
+    let (tx, rx) = bounded::<IpAddr>(QUEUED);
+    for _ in 0..LOOKUPS {
+        let rx = rx.clone();
+        thread::spawn(move || {
+            for addr in rx {
+                let name = lookup(addr);
+                debug!("{} resolved to {:?}", addr, name);
+                let (names, done) = &*NAMES;
+                let mut names = names.lock().unwrap();
+                names.queued.remove(&addr);
+                names.found.put(addr, name);
+                done.notify_all();
+            }
+        });
+    }
+    tx
+});
+
+// Ask the system resolver for the name of an address, which does not take a timeout itself.
+fn lookup(addr: IpAddr) -> Option<String> {
+    dns_lookup::lookup_addr(&addr).ok()
+}
+
+// Queue the addresses that are not known or queued yet, waiting until they are looked up or the
+// time is up.
+fn resolve(addrs: &[IpAddr]) {
+    let (names, done) = &*NAMES;
+    let mut names = names.lock().unwrap();
+    for addr in addrs {
+        if !names.found.contains(addr)
+            && !names.queued.contains(addr)
+            && QUEUE.try_send(*addr).is_ok()
+        {
+            names.queued.insert(*addr);
+        }
+    }
+
+    let deadline = Instant::now() + TIMEOUT;
+    while addrs.iter().any(|a| names.queued.contains(a)) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            debug!("gave up waiting for reverse DNS lookups");
+            break;
+        }
+        names = done.wait_timeout(names, left).unwrap().0;
+    }
+}
+
+/// Add a remote_host column after every remote_addr column holding the name the address resolves
+/// to, or null when it has none or the lookup did not finish in time.
+pub(crate) fn add_hostnames(results: &[QueryResult]) -> Vec<QueryResult> {
+    let addr = |v: &Value| match v {
+        Value::Text(t) => t.parse::<IpAddr>().ok(),
+        _ => None,
+    };
+
+    let mut pending = Vec::new();
+    {
+        let names = NAMES.0.lock().unwrap();
+        for result in results {
+            if let Some(i) = result.columns.iter().position(|c| c == "remote_addr") {
+                for a in result.rows.iter().filter_map(|r| addr(&r[i])) {
+                    if !names.found.contains(&a) && !pending.contains(&a) {
+                        pending.push(a);
+                    }
+                }
+            }
+        }
+    }
+    if !pending.is_empty() {
+        resolve(&pending);
+    }
+
+    let mut names = NAMES.0.lock().unwrap();
+    results
+        .iter()
+        .map(|result| {
+            let i = match result.columns.iter().position(|c| c == "remote_addr") {
+                Some(i) => i,
+                None => {
+                    return QueryResult {
+                        columns: result.columns.clone(),
+                        rows: result.rows.clone(),
+                    }
+                }
+            };
+            let mut columns = result.columns.clone();
+            columns.insert(i + 1, String::from("remote_host"));
+            let rows = result
+                .rows
+                .iter()
+                .map(|r| {
+                    let name = addr(&r[i])
+                        .and_then(|a| names.found.get(&a).cloned().flatten())
+                        .map_or(Value::Null, Value::Text);
+                    let mut row = r.clone();
+                    row.insert(i + 1, name);
+                    row
+                })
+                .collect();
+            QueryResult { columns, rows }
+        })
+        .collect()
+}
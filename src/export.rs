@@ -0,0 +1,225 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use rusqlite::types::Value;
+
+use super::processor::QueryResult;
+
+// Write the rows as CSV. NULL values are written as empty fields.
+fn write_csv<W: Write>(writer: &mut csv::Writer<W>, rows: &[Vec<Value>]) -> Result<()> {
+    for row in rows {
+        writer.write_record(row.iter().map(|val| match val {
+            Value::Null => String::new(),
+            Value::Integer(i) => i.to_string(),
+            Value::Real(r) => r.to_string(),
+            Value::Text(t) => t.clone(),
+            Value::Blob(b) => String::from_utf8_lossy(b).into_owned(),
+        }))?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_file {
+    use std::fs::File;
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use parquet::basic::{LogicalType, Repetition, Type as PhysicalType};
+    use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::types::Type;
+    use rusqlite::types::Value;
+
+    use crate::nginx::ColumnType;
+
+    // The most rows written to a single row group.
+    const ROW_GROUP_SIZE: usize = 100_000;
+
+    /// A Parquet file with a nullable column for each field, typed the same way as the database
+    /// columns. Values that do not fit the type of their column are written as nulls.
+    pub(super) struct Writer {
+        writer: SerializedFileWriter<File>,
+        types: Vec<ColumnType>,
+    }
+
+    impl Writer {
+        pub(super) fn new(file: File, columns: &[String]) -> Result<Writer> {
+            let types = columns
+                .iter()
+                .map(|c| ColumnType::of(c))
+                .collect::<Vec<ColumnType>>();
+            let fields = columns
+                .iter()
+                .zip(&types)
+                .map(|(name, t)| {
+                    let builder = match t {
+                        ColumnType::Integer => {
+                            Type::primitive_type_builder(name, PhysicalType::INT64)
+                        }
+                        ColumnType::Real => {
+                            Type::primitive_type_builder(name, PhysicalType::DOUBLE)
+                        }
+                        ColumnType::Text => {
+                            Type::primitive_type_builder(name, PhysicalType::BYTE_ARRAY)
+                                .with_logical_type(Some(LogicalType::String))
+                        }
+                    };
+                    Ok(Arc::new(
+                        builder.with_repetition(Repetition::OPTIONAL).build()?,
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let schema = Arc::new(
+                Type::group_type_builder("log")
+                    .with_fields(fields)
+                    .build()?,
+            );
+
+            let writer =
+                SerializedFileWriter::new(file, schema, Arc::new(WriterProperties::default()))?;
+            Ok(Writer { writer, types })
+        }
+
+        pub(super) fn write(&mut self, rows: &[Vec<Value>]) -> Result<()> {
+            let types = &self.types;
+            for rows in rows.chunks(ROW_GROUP_SIZE) {
+                let mut row_group = self.writer.next_row_group()?;
+                let mut index = 0;
+                while let Some(mut column) = row_group.next_column()? {
+                    let values = rows.iter().map(|r| &r[index]);
+                    // A definition level of one marks a value that is present.
+                    let mut levels = Vec::with_capacity(rows.len());
+                    match types[index] {
+                        ColumnType::Integer => {
+                            let mut present = Vec::with_capacity(rows.len());
+                            for v in values {
+                                let i = match v {
+                                    Value::Integer(i) => Some(*i),
+                                    _ => None,
+                                };
+                                present.extend(i);
+                                levels.push(i16::from(i.is_some()));
+                            }
+                            column.typed::<Int64Type>().write_batch(
+                                &present,
+                                Some(&levels),
+                                None,
+                            )?;
+                        }
+                        ColumnType::Real => {
+                            let mut present = Vec::with_capacity(rows.len());
+                            for v in values {
+                                let r = match v {
+                                    Value::Real(r) => Some(*r),
+                                    Value::Integer(i) => Some(*i as f64),
+                                    _ => None,
+                                };
+                                present.extend(r);
+                                levels.push(i16::from(r.is_some()));
+                            }
+                            column.typed::<DoubleType>().write_batch(
+                                &present,
+                                Some(&levels),
+                                None,
+                            )?;
+                        }
+                        ColumnType::Text => {
+                            let mut present = Vec::with_capacity(rows.len());
+                            for v in values {
+                                let t = match v {
+                                    Value::Null => None,
+                                    Value::Integer(i) => Some(i.to_string()),
+                                    Value::Real(r) => Some(r.to_string()),
+                                    Value::Text(t) => Some(t.clone()),
+                                    Value::Blob(b) => Some(String::from_utf8_lossy(b).into_owned()),
+                                };
+                                levels.push(i16::from(t.is_some()));
+                                present.extend(t.map(|t| ByteArray::from(t.into_bytes())));
+                            }
+                            column.typed::<ByteArrayType>().write_batch(
+                                &present,
+                                Some(&levels),
+                                None,
+                            )?;
+                        }
+                    }
+                    column.close()?;
+                    index += 1;
+                }
+                row_group.close()?;
+            }
+            Ok(())
+        }
+
+        pub(super) fn close(self) -> Result<()> {
+            self.writer.close()?;
+            Ok(())
+        }
+    }
+}
+
+// The writer of each format that can be exported to.
+enum Writer {
+    Csv(csv::Writer<Box<dyn Write>>),
+    #[cfg(feature = "parquet")]
+    Parquet(parquet_file::Writer),
+}
+
+/// A file that records are exported to a page of rows at a time.
+pub(crate) struct Export(Writer);
+
+impl Export {
+    /// Create the file at the given path in the format named by its extension, with a column for
+    /// each of the given fields.
+    pub(crate) fn create(path: &Path, columns: &[String]) -> Result<Export> {
+        debug!("exporting {} to {}", columns.join(", "), path.display());
+
+        let csv = |out: Box<dyn Write>| -> Result<Export> {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record(columns)?;
+            Ok(Export(Writer::Csv(writer)))
+        };
+        if path == Path::new("-") {
+            return csv(Box::new(io::stdout()));
+        }
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => csv(Box::new(File::create(path)?)),
+            #[cfg(feature = "parquet")]
+            Some("parquet") => Ok(Export(Writer::Parquet(parquet_file::Writer::new(
+                File::create(path)?,
+                columns,
+            )?))),
+            #[cfg(not(feature = "parquet"))]
+            Some("parquet") => Err(anyhow!(
+                "topngx was built without Parquet support, enable the parquet feature"
+            )),
+            _ => Err(anyhow!(
+                "cannot tell the export format of {}, use a .csv or .parquet extension",
+                path.display()
+            )),
+        }
+    }
+
+    /// Add the rows of a page to the file.
+    pub(crate) fn write(&mut self, result: &QueryResult) -> Result<()> {
+        match &mut self.0 {
+            Writer::Csv(writer) => write_csv(writer, &result.rows),
+            #[cfg(feature = "parquet")]
+            Writer::Parquet(writer) => writer.write(&result.rows),
+        }
+    }
+
+    /// Write out whatever is still buffered and finish the file.
+    pub(crate) fn finish(self) -> Result<()> {
+        match self.0 {
+            Writer::Csv(mut writer) => Ok(writer.flush()?),
+            #[cfg(feature = "parquet")]
+            Writer::Parquet(writer) => writer.close(),
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use rusqlite::types::Value;
+
+/// A boolean expression over the fields of a record, for example
+/// `status >= 500 && request_path =~ /api/`. The SQL spellings `AND`, `OR`, `NOT`, `=` and `<>` can
+/// be used as well.
+#[derive(Debug)]
+pub enum Expression {
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    Compare(String, Operator, Literal),
+    Matches(String, Regex, bool),
+}
+
+/// The comparison operators that can be applied to a field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// The value a field is compared against.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(Literal),
+    Regex(String),
+    Operator(Operator),
+    Match(bool),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let chars = s.chars().collect::<Vec<char>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        let (token, width) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Operator(Operator::Eq), 2),
+            ('<', Some('>')) => (Token::Operator(Operator::Ne), 2),
+            ('=', Some('~')) => (Token::Match(true), 2),
+            ('!', Some('~')) => (Token::Match(false), 2),
+            ('!', Some('=')) => (Token::Operator(Operator::Ne), 2),
+            ('<', Some('=')) => (Token::Operator(Operator::Le), 2),
+            ('>', Some('=')) => (Token::Operator(Operator::Ge), 2),
+            ('<', _) => (Token::Operator(Operator::Lt), 1),
+            ('>', _) => (Token::Operator(Operator::Gt), 1),
+            ('=', _) => (Token::Operator(Operator::Eq), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            ('"', _) | ('\'', _) | ('/', _) => {
+                // Quoted strings and regular expressions run until the closing delimiter. A
+                // backslash only escapes the delimiter itself so that regex escapes still work.
+                let mut value = String::new();
+                let mut j = i + 1;
+                loop {
+                    match chars.get(j) {
+                        Some('\\') if chars.get(j + 1) == Some(&c) => {
+                            value.push(c);
+                            j += 2;
+                        }
+                        Some(&d) if d == c => break,
+                        Some(&d) => {
+                            value.push(d);
+                            j += 1;
+                        }
+                        None => return Err(anyhow!("missing closing {} in expression", c)),
+                    }
+                }
+                let token = if c == '/' {
+                    Token::Regex(value)
+                } else {
+                    Token::Literal(Literal::Text(value))
+                };
+                (token, j + 1 - i)
+            }
+            _ => {
+                let mut j = i;
+                while j < chars.len() && (chars[j].is_alphanumeric() || "_.-:".contains(chars[j])) {
+                    j += 1;
+                }
+                if j == i {
+                    return Err(anyhow!("unexpected character in expression: {}", c));
+                }
+                let word = chars[i..j].iter().collect::<String>();
+                // Words such as inf and nan are names rather than numbers.
+                let numeric = word
+                    .strip_prefix('-')
+                    .unwrap_or(&word)
+                    .starts_with(|c: char| c.is_ascii_digit() || c == '.');
+                let token = match (word.parse::<f64>(), word.to_ascii_uppercase().as_str()) {
+                    (Ok(n), _) if numeric => Token::Literal(Literal::Number(n)),
+                    (_, "AND") => Token::And,
+                    (_, "OR") => Token::Or,
+                    (_, "NOT") => Token::Not,
+                    _ => Token::Ident(word),
+                };
+                (token, j - i)
+            }
+        };
+
+        tokens.push(token);
+        i += width;
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expression> {
+        let mut left = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            left = Expression::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expression> {
+        let mut left = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            left = Expression::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expression::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expression = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expression),
+                    _ => Err(anyhow!("missing closing parenthesis in expression")),
+                }
+            }
+            Some(Token::Ident(field)) => match (self.next(), self.next()) {
+                (Some(Token::Operator(op)), Some(Token::Literal(l))) => {
+                    Ok(Expression::Compare(field, op, l))
+                }
+                // A bare word is compared as text, for example request_method == GET.
+                (Some(Token::Operator(op)), Some(Token::Ident(word))) => {
+                    Ok(Expression::Compare(field, op, Literal::Text(word)))
+                }
+                (Some(Token::Match(matches)), Some(Token::Regex(r)))
+                | (Some(Token::Match(matches)), Some(Token::Literal(Literal::Text(r)))) => {
+                    Ok(Expression::Matches(field, Regex::new(&r)?, matches))
+                }
+                _ => Err(anyhow!("expected a comparison after {}", field)),
+            },
+            Some(t) => Err(anyhow!("unexpected {:?} in expression", t)),
+            None => Err(anyhow!("unexpected end of expression")),
+        }
+    }
+}
+
+impl FromStr for Expression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            position: 0,
+        };
+        let expression = parser.or()?;
+        match parser.peek() {
+            None => Ok(expression),
+            Some(t) => Err(anyhow!("unexpected {:?} in expression", t)),
+        }
+    }
+}
+
+// Compare numerically when both sides are numbers and as text otherwise, giving nothing when a
+// number is compared with text that is not one.
+fn compare(value: &Value, literal: &Literal) -> Option<Ordering> {
+    let text = match value {
+        Value::Null => return None,
+        Value::Integer(i) => i.to_string(),
+        Value::Real(r) => r.to_string(),
+        Value::Text(t) => t.clone(),
+        Value::Blob(b) => String::from_utf8_lossy(b).into_owned(),
+    };
+
+    match literal {
+        Literal::Number(n) => text.parse::<f64>().ok()?.partial_cmp(n),
+        Literal::Text(t) => Some(text.as_str().cmp(t.as_str())),
+    }
+}
+
+// Quote a field so that names like 2XX can be used in SQL.
+fn quote_identifier(field: &str) -> String {
+    format!("\"{}\"", field.replace('"', "\"\""))
+}
+
+fn quote_text(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+impl Expression {
+    /// Every field that the expression refers to.
+    pub fn fields(&self) -> Vec<&str> {
+        match self {
+            Expression::And(l, r) | Expression::Or(l, r) => {
+                let mut fields = l.fields();
+                fields.extend(r.fields());
+                fields
+            }
+            Expression::Not(e) => e.fields(),
+            Expression::Compare(field, _, _) | Expression::Matches(field, _, _) => vec![field],
+        }
+    }
+
+    /// Write the expression as SQL. Regular expressions are matched with the regexp function
+    /// that the processor registers.
+    pub fn to_sql(&self) -> String {
+        match self {
+            Expression::And(l, r) => format!("({} AND {})", l.to_sql(), r.to_sql()),
+            Expression::Or(l, r) => format!("({} OR {})", l.to_sql(), r.to_sql()),
+            Expression::Not(e) => format!("NOT {}", e.to_sql()),
+            Expression::Compare(field, op, literal) => {
+                let op = match op {
+                    Operator::Eq => "=",
+                    Operator::Ne => "!=",
+                    Operator::Lt => "<",
+                    Operator::Le => "<=",
+                    Operator::Gt => ">",
+                    Operator::Ge => ">=",
+                };
+                // SQLite has no literals for the infinities, but reads numbers too large for a
+                // double as them.
+                let literal = match literal {
+                    Literal::Number(n) if n.is_nan() => String::from("NULL"),
+                    Literal::Number(n) if n.is_infinite() => {
+                        String::from(if *n > 0.0 { "9e999" } else { "-9e999" })
+                    }
+                    Literal::Number(n) => n.to_string(),
+                    Literal::Text(t) => quote_text(t),
+                };
+                format!("{} {} {}", quote_identifier(field), op, literal)
+            }
+            Expression::Matches(field, regex, matches) => format!(
+                "{}regexp({}, {})",
+                if *matches { "" } else { "NOT " },
+                quote_text(regex.as_str()),
+                quote_identifier(field)
+            ),
+        }
+    }
+
+    /// Evaluate the expression using the given function to look up the value of a field. As in
+    /// SQL a comparison with a NULL value is unknown rather than true or false, and the expression
+    /// only holds when it is true.
+    pub fn evaluate<F: Fn(&str) -> Value>(&self, lookup: &F) -> bool {
+        self.truth(lookup) == Some(true)
+    }
+
+    // The truth of the expression in the three-valued logic of SQL, where None is unknown.
+    fn truth<F: Fn(&str) -> Value>(&self, lookup: &F) -> Option<bool> {
+        match self {
+            Expression::And(l, r) => match (l.truth(lookup), r.truth(lookup)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Expression::Or(l, r) => match (l.truth(lookup), r.truth(lookup)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+            Expression::Not(e) => e.truth(lookup).map(|t| !t),
+            Expression::Compare(field, op, literal) => {
+                let value = lookup(field);
+                if value == Value::Null {
+                    return None;
+                }
+                let ordering = match compare(&value, literal) {
+                    Some(o) => o,
+                    None => return Some(*op == Operator::Ne),
+                };
+                Some(match op {
+                    Operator::Eq => ordering == Ordering::Equal,
+                    Operator::Ne => ordering != Ordering::Equal,
+                    Operator::Lt => ordering == Ordering::Less,
+                    Operator::Le => ordering != Ordering::Greater,
+                    Operator::Gt => ordering == Ordering::Greater,
+                    Operator::Ge => ordering != Ordering::Less,
+                })
+            }
+            // The regexp function treats NULL as text that does not match.
+            Expression::Matches(field, regex, matches) => {
+                let found = match lookup(field) {
+                    Value::Text(t) => regex.is_match(&t),
+                    Value::Integer(i) => regex.is_match(&i.to_string()),
+                    Value::Real(r) => regex.is_match(&r.to_string()),
+                    _ => false,
+                };
+                Some(found == *matches)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(field: &str) -> Value {
+        match field {
+            "status" => Value::Integer(502),
+            "request_path" => Value::Text(String::from("/api/users")),
+            "request_method" => Value::Text(String::from("GET")),
+            _ => Value::Null,
+        }
+    }
+
+    fn eval(s: &str) -> bool {
+        s.parse::<Expression>().unwrap().evaluate(&lookup)
+    }
+
+    #[test]
+    fn sql_expressions() {
+        let e = "count > 100 AND (avg_bytes_sent >= 1e4 OR NOT request_path =~ /it's/)"
+            .parse::<Expression>()
+            .unwrap();
+        assert_eq!(e.fields(), vec!["count", "avg_bytes_sent", "request_path"]);
+        assert_eq!(
+            e.to_sql(),
+            r#"("count" > 100 AND ("avg_bytes_sent" >= 10000 OR NOT regexp('it''s', "request_path")))"#
+        );
+        assert!(eval("status = 502 and request_method <> POST"));
+
+        // Only words that start like a number are numbers.
+        let e = "count < 1e999 && avg > -.5 && name != inf && name != NaN"
+            .parse::<Expression>()
+            .unwrap();
+        assert_eq!(
+            e.to_sql(),
+            r#"((("count" < 9e999 AND "avg" > -0.5) AND "name" != 'inf') AND "name" != 'NaN')"#
+        );
+    }
+
+    #[test]
+    fn expressions() {
+        assert!(eval("status >= 500 && request_path =~ /api/"));
+        assert!(!eval("status >= 500 && request_path !~ /^\\/api\\//"));
+        assert!(eval("status < 500 || request_method == GET"));
+        assert!(eval("!(status == 200) && request_method != 'POST'"));
+        assert!(!eval("missing == 1"));
+        assert!(!eval("missing != 1"));
+        assert!(!eval("!(missing == 1)"));
+        assert!(eval("missing == 1 || status == 502"));
+    }
+
+    #[test]
+    fn nulls_are_filtered_as_in_sql() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        super::super::functions::register(&conn).unwrap();
+        for s in &[
+            "missing == 1",
+            "missing != 1",
+            "missing < 1",
+            "!(missing >= 1)",
+            "missing != 1 && status == 502",
+            "missing != 1 || status == 502",
+            "!(missing != 1 || status == 200)",
+            "!(missing != 1 && status == 200)",
+            "missing =~ /./",
+            "missing !~ /./",
+        ] {
+            let e = s.parse::<Expression>().unwrap();
+            let query = format!(
+                "SELECT IFNULL({}, 0) FROM (SELECT NULL AS missing, 502 AS status)",
+                e.to_sql()
+            );
+            let sql: bool = conn.query_row(&query, [], |r| r.get(0)).unwrap();
+            assert_eq!(e.evaluate(&lookup), sql, "{}", s);
+        }
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        assert!("status >=".parse::<Expression>().is_err());
+        assert!("(status > 1".parse::<Expression>().is_err());
+        assert!("status > 1 status".parse::<Expression>().is_err());
+        assert!("request_path =~ /unterminated"
+            .parse::<Expression>()
+            .is_err());
+    }
+}
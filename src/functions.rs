@@ -0,0 +1,200 @@
+use anyhow::Result;
+use regex::Regex;
+use rusqlite::functions::{Aggregate, Context, FunctionFlags};
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
+
+use super::sketch::HyperLogLog;
+use super::time;
+
+// An aggregate approximating COUNT(DISTINCT x) with a HyperLogLog sketch so that the distinct
+// values do not all need to be kept around.
+struct ApproxDistinct;
+
+impl Aggregate<HyperLogLog, i64> for ApproxDistinct {
+    fn init(&self, _: &mut Context<'_>) -> rusqlite::Result<HyperLogLog> {
+        Ok(HyperLogLog::default())
+    }
+
+    fn step(&self, ctx: &mut Context<'_>, hll: &mut HyperLogLog) -> rusqlite::Result<()> {
+        // Every value is hashed as the text SQLite would show for it, so that a number counts as
+        // the same value whether it was stored as a number or as text.
+        match ctx.get_raw(0) {
+            ValueRef::Null => {}
+            ValueRef::Integer(i) => hll.insert(i.to_string().as_bytes()),
+            ValueRef::Real(r) if r.fract() == 0.0 => hll.insert(format!("{:.1}", r).as_bytes()),
+            ValueRef::Real(r) => hll.insert(r.to_string().as_bytes()),
+            ValueRef::Text(t) | ValueRef::Blob(t) => hll.insert(t),
+        }
+        Ok(())
+    }
+
+    fn finalize(&self, _: &mut Context<'_>, hll: Option<HyperLogLog>) -> rusqlite::Result<i64> {
+        Ok(hll.map_or(0, |h| h.estimate().round() as i64))
+    }
+}
+
+// The running count, mean and sum of squared differences from the mean of the values seen so
+// far, updated with Welford's method so that it stays accurate for large inputs.
+#[derive(Default)]
+struct Moments {
+    count: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Moments {
+    // The sample variance, which needs at least two values.
+    fn variance(&self) -> Option<f64> {
+        if self.count < 2 {
+            None
+        } else {
+            Some(self.m2 / (self.count - 1) as f64)
+        }
+    }
+}
+
+// Aggregates for the sample variance and standard deviation of the numeric values. Text and NULL
+// values are skipped like AVG does with NULL.
+struct Variance {
+    sqrt: bool,
+}
+
+impl Aggregate<Moments, Option<f64>> for Variance {
+    fn init(&self, _: &mut Context<'_>) -> rusqlite::Result<Moments> {
+        Ok(Moments::default())
+    }
+
+    fn step(&self, ctx: &mut Context<'_>, m: &mut Moments) -> rusqlite::Result<()> {
+        let x = match ctx.get_raw(0) {
+            ValueRef::Integer(i) => i as f64,
+            ValueRef::Real(r) => r,
+            _ => return Ok(()),
+        };
+        m.count += 1;
+        let delta = x - m.mean;
+        m.mean += delta / m.count as f64;
+        m.m2 += delta * (x - m.mean);
+        Ok(())
+    }
+
+    fn finalize(&self, _: &mut Context<'_>, m: Option<Moments>) -> rusqlite::Result<Option<f64>> {
+        let variance = m.and_then(|m| m.variance());
+        Ok(if self.sqrt {
+            variance.map(f64::sqrt)
+        } else {
+            variance
+        })
+    }
+}
+
+// Whether the text matches a regular expression, as in regexp(pattern, text). The compiled
+// pattern is kept by SQLite for as long as the same pattern is used.
+fn regexp(ctx: &Context<'_>) -> rusqlite::Result<bool> {
+    let regex = ctx.get_or_create_aux(0, |pattern| -> anyhow::Result<Regex> {
+        Ok(Regex::new(pattern.as_str()?)?)
+    })?;
+    let matched = match ctx.get_raw(1) {
+        ValueRef::Null => false,
+        ValueRef::Integer(i) => regex.is_match(&i.to_string()),
+        ValueRef::Real(r) => regex.is_match(&r.to_string()),
+        ValueRef::Text(t) | ValueRef::Blob(t) => regex.is_match(&String::from_utf8_lossy(t)),
+    };
+    Ok(matched)
+}
+
+// A number of bytes in the largest unit that keeps it at least 1, such as 1.50 MB, in powers of
+// 1024 like the sizes given on the command line.
+fn human_bytes(ctx: &Context<'_>) -> rusqlite::Result<Option<String>> {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB", "PB"];
+    let mut n = match ctx.get_raw(0) {
+        ValueRef::Integer(i) => i as f64,
+        ValueRef::Real(r) => r,
+        _ => return Ok(None),
+    };
+    let mut unit = 0;
+    while n.abs() >= 1024.0 && unit < UNITS.len() - 1 {
+        n /= 1024.0;
+        unit += 1;
+    }
+    Ok(Some(if unit == 0 {
+        format!("{} {}", n.round(), UNITS[unit])
+    } else {
+        format!("{:.2} {}", n, UNITS[unit])
+    }))
+}
+
+/// Register the custom SQL functions that the queries can use.
+pub(crate) fn register(conn: &Connection) -> Result<()> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+    conn.create_aggregate_function("approx_distinct", 1, flags, ApproxDistinct)?;
+    conn.create_aggregate_function("variance", 1, flags, Variance { sqrt: false })?;
+    conn.create_aggregate_function("stddev", 1, flags, Variance { sqrt: true })?;
+    conn.create_scalar_function("regexp", 2, flags, regexp)?;
+    conn.create_scalar_function("human_bytes", 1, flags, human_bytes)?;
+    conn.create_scalar_function("utc_offset", 1, flags, |ctx| {
+        Ok(ctx.get::<Option<i64>>(0)?.map(time::utc_offset))
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stddev_and_variance() {
+        let conn = Connection::open_in_memory().unwrap();
+        register(&conn).unwrap();
+        let (variance, stddev): (f64, f64) = conn
+            .query_row(
+                "SELECT variance(x), stddev(x) FROM (SELECT 2 AS x UNION ALL SELECT 4 UNION ALL SELECT 4 UNION ALL SELECT 4 UNION ALL SELECT 5 UNION ALL SELECT 5 UNION ALL SELECT 7 UNION ALL SELECT 9 UNION ALL SELECT NULL)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert!((variance - 32.0 / 7.0).abs() < 1e-9);
+        assert!((stddev - (32.0f64 / 7.0).sqrt()).abs() < 1e-9);
+
+        let single: Option<f64> = conn
+            .query_row("SELECT stddev(1)", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(single, None);
+    }
+
+    #[test]
+    fn approx_distinct_ignores_storage_class() {
+        let conn = Connection::open_in_memory().unwrap();
+        register(&conn).unwrap();
+        let distinct: i64 = conn
+            .query_row(
+                "SELECT approx_distinct(x) FROM (SELECT 42 AS x UNION ALL SELECT '42' UNION ALL SELECT 1.5 UNION ALL SELECT '1.5' UNION ALL SELECT 2.0 UNION ALL SELECT '2.0' UNION ALL SELECT NULL)",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(distinct, 3);
+    }
+
+    #[test]
+    fn bytes_are_readable() {
+        let conn = Connection::open_in_memory().unwrap();
+        register(&conn).unwrap();
+        let sizes: (String, String, String, Option<String>) = conn
+            .query_row(
+                "SELECT human_bytes(512), human_bytes(1536), human_bytes(3 * 1073741824.0), human_bytes(NULL)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            sizes,
+            (
+                String::from("512 B"),
+                String::from("1.50 KB"),
+                String::from("3.00 GB"),
+                None
+            )
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use rusqlite::types::Value;
+
+use super::processor::QueryResult;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// The measurement every point is written to. The grouped fields tell the series apart.
+const MEASUREMENT: &str = "topngx";
+
+// Escape the commas, equals signs and spaces that separate the parts of a line.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == ',' || c == '=' || c == ' ' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Turn the results into InfluxDB line protocol with a point for each row, all stamped with the
+/// current time. Text columns become tags and numeric columns become fields, so the summary is a
+/// single untagged point and the rows of a grouped query are a series for each group. Rows with
+/// nothing to measure are left out.
+pub(crate) fn lines(results: &[QueryResult]) -> Result<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    let mut lines = String::new();
+    for result in results {
+        for row in &result.rows {
+            let mut tags = String::new();
+            let mut fields = Vec::new();
+            for (column, value) in result.columns.iter().zip(row) {
+                match value {
+                    Value::Text(t) if !t.is_empty() => {
+                        tags.push_str(&format!(",{}={}", escape(column), escape(t)))
+                    }
+                    Value::Integer(i) => fields.push(format!("{}={}i", escape(column), i)),
+                    Value::Real(r) if r.is_finite() => {
+                        fields.push(format!("{}={}", escape(column), r))
+                    }
+                    _ => {}
+                }
+            }
+            if !fields.is_empty() {
+                lines.push_str(&format!(
+                    "{}{} {} {}\n",
+                    MEASUREMENT,
+                    tags,
+                    fields.join(","),
+                    now
+                ));
+            }
+        }
+    }
+    Ok(lines)
+}
+
+/// Write the points to an InfluxDB write endpoint such as /api/v2/write, authenticating with the
+/// token when one is given.
+pub(crate) fn push(url: &str, token: Option<&str>, lines: &str) -> Result<()> {
+    if lines.is_empty() {
+        return Ok(());
+    }
+    debug!("writing {} points to {}", lines.lines().count(), url);
+    let mut request = ureq::post(url).timeout(TIMEOUT);
+    if let Some(token) = token {
+        request = request.set("Authorization", &format!("Token {}", token));
+    }
+
+    match request.send_string(lines) {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(code, response)) => {
+            let message = response.into_string().unwrap_or_default();
+            Err(anyhow!("InfluxDB returned {}: {}", code, message.trim()))
+        }
+        Err(e) => Err(anyhow!("could not reach InfluxDB at {}: {}", url, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_are_points() {
+        let result = QueryResult {
+            columns: vec![
+                String::from("request_path"),
+                String::from("count"),
+                String::from("avg_bytes_sent"),
+            ],
+            rows: vec![
+                vec![
+                    Value::Text(String::from("/a b,c")),
+                    Value::Integer(3),
+                    Value::Real(1.5),
+                ],
+                vec![Value::Text(String::from("/")), Value::Null, Value::Null],
+            ],
+        };
+        let lines = lines(&[result]).unwrap();
+        let mut lines = lines.lines();
+        let point = lines.next().unwrap();
+        assert!(point.starts_with("topngx,request_path=/a\\ b\\,c count=3i,avg_bytes_sent=1.5 "));
+        assert!(lines.next().is_none());
+    }
+}
@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+use anyhow::{anyhow, Result};
+use bzip2::bufread::MultiBzDecoder;
+use flate2::bufread::MultiGzDecoder;
+use log::debug;
+use xz2::bufread::XzDecoder;
+
+use super::journal;
+use super::progress::Progress;
+use super::s3;
+use super::STDIN;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const XZ_MAGIC: &[u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+
+// Peek at the start of the input and transparently decompress it if it is a known format.
+fn decompress(mut reader: Box<dyn BufRead + Send>) -> Result<Box<dyn BufRead + Send>> {
+    let magic = reader.fill_buf()?;
+
+    if magic.starts_with(GZIP_MAGIC) {
+        debug!("detected gzip compressed input");
+        return Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))));
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        debug!("detected zstd compressed input");
+        return Ok(Box::new(BufReader::new(zstd::Decoder::with_buffer(
+            reader,
+        )?)));
+    } else if magic.starts_with(BZIP2_MAGIC) {
+        debug!("detected bzip2 compressed input");
+        return Ok(Box::new(BufReader::new(MultiBzDecoder::new(reader))));
+    } else if magic.starts_with(XZ_MAGIC) {
+        debug!("detected xz compressed input");
+        return Ok(Box::new(BufReader::new(XzDecoder::new_multi_decoder(
+            reader,
+        ))));
+    }
+
+    Ok(reader)
+}
+
+/// Either read from STDIN, the journal, S3 or the file specified, decompressing the input if
+/// needed. Reading a file counts towards the progress.
+pub(crate) fn input_source(
+    access_log: &str,
+    progress: &Progress,
+) -> Result<Box<dyn BufRead + Send>> {
+    if access_log == STDIN {
+        return decompress(Box::new(BufReader::new(io::stdin())));
+    }
+    if let Some(unit) = journal::unit(access_log) {
+        return journal::read(unit);
+    }
+    if s3::is_object(access_log) {
+        return decompress(Box::new(BufReader::new(s3::fetch(access_log)?)));
+    }
+    decompress(Box::new(BufReader::new(
+        progress.wrap(File::open(access_log)?),
+    )))
+}
+
+/// Read the lines of the input, replacing any invalid UTF-8 rather than failing on it so that one
+/// bad byte does not end the input early.
+pub(crate) fn lossy_lines<R: BufRead>(reader: R) -> impl Iterator<Item = io::Result<String>> {
+    reader.split(b'\n').map(|line| line.map(|l| lossy_line(&l)))
+}
+
+/// Decode a single line read as bytes, without its line ending.
+pub(crate) fn lossy_line(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8_lossy(line).into_owned()
+}
+
+/// Expand any glob patterns in the given access log paths. A pattern that matches nothing is kept
+/// as is, so a plain path that does not exist still produces an error when it is opened.
+pub(crate) fn expand_access_logs(patterns: &[String]) -> Result<Vec<String>> {
+    let mut access_logs = Vec::with_capacity(patterns.len());
+
+    for pattern in patterns {
+        if pattern == STDIN {
+            access_logs.push(pattern.clone());
+            continue;
+        }
+
+        let mut matches = glob::glob(pattern)?
+            .map(|p| {
+                p?.into_os_string()
+                    .into_string()
+                    .map_err(|p| anyhow!("invalid access log path: {:?}", p))
+            })
+            .collect::<Result<Vec<String>>>()?;
+        debug!("access log pattern {} matched {:?}", pattern, matches);
+
+        if matches.is_empty() {
+            access_logs.push(pattern.clone());
+        } else {
+            matches.sort();
+            access_logs.append(&mut matches);
+        }
+    }
+
+    Ok(access_logs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::*;
+
+    fn decompressed(input: Vec<u8>) -> String {
+        let mut output = String::new();
+        decompress(Box::new(Cursor::new(input)))
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        let input = Cursor::new(b"first\r\nsec\xffond\nthird".to_vec());
+        let lines = lossy_lines(input).collect::<io::Result<Vec<String>>>();
+        assert_eq!(lines.unwrap(), vec!["first", "sec\u{fffd}ond", "third"]);
+    }
+
+    #[test]
+    fn compression_is_detected() {
+        const INPUT: &[u8] = b"first line\nsecond line\n";
+        const OUTPUT: &str = "first line\nsecond line\n";
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(INPUT).unwrap();
+        assert_eq!(decompressed(encoder.finish().unwrap()), OUTPUT);
+
+        assert_eq!(decompressed(zstd::encode_all(INPUT, 0).unwrap()), OUTPUT);
+
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        encoder.write_all(INPUT).unwrap();
+        assert_eq!(decompressed(encoder.finish().unwrap()), OUTPUT);
+
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(INPUT).unwrap();
+        assert_eq!(decompressed(encoder.finish().unwrap()), OUTPUT);
+
+        assert_eq!(decompressed(b"plain\n".to_vec()), "plain\n");
+    }
+}
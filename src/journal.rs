@@ -0,0 +1,89 @@
+use std::io::{BufRead, BufReader, Cursor};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+
+use anyhow::{anyhow, Context, Result};
+use crossbeam_channel::{Receiver, Sender};
+use log::debug;
+
+use super::input::lossy_lines;
+
+// Access logs given with this prefix are read from the journal of a systemd unit.
+const PREFIX: &str = "journal:";
+
+/// The name of the source holding the log lines of the given unit.
+pub(crate) fn source(unit: &str) -> String {
+    format!("{}{}", PREFIX, unit)
+}
+
+/// The unit that a source reads from if it is a journal.
+pub(crate) fn unit(source: &str) -> Option<&str> {
+    source.strip_prefix(PREFIX)
+}
+
+// Run journalctl printing only the messages of the given unit.
+fn journalctl(unit: &str, follow: bool) -> Result<Child> {
+    let mut cmd = Command::new("journalctl");
+    cmd.args(["--output", "cat", "--no-pager", "--unit", unit]);
+    if follow {
+        cmd.args(["--follow", "--lines", "0"]);
+    }
+    debug!("running {:?}", cmd);
+
+    // Keep an interrupt from the terminal away from journalctl so that it is only stopped once
+    // the statistics are done with it.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+
+    cmd.stdout(Stdio::piped())
+        .stdin(Stdio::null())
+        .spawn()
+        .context("failed to run journalctl")
+}
+
+/// Read every message in the journal of the given unit.
+pub(crate) fn read(unit: &str) -> Result<Box<dyn BufRead + Send>> {
+    let output = journalctl(unit, false)?.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!("journalctl failed with {}", output.status));
+    }
+    Ok(Box::new(Cursor::new(output.stdout)))
+}
+
+/// Follow the journal of the given unit sending each new message along with its source. The
+/// journalctl process is killed once the sending half of the stop channel is dropped.
+pub(crate) fn follow(
+    unit: &str,
+    tx: Sender<(String, String)>,
+    stop_rx: Receiver<()>,
+) -> Result<thread::JoinHandle<Result<()>>> {
+    let mut child = journalctl(unit, true)?;
+    let stdout = child.stdout.take().expect("stdout should be piped");
+    let source = source(unit);
+
+    let reader = thread::spawn(move || -> Result<()> {
+        for line in lossy_lines(BufReader::new(stdout)) {
+            let line = line?;
+            debug!("journal read from {}: {}", source, line);
+            tx.send((source.clone(), line))?;
+        }
+        Err(anyhow!("journalctl stopped following {}", source))
+    });
+
+    Ok(thread::spawn(move || -> Result<()> {
+        // This only returns once every sender has been dropped.
+        let _ = stop_rx.recv();
+        // journalctl only stops by itself when something went wrong, which the reader reports.
+        let exited = child.try_wait()?.is_some();
+        if !exited {
+            child.kill()?;
+            child.wait()?;
+        }
+        match reader.join() {
+            Ok(result) if exited => result,
+            // The reader fails once journalctl has been killed.
+            Ok(_) => Ok(()),
+            Err(_) => Err(anyhow!("the journal reading thread panicked")),
+        }
+    }))
+}
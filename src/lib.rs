@@ -0,0 +1,783 @@
+//! topngx parses NGINX access logs into SQLite and runs queries over them.
+//!
+//! The command line interface is a thin wrapper around [`execute`]. The pieces it is built from
+//! can also be used on their own: a [`LogParser`] breaks lines into [`Variables`], the values of
+//! each field are computed with [`field_value`] and a [`Processor`] stores the records and runs
+//! queries such as [`summary_query`] over them.
+//!
+//! The same [`Options`] the command line is parsed into are built up from their defaults to use
+//! these without it:
+//!
+//! ```
+//! use rusqlite::types::Value;
+//! use topngx::{field_value, generate_processor, summary_query, LogParser, Options};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let opts = Options::default().format("combined").group_by("remote_addr");
+//! let parser = LogParser::new("combined")?;
+//! let processor = generate_processor(&opts, None, None)?;
+//!
+//! let line = r#"1.1.1.1 - - [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 200 100 "-" "curl/7.68.0""#;
+//! let vars = parser.parse(line).expect("the line should match the format");
+//! let record = processor
+//!     .fields
+//!     .iter()
+//!     .map(|f| (format!(":{}", f), field_value(&opts, f, &vars, "example")))
+//!     .collect();
+//! processor.process(vec![record])?;
+//!
+//! let summary = processor.query(&summary_query(&opts))?;
+//! assert_eq!(summary.rows[0][0], Value::Integer(1));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use structopt::StructOpt;
+
+use alert::Alert;
+use processor::{parse_percent, parse_size};
+use record::{Network, Prefixes, Statuses};
+use regex::Regex;
+
+pub use cli::execute;
+pub use filter::Expression;
+pub use nginx::{ColumnType, LogParser, Variables};
+pub use processor::{
+    detailed_query, generate_processor, summary_query, Derived, OrderBy, OutputFormat, Processor,
+    QueryResult,
+};
+pub use record::{field_value, keep};
+pub use time::{Time, Zone};
+
+mod alert;
+mod anomaly;
+mod cli;
+mod clickhouse;
+mod config;
+mod dashboard;
+mod delta;
+mod dns;
+mod enrich;
+mod export;
+pub mod filter;
+mod functions;
+mod influx;
+mod input;
+mod journal;
+pub mod nginx;
+pub mod processor;
+mod progress;
+mod quantile;
+pub mod record;
+mod repl;
+mod s3;
+mod sketch;
+mod statsd;
+mod syslog;
+pub mod time;
+mod trend;
+mod webhook;
+
+const STDIN: &str = "STDIN";
+
+// The format used when none is given.
+const DEFAULT_FORMAT: &str = "combined";
+
+// Common field names.
+pub const STATUS_TYPE: &str = "status_type";
+pub const BYTES_SENT: &str = "bytes_sent";
+pub const REQUEST_METHOD: &str = "request_method";
+pub const REQUEST_PATH: &str = "request_path";
+pub const REQUEST_PROTOCOL: &str = "request_protocol";
+pub const SOURCE_FILE: &str = "source_file";
+pub const TIMESTAMP: &str = "ts";
+pub const COUNTRY: &str = "country";
+pub const CITY: &str = "city";
+pub const ASN: &str = "asn";
+pub const AS_ORG: &str = "as_org";
+pub const UA_BROWSER: &str = "ua_browser";
+pub const UA_OS: &str = "ua_os";
+pub const UA_DEVICE: &str = "ua_device";
+pub const UA_NAME: &str = "ua_name";
+pub const IS_BOT: &str = "is_bot";
+pub const BOT_NAME: &str = "bot_name";
+pub const TOOL_NAME: &str = "tool_name";
+pub const SCAN_TYPE: &str = "scan_type";
+pub const REFERER_DOMAIN: &str = "referer_domain";
+pub const REMOTE_NET: &str = "remote_net";
+pub const UPSTREAM_TIME_SUM: &str = "upstream_time_sum";
+pub const UPSTREAM_TIME_MAX: &str = "upstream_time_max";
+pub const UPSTREAM_TIME_LAST: &str = "upstream_time_last";
+pub const HOST: &str = "host";
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    author,
+    name = "topngx",
+    about = "top for NGINX",
+    rename_all = "kebab-case"
+)]
+pub struct Options {
+    /// Read defaults for the access logs, format, group by, limit, interval, output and exclusions
+    /// from this TOML file instead of ~/.config/topngx/config.toml. Options given on the command
+    /// line take precedence.
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// The access log to parse. This can be repeated and each value may be a glob pattern. The
+    /// file a record came from is available as the source_file field.
+    #[structopt(short, long, number_of_values = 1)]
+    access_log: Vec<String>,
+
+    /// The specific log format with which to parse. This can be a log_format string, one of the
+    /// presets (common, combined, combined_with_time, main, upstream_time), one of the Apache httpd
+    /// presets (vhost_combined, combinedio), alb for AWS Application Load Balancer logs, w3c for
+    /// W3C extended logs with a #Fields header such as CloudFront and IIS, ltsv for labeled
+    /// tab-separated values, logfmt for key=value pairs, json, error for the NGINX error log or the
+    /// name of a log_format defined in the configuration given by --nginx-conf. With auto the
+    /// format that matches the most of the first lines of the first access log is used.
+    #[structopt(short, long, default_value = DEFAULT_FORMAT)]
+    format: String,
+
+    /// Parse each line with this regular expression instead of a format. Its named capture groups,
+    /// such as (?P<status>\d+), become the variables.
+    #[structopt(long, conflicts_with = "format")]
+    format_regex: Option<String>,
+
+    /// An NGINX configuration file to read named log_format directives from.
+    #[structopt(short = "c", long, parse(from_os_str))]
+    nginx_conf: Option<PathBuf>,
+
+    /// Store the records in this SQLite database file instead of in memory. When a previous run
+    /// already stored records in the same file they are queried as they are if no access logs are
+    /// given, and giving any is an error unless --db-append is given as well.
+    #[structopt(long, parse(from_os_str))]
+    db: Option<PathBuf>,
+
+    /// Parse the access logs and add their records to those already stored in --db.
+    #[structopt(long, requires = "db")]
+    db_append: bool,
+
+    /// Keep the records in memory until they take up more than this much, for example 512M or 2G,
+    /// and then move them to a temporary file that is removed when done, keeping only about this
+    /// much of it in memory, instead of running out of memory. With --db this is how much of the
+    /// database file is kept in memory.
+    #[structopt(long, parse(try_from_str = parse_size))]
+    max_memory: Option<u64>,
+
+    /// A MaxMind database, such as GeoLite2-City or GeoLite2-ASN, used to look up the client
+    /// address. This adds the country, city, asn and as_org fields and can be repeated.
+    #[structopt(long, number_of_values = 1, parse(from_os_str))]
+    geoip_db: Vec<PathBuf>,
+
+    /// Group by this variable. Grouping by host works whenever the format logs $host,
+    /// $server_name or $http_host.
+    #[structopt(short, long, default_value = "request_path")]
+    group_by: String,
+
+    /// Only show the groups of the default queries matching this expression, for example
+    /// "count > 100 AND error_rate > 0.01". It can use the grouped field, count, avg_bytes_sent,
+    /// 2XX, 3XX, 4XX, 5XX, error_rate and pct_of_total and is written the same way as --where.
+    #[structopt(short = "w", long)]
+    having: Option<Expression>,
+
+    /// Refresh the statistics using this interval which is given in seconds.
+    #[structopt(short, long, default_value = "2")]
+    interval: u64,
+
+    /// Tail the specified log files. You cannot tail standard input.
+    #[structopt(short = "t", long)]
+    follow: bool,
+
+    /// Parse the log files again from the start every interval instead of tailing them, for
+    /// filesystems where following a file does not work. Without any access logs the records in
+    /// --db are queried again instead.
+    #[structopt(long, conflicts_with = "follow")]
+    watch: bool,
+
+    /// Strip the RFC 3164 or RFC 5424 header from lines that were forwarded through syslog before
+    /// parsing them. The hostname from the header is available as the hostname field.
+    #[structopt(long)]
+    syslog: bool,
+
+    /// Read every object under this s3://bucket/prefix/ URL using the AWS CLI, which picks up the
+    /// credentials from the environment. This can be repeated and combined with access logs.
+    #[structopt(long, number_of_values = 1)]
+    s3: Vec<String>,
+
+    /// Read the log lines of this systemd unit, such as nginx.service, from the journal. This can
+    /// be combined with --follow and with access logs.
+    #[structopt(long)]
+    journal_unit: Option<String>,
+
+    /// Act as a syslog server receiving log lines over UDP and TCP on this address, for example
+    /// 0.0.0.0:5514. This implies --follow and --syslog.
+    #[structopt(long)]
+    listen_syslog: Option<SocketAddr>,
+
+    /// Only keep records from this far back when tailing, for example 10m or 1h. Older records are
+    /// removed each interval which requires the format to contain a time variable.
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    window: Option<Duration>,
+
+    /// Count the values of --group-by with a fixed amount of memory when tailing instead of storing
+    /// every record, for very busy servers. Only the approximate counts of the most frequent values
+    /// are reported along with how much each could be overestimated by.
+    #[structopt(long, conflicts_with_all = &["window", "alert", "statsd", "anomaly"])]
+    approximate: bool,
+
+    /// Warn when a rule such as "5xx_rate > 0.05" or "count < 10" holds for the records that
+    /// arrived during the last interval when tailing. Rules are written the same way as --where
+    /// using count, req_per_sec, avg_bytes_sent, 2xx to 5xx and 2xx_rate to 5xx_rate. This can be
+    /// repeated.
+    #[structopt(long, number_of_values = 1)]
+    alert: Vec<Alert>,
+
+    /// Exit with an error as soon as an alert is triggered.
+    #[structopt(long, requires = "alert")]
+    alert_exit: bool,
+
+    /// POST a JSON payload describing the alerts and the groups with the most requests to this
+    /// URL, such as a Slack incoming webhook, whenever an alert starts being triggered.
+    #[structopt(long, requires = "alert")]
+    alert_webhook: Option<String>,
+
+    /// Warn when the request rate or the error rate of the last interval is more than this many
+    /// standard deviations away from those of the 30 intervals before it when tailing, for
+    /// example 3.
+    #[structopt(long)]
+    anomaly: Option<f64>,
+
+    /// Send the request count, the counts of each class of status, the average size and the
+    /// request time percentiles of every interval to the StatsD server at this host:port over UDP
+    /// when tailing.
+    #[structopt(long)]
+    statsd: Option<String>,
+
+    /// The prefix of the names of the metrics sent to StatsD.
+    #[structopt(long, default_value = "topngx")]
+    statsd_prefix: String,
+
+    /// Look up the names of the client addresses in the results with reverse DNS and show them in
+    /// a remote_host column next to remote_addr. Lookups that take longer than two seconds are left
+    /// out.
+    #[structopt(long)]
+    resolve: bool,
+
+    /// The prefix lengths of the remote_net field, which holds the network of each client address
+    /// so that requests from one subnet are grouped together. This is the IPv4 length optionally
+    /// followed by the IPv6 one, such as 16,48.
+    #[structopt(long, default_value = "24,64")]
+    cidr: Prefixes,
+
+    /// Strip query strings from request paths and replace numeric and UUID path segments with
+    /// placeholders, so /users/123?a=b becomes /users/:id.
+    #[structopt(long)]
+    normalize_paths: bool,
+
+    /// Skip records from before this time. This is either a duration like 30m or 2h before now,
+    /// now, today or yesterday or an absolute time such as 2020-06-06T23:16:43Z or
+    /// 2020-06-06 23:16:43. Days and times without an offset are in --timezone, or in UTC.
+    #[structopt(long)]
+    since: Option<Time>,
+
+    /// Skip records from after this time, given the same way as --since.
+    #[structopt(long)]
+    until: Option<Time>,
+
+    /// Only keep the records from this far before the newest one in the logs, for example 15m, 6h
+    /// or 2d. Unlike --since this works for logs that ended some time ago.
+    #[structopt(long, conflicts_with = "follow", parse(try_from_str = humantime::parse_duration))]
+    last: Option<Duration>,
+
+    /// Show times and start time buckets in this zone instead of UTC, for example local,
+    /// America/New_York or +05:30. $time_local and $time_iso8601 values are converted as well.
+    /// Error log times have no offset so they are read as times in this zone.
+    #[structopt(long)]
+    timezone: Option<Zone>,
+
+    /// Only keep records matching this expression, for example
+    /// "status >= 500 && request_path =~ /api/". Fields can be compared with ==, !=, <, <=, >
+    /// and >=, matched against a regex with =~ and !~ and combined with &&, || and !.
+    #[structopt(long = "where")]
+    filter: Option<Expression>,
+
+    /// Add a column computed from other fields with an SQLite expression, given as
+    /// name=expression such as "is_api=request_path LIKE '/api/%'". It can be grouped by and
+    /// queried like any other field. This can be repeated.
+    #[structopt(long, number_of_values = 1)]
+    derive: Vec<Derived>,
+
+    /// Only keep the requests with these statuses, given as codes and classes such as 5xx or
+    /// 401,403.
+    #[structopt(long)]
+    status: Option<Statuses>,
+
+    /// Leave out the requests whose path matches this regex, such as ^/healthz. This can be
+    /// repeated.
+    #[structopt(long, number_of_values = 1)]
+    exclude_path: Vec<Regex>,
+
+    /// Leave out the requests from this address or network, such as 10.0.0.0/8. This can be
+    /// repeated.
+    #[structopt(long, number_of_values = 1)]
+    exclude_ip: Vec<Network>,
+
+    /// Leave out the requests whose user agent matches this regex, such as (?i)pingdom. This can
+    /// be repeated.
+    #[structopt(long, number_of_values = 1)]
+    exclude_ua: Vec<Regex>,
+
+    /// Stop with an error at the first line that does not match the format instead of skipping
+    /// it.
+    #[structopt(long)]
+    strict: bool,
+
+    /// Write every line that did not match the format to this file, which helps when debugging a
+    /// format.
+    #[structopt(long, parse(from_os_str))]
+    dump_unmatched: Option<PathBuf>,
+
+    /// Only keep this fraction of the lines, for example 0.05, chosen at random. The counts of the
+    /// default queries and of top are scaled up to estimate the totals, and avg, max, min and
+    /// percentile are estimated from the sample. No other subcommand can be run on a sample.
+    #[structopt(long)]
+    sample: Option<f64>,
+
+    /// The number of records to limit for each query.
+    #[structopt(short, long, default_value = "10")]
+    limit: u64,
+
+    /// Order of output for the default queries. This is a comma separated list of columns, each
+    /// optionally followed by asc or desc, for example "5XX desc,count desc". Columns are sorted
+    /// in descending order unless asc is given.
+    #[structopt(short, long, default_value = "count")]
+    order_by: OrderBy,
+
+    /// How to write the results of each query, either table, vertical with a line for each column
+    /// of every row, json or influx for InfluxDB line protocol.
+    #[structopt(long, default_value = "table")]
+    output: OutputFormat,
+
+    /// Write the results to this file instead of standard output, replacing what it held unless
+    /// --append is given. When tailing, a snapshot headed by the time is written each interval.
+    #[structopt(long, parse(from_os_str))]
+    output_file: Option<PathBuf>,
+
+    /// Add the results to the end of --output-file instead of replacing it.
+    #[structopt(long, requires = "output-file")]
+    append: bool,
+
+    /// Push the results as InfluxDB line protocol to this write endpoint, for example
+    /// http://localhost:8086/api/v2/write?org=ops&bucket=nginx, instead of writing them out.
+    #[structopt(long, conflicts_with = "output-file")]
+    influx_url: Option<String>,
+
+    /// The API token to authenticate the writes to --influx-url with.
+    #[structopt(long, requires = "influx-url")]
+    influx_token: Option<String>,
+
+    #[structopt(subcommand)]
+    subcommand: Option<SubCommand>,
+}
+
+impl Default for Options {
+    /// The options of running topngx without any arguments.
+    fn default() -> Options {
+        Options::from_iter(&["topngx"])
+    }
+}
+
+impl Options {
+    /// Add an access log to parse, which may be a glob pattern.
+    pub fn access_log(mut self, access_log: impl Into<String>) -> Options {
+        self.access_log.push(access_log.into());
+        self
+    }
+
+    /// Parse with this log format, which is given the same way as --format.
+    pub fn format(mut self, format: impl Into<String>) -> Options {
+        self.format = format.into();
+        self
+    }
+
+    /// Group the default queries by this variable.
+    pub fn group_by(mut self, group_by: impl Into<String>) -> Options {
+        self.group_by = group_by.into();
+        self
+    }
+
+    /// Only show the groups of the default queries matching this expression.
+    pub fn having(mut self, having: Expression) -> Options {
+        self.having = Some(having);
+        self
+    }
+
+    /// Only keep the records matching this expression.
+    pub fn filter(mut self, filter: Expression) -> Options {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Skip the records from before this time.
+    pub fn since(mut self, since: Time) -> Options {
+        self.since = Some(since);
+        self
+    }
+
+    /// Skip the records from after this time.
+    pub fn until(mut self, until: Time) -> Options {
+        self.until = Some(until);
+        self
+    }
+
+    /// Show times and start time buckets in this zone instead of UTC.
+    pub fn timezone(mut self, timezone: Zone) -> Options {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    /// Add a column computed from other fields with an SQLite expression.
+    pub fn derive(mut self, derived: Derived) -> Options {
+        self.derive.push(derived);
+        self
+    }
+
+    /// Strip query strings from request paths and replace numeric and UUID segments with
+    /// placeholders.
+    pub fn normalize_paths(mut self, normalize_paths: bool) -> Options {
+        self.normalize_paths = normalize_paths;
+        self
+    }
+
+    /// Store the records in this SQLite database file instead of in memory.
+    pub fn db(mut self, db: impl Into<PathBuf>) -> Options {
+        self.db = Some(db.into());
+        self
+    }
+
+    /// The number of records to limit each query to.
+    pub fn limit(mut self, limit: u64) -> Options {
+        self.limit = limit;
+        self
+    }
+
+    /// The order of the groups of the default queries.
+    pub fn order_by(mut self, order_by: OrderBy) -> Options {
+        self.order_by = order_by;
+        self
+    }
+
+    /// How to write the results of each query.
+    pub fn output(mut self, output: OutputFormat) -> Options {
+        self.output = output;
+        self
+    }
+
+    /// Write the results to this file instead of standard output.
+    pub fn output_file(mut self, output_file: impl Into<PathBuf>) -> Options {
+        self.output_file = Some(output_file.into());
+        self
+    }
+}
+
+// The list of subcommands available to use.
+#[derive(Debug, StructOpt)]
+enum SubCommand {
+    /// Score how satisfied users are with the request times overall and for each group.
+    Apdex(Apdex),
+
+    /// Print the average of the given fields.
+    Avg(Fields),
+
+    /// Total the bytes sent overall, for each interval of time and for each group.
+    Bandwidth(Bandwidth),
+
+    /// Summarize bot, tool and human traffic and list the top crawlers and tools.
+    Bots,
+
+    /// Report the proxy cache hit ratio overall and for each path and the bytes served from it.
+    Cache,
+
+    /// Compare the groups of the default query between two logs or before and after a time.
+    Diff(Diff),
+
+    /// Summarize an NGINX error log by message, failing upstream and over time.
+    Errors(Buckets),
+
+    /// Write the parsed records to a CSV or Parquet file instead of running any queries.
+    Export(Export),
+
+    /// Show how the values of a numeric field are distributed across buckets.
+    Hist(Hist),
+
+    /// List the available fields as well as the access log and format being used.
+    Info,
+
+    /// Print the maximum of the given fields.
+    Max(Fields),
+
+    /// Print the minimum of the given fields.
+    Min(Fields),
+
+    /// List the paths that were not found along with the pages linking to them and the clients
+    /// asking for them.
+    Notfound,
+
+    /// Compute percentiles of a numeric field for each group.
+    Percentile(Percentile),
+
+    /// Count the requests for every combination of the values of two fields as a table.
+    Pivot(Pivot),
+
+    /// Print out the supplied fields with the given limit.
+    Print(Fields),
+
+    /// Report the share, error rate and latency of each HTTP protocol version.
+    Protocols,
+
+    /// Supply a custom query.
+    Query(Query),
+
+    /// Report the requests and bytes per second for each interval of time.
+    Rate(Buckets),
+
+    /// Report the external sites that refer the most requests and the pages they lead to.
+    Referrers(Referrers),
+
+    /// Parse the access logs once and then run SQL statements against them interactively.
+    Repl(Fields),
+
+    /// Split the requests of each client address into sessions and summarize them.
+    Sessions(Sessions),
+
+    /// Load the parsed records into another database for long term analysis.
+    Ship(Ship),
+
+    /// Report how much of the error budget of an availability target the requests used up.
+    Slo(Slo),
+
+    /// List the individual requests that took the longest by $request_time.
+    Slowest,
+
+    /// Report the count, mean, standard deviation, minimum and maximum of numeric fields.
+    Stats(Fields),
+
+    /// Compute the sum of the given fields.
+    Sum(Fields),
+
+    /// List the clients probing for well known attack paths and how the server answered them.
+    Suspicious,
+
+    /// Report requests, bytes and errors for each interval of time.
+    Timeseries(Timeseries),
+
+    /// Break the requests down by TLS protocol and cipher and list the clients on TLS 1.0 or 1.1.
+    Tls,
+
+    /// Find the top values for the given fields.
+    Top(Fields),
+
+    /// Report the top user agents by browser and operating system rather than by every version.
+    Ua,
+
+    /// Estimate the number of distinct values of a field for each group.
+    Uniques(Uniques),
+
+    /// Report the requests, bytes and error rate of each site by $host, $server_name or $http_host.
+    Vhosts,
+
+    /// Count the distinct client addresses overall, for each day and for each hour.
+    Visitors,
+}
+
+#[derive(Debug, StructOpt)]
+struct Fields {
+    /// A space Separated list of field names.
+    fields: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct Diff {
+    /// The access log to compare the access logs that were given with -a against.
+    #[structopt(required_unless = "at")]
+    access_log: Option<String>,
+
+    /// Compare the records from before this time with the ones from after it instead of two
+    /// logs. It is given the same way as --since.
+    #[structopt(long, conflicts_with = "access-log")]
+    at: Option<Time>,
+}
+
+#[derive(Debug, StructOpt)]
+struct Export {
+    /// The file to write. Its extension picks the format, either .csv or .parquet, and - writes
+    /// CSV to standard output.
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+
+    /// A space separated list of the fields to export. Every field of the format is exported
+    /// when this is not given.
+    #[structopt(short, long)]
+    fields: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct Hist {
+    /// The numeric field to bucket, for example request_time or bytes_sent.
+    field: String,
+
+    /// A comma separated list of increasing bucket boundaries, for example 0.1,0.5,1,5.
+    #[structopt(short, long, use_delimiter = true, required = true)]
+    buckets: Vec<f64>,
+}
+
+#[derive(Debug, StructOpt)]
+struct Apdex {
+    /// The request time in seconds that users are satisfied with. Up to four times as long is
+    /// tolerated and anything longer, or a 5XX response, frustrates them.
+    #[structopt(short, long, default_value = "0.5")]
+    target: f64,
+}
+
+#[derive(Debug, StructOpt)]
+struct Percentile {
+    /// The numeric field to compute percentiles of, for example request_time.
+    field: String,
+
+    /// A comma separated list of the percentiles to compute.
+    #[structopt(short, long, use_delimiter = true, default_value = "50,90,95,99")]
+    percentiles: Vec<f64>,
+}
+
+#[derive(Debug, StructOpt)]
+struct Pivot {
+    /// The field whose most frequent values make up the rows, for example request_path.
+    #[structopt(short, long)]
+    rows: String,
+
+    /// The field whose most frequent values make up the columns, for example status_type.
+    #[structopt(short, long)]
+    cols: String,
+}
+
+#[derive(Debug, StructOpt)]
+struct Query {
+    /// A space separated list of field names.
+    #[structopt(short, long)]
+    fields: Vec<String>,
+
+    /// The supplied query. You typically will want to use your shell to quote it.
+    #[structopt(short, long)]
+    query: String,
+}
+
+#[derive(Debug, StructOpt)]
+struct Referrers {
+    /// A domain of the site itself, such as example.com, whose referrals including those from
+    /// its subdomains are left out. This can be repeated. Referrals from the host of the request
+    /// are left out as well when the format has $host or $http_host.
+    #[structopt(short, long, number_of_values = 1)]
+    site: Vec<String>,
+
+    /// The number of landing pages to show for each referrer.
+    #[structopt(short, long, default_value = "3")]
+    pages: u64,
+}
+
+#[derive(Debug, StructOpt)]
+enum Ship {
+    /// Insert the records into a ClickHouse table through its HTTP interface.
+    Clickhouse(ClickHouse),
+}
+
+#[derive(Debug, StructOpt)]
+struct ClickHouse {
+    /// The table to insert into, such as logs.nginx.
+    table: String,
+
+    /// The URL of the HTTP interface of the server.
+    #[structopt(long, default_value = "http://localhost:8123")]
+    url: String,
+
+    /// The user to insert as.
+    #[structopt(long)]
+    user: Option<String>,
+
+    /// The password of the user.
+    #[structopt(long)]
+    password: Option<String>,
+
+    /// Create the table with a column for each field if it does not exist.
+    #[structopt(long)]
+    create: bool,
+
+    /// The most rows that are inserted by a single request.
+    #[structopt(long, default_value = "10000")]
+    batch_size: u64,
+
+    /// A space separated list of the fields to insert. Every field of the format is inserted when
+    /// this is not given.
+    #[structopt(short, long)]
+    fields: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct Sessions {
+    /// How long a client can be idle before its next request starts a new session, for example
+    /// 30m or 1h.
+    #[structopt(short, long, default_value = "30m", parse(try_from_str = humantime::parse_duration))]
+    timeout: Duration,
+}
+
+#[derive(Debug, StructOpt)]
+struct Slo {
+    /// The share of requests that should succeed, in percent. Requests fail when they get a 5XX
+    /// response. At 100 there is no budget, so any failure leaves the budget and burn rates empty.
+    #[structopt(short, long, default_value = "99.9", parse(try_from_str = parse_percent))]
+    target: f64,
+
+    /// How far back from the last record the target applies, for example 7d or 30d.
+    #[structopt(short, long, default_value = "30d", parse(try_from_str = humantime::parse_duration))]
+    window: Duration,
+}
+
+#[derive(Debug, StructOpt)]
+struct Uniques {
+    /// The field to count the distinct values of, for example remote_addr.
+    field: String,
+}
+
+#[derive(Debug, StructOpt)]
+struct Bandwidth {
+    /// The width of each time bucket, for example 1m, 1h or 1d.
+    #[structopt(short, long, default_value = "1h", parse(try_from_str = humantime::parse_duration))]
+    bucket: Duration,
+
+    /// Show the number of bytes instead of sizes such as 1.50 MB.
+    #[structopt(long)]
+    bytes: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct Buckets {
+    /// The width of each time bucket, for example 30s, 1m or 1h.
+    #[structopt(short, long, default_value = "1m", parse(try_from_str = humantime::parse_duration))]
+    bucket: Duration,
+}
+
+#[derive(Debug, StructOpt)]
+struct Timeseries {
+    /// The width of each time bucket, for example 30s, 1m or 1h.
+    #[structopt(short, long, default_value = "1m", parse(try_from_str = humantime::parse_duration))]
+    bucket: Duration,
+
+    /// Add moving averages of the request count and the 5XX rate over this many buckets, for
+    /// example 5. Buckets without any requests are not part of the output nor the averages.
+    #[structopt(short, long)]
+    smooth: Option<usize>,
+}
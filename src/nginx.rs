@@ -1,48 +1,974 @@
-use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, NaiveDateTime};
+use log::debug;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use rusqlite::types::{Null, ToSql};
+
+use super::time;
+
+const JSON: &str = "json";
+pub const ERROR_LOG: &str = "error";
+const ALB: &str = "alb";
+const W3C: &str = "w3c";
+const LTSV: &str = "ltsv";
+const LOGFMT: &str = "logfmt";
+pub const AUTO: &str = "auto";
+pub const REGEX_PREFIX: &str = "regex:";
+
+// The labels recommended by ltsv.org that have an equivalent NGINX variable. Any other label is
+// used as the name of the variable as is.
+const LTSV_LABELS: &[(&str, &str)] = &[
+    ("host", "remote_addr"),
+    ("user", "remote_user"),
+    ("time", "time_local"),
+    ("req", "request"),
+    ("method", "request_method"),
+    ("uri", "request_uri"),
+    ("protocol", "server_protocol"),
+    ("size", "body_bytes_sent"),
+    ("reqsize", "request_length"),
+    ("referer", "http_referer"),
+    ("ua", "http_user_agent"),
+    ("vhost", "host"),
+    ("reqtime", "request_time"),
+    ("apptime", "upstream_response_time"),
+];
 
-const COMBINED: &str = "combined";
+// Common logfmt keys that have an equivalent NGINX variable. Any other key is used as the name of
+// the variable as is.
+const LOGFMT_KEYS: &[(&str, &str)] = &[
+    ("ip", "remote_addr"),
+    ("client_ip", "remote_addr"),
+    ("remote_ip", "remote_addr"),
+    ("time", "time_iso8601"),
+    ("ts", "time_iso8601"),
+    ("method", "request_method"),
+    ("path", "request_uri"),
+    ("uri", "request_uri"),
+    ("proto", "server_protocol"),
+    ("bytes", "body_bytes_sent"),
+    ("size", "body_bytes_sent"),
+    ("duration", "request_time"),
+    ("referer", "http_referer"),
+    ("user_agent", "http_user_agent"),
+    ("ua", "http_user_agent"),
+];
+
+// The W3C extended log fields that have an equivalent NGINX variable.
+const W3C_VARIABLES: &[(&str, &str)] = &[
+    ("c-ip", "remote_addr"),
+    ("c-port", "remote_port"),
+    ("cs-method", "request_method"),
+    ("cs-uri-stem", "request_uri"),
+    ("cs-uri-query", "query_string"),
+    ("cs-protocol-version", "server_protocol"),
+    ("cs-version", "server_protocol"),
+    ("cs-host", "host"),
+    ("cs(Host)", "host"),
+    ("cs(User-Agent)", "http_user_agent"),
+    ("cs(Referer)", "http_referer"),
+    ("cs(Referrer)", "http_referer"),
+    ("cs-bytes", "request_length"),
+    ("sc-status", "status"),
+    ("sc-bytes", "bytes_sent"),
+    ("s-ip", "server_addr"),
+    ("s-port", "server_port"),
+];
+const LOG_FORMAT_COMMON: &str =
+    r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent"#;
 const LOG_FORMAT_COMBINED: &str = r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#;
+// The variables of the combined format in order. The common format has the first six.
+const COMBINED_VARIABLES: &[&str] = &[
+    "remote_addr",
+    "remote_user",
+    "time_local",
+    "request",
+    "status",
+    "body_bytes_sent",
+    "http_referer",
+    "http_user_agent",
+];
+// Text variables that do not contain spaces so they can be matched up to the next one.
+const WORD_VARIABLES: &[&str] = &[
+    "remote_addr",
+    "server_addr",
+    "server_name",
+    "host",
+    "scheme",
+    "request_method",
+    "request_uri",
+    "uri",
+    "server_protocol",
+    "time_iso8601",
+];
+const LOG_FORMAT_COMBINED_WITH_TIME: &str = r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent" $request_time"#;
+const LOG_FORMAT_MAIN: &str = r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent" "$http_x_forwarded_for""#;
+const LOG_FORMAT_UPSTREAM_TIME: &str = r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent" rt=$request_time uct="$upstream_connect_time" uht="$upstream_header_time" urt="$upstream_response_time""#;
+
+// Apache httpd formats written with the equivalent NGINX variables. The %I and %O byte counts
+// include the headers, like $request_length and $bytes_sent.
+const LOG_FORMAT_VHOST_COMBINED: &str = r#"$server_name:$server_port $remote_addr - $remote_user [$time_local] "$request" $status $bytes_sent "$http_referer" "$http_user_agent""#;
+const LOG_FORMAT_COMBINEDIO: &str = r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent" $request_length $bytes_sent"#;
+
+// The layout of $time_local, for example 06/Jun/2020:23:16:43 +0000.
+pub const TIME_LOCAL_FORMAT: &str = "%d/%b/%Y:%H:%M:%S %z";
+
+// The layout of the time at the start of each error log line which is in the local time zone of
+// the server, for example 2020/06/06 23:16:43.
+const ERROR_TIME_FORMAT: &str = "%Y/%m/%d %H:%M:%S";
+
+// The variables that are parsed out of each line of an error log.
+const ERROR_LOG_VARIABLES: &[&str] = &[
+    "time",
+    "level",
+    "pid",
+    "tid",
+    "connection",
+    "message",
+    "client",
+    "server",
+    "request",
+    "upstream",
+    "host",
+];
+
+/// The named formats that can be given instead of a log_format string.
+pub const PRESETS: &[(&str, &str)] = &[
+    ("common", LOG_FORMAT_COMMON),
+    ("combined", LOG_FORMAT_COMBINED),
+    ("combined_with_time", LOG_FORMAT_COMBINED_WITH_TIME),
+    ("main", LOG_FORMAT_MAIN),
+    ("upstream_time", LOG_FORMAT_UPSTREAM_TIME),
+    ("vhost_combined", LOG_FORMAT_VHOST_COMBINED),
+    ("combinedio", LOG_FORMAT_COMBINEDIO),
+];
+
+// We know that this pattern will compile. NGINX allows variables to be written as either `$name`
+// or `${name}`, the latter being useful when a variable is directly followed by other text.
+static NGINX_VARIABLE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\$(?:\{([a-zA-Z0-9_]+)\}|([a-zA-Z0-9_]+))").unwrap());
+
+// An error log line such as
+// `2020/06/06 23:16:43 [error] 7#7: *3 connect() failed ..., client: 1.2.3.4, server: localhost`.
+static ERROR_LINE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^(?P<time>\d{4}/\d{2}/\d{2} \d{2}:\d{2}:\d{2}) \[(?P<level>[a-z]+)\] (?P<pid>\d+)#(?P<tid>\d+): (?:\*(?P<connection>\d+) )?(?P<message>.*)$",
+    )
+    .unwrap()
+});
+
+// The context NGINX appends to an error message, each part being either quoted or running up to
+// the next comma.
+static ERROR_CONTEXT_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#", (client|server|request|upstream|host): (?:"([^"]*)"|([^,]*))"#).unwrap()
+});
 
-// We know that these patterns will compile.
-static NGINX_VARIABLE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\$([a-zA-Z0-9_]+)").unwrap());
-static SPECIAL_CHARS_REGEX: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"([\.\*\+\?\|\(\)\{\}\[\]])").unwrap());
+// An AWS Application Load Balancer access log line. The fields are named after the closest NGINX
+// variables so that the derived fields work as usual and any fields added to the format by AWS
+// in the future are ignored.
+static ALB_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(concat!(
+        r#"^(?P<type>\S+) (?P<time_iso8601>\S+) (?P<elb>\S+) "#,
+        r#"(?P<remote_addr>\S+):(?P<remote_port>\d+) (?P<upstream_addr>\S+) "#,
+        r#"(?P<request_processing_time>\S+) (?P<upstream_response_time>\S+) "#,
+        r#"(?P<response_processing_time>\S+) (?P<status>\S+) (?P<upstream_status>\S+) "#,
+        r#"(?P<request_length>\S+) (?P<bytes_sent>\S+) "(?P<request>[^"]*)" "#,
+        r#""(?P<http_user_agent>[^"]*)" (?P<ssl_cipher>\S+) (?P<ssl_protocol>\S+) "#,
+        r#"(?P<target_group_arn>\S+) "(?P<trace_id>[^"]*)" "(?P<host>[^"]*)"(?: .*)?$"#,
+    ))
+    .unwrap()
+});
 
-pub(crate) fn format_to_pattern(mut format: &str) -> Result<Regex> {
-    if format == COMBINED {
-        format = LOG_FORMAT_COMBINED;
+// Pull out the `"key": "$variable"` pairs of a log_format that writes JSON objects.
+static JSON_TEMPLATE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#""([^"]+)"\s*:\s*"?\$(?:\{([a-zA-Z0-9_]+)\}|([a-zA-Z0-9_]+))"#).unwrap()
+});
+
+/// The SQLite type affinity of a column.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Integer,
+    Real,
+    Text,
+}
+
+impl ColumnType {
+    /// The type of the column holding the given variable or derived field.
+    pub fn of(name: &str) -> ColumnType {
+        match name {
+            "status"
+            | "body_bytes_sent"
+            | "bytes_sent"
+            | "request_length"
+            | "connection"
+            | "connection_requests"
+            | "content_length"
+            | "pid"
+            | "tid"
+            | "remote_port"
+            | "server_port" => ColumnType::Integer,
+            n if n == super::STATUS_TYPE
+                || n == super::TIMESTAMP
+                || n == super::ASN
+                || n == super::IS_BOT =>
+            {
+                ColumnType::Integer
+            }
+            "request_time"
+            | "upstream_response_time"
+            | "upstream_connect_time"
+            | "upstream_header_time"
+            | "upstream_queue_time"
+            | "request_processing_time"
+            | "response_processing_time"
+            | "msec" => ColumnType::Real,
+            n if n == super::UPSTREAM_TIME_SUM
+                || n == super::UPSTREAM_TIME_MAX
+                || n == super::UPSTREAM_TIME_LAST =>
+            {
+                ColumnType::Real
+            }
+            _ => ColumnType::Text,
+        }
+    }
+
+    /// The name of the type when declaring a column.
+    pub fn declaration(self) -> &'static str {
+        match self {
+            ColumnType::Integer => "INTEGER",
+            ColumnType::Real => "REAL",
+            ColumnType::Text => "TEXT",
+        }
+    }
+
+    /// Convert a raw value into one for a column of this type. NGINX writes a dash for an empty
+    /// value which is stored as NULL for numeric columns so it does not skew any aggregates. Other
+    /// values that do not parse are kept as is.
+    pub fn value(self, raw: Option<&str>) -> Box<dyn ToSql + Send + Sync> {
+        let raw = raw.unwrap_or("");
+        match self {
+            ColumnType::Text => Box::new(raw.to_string()),
+            _ if raw.is_empty() || raw == "-" => Box::new(Null),
+            ColumnType::Integer => match raw.parse::<i64>() {
+                Ok(i) => Box::new(i),
+                Err(_) => Box::new(raw.to_string()),
+            },
+            ColumnType::Real => match raw.parse::<f64>() {
+                Ok(r) => Box::new(r),
+                Err(_) => Box::new(raw.to_string()),
+            },
+        }
     }
+}
+
+/// The variables extracted from a single line of an access log.
+pub enum Variables<'a> {
+    Captures(regex::Captures<'a>),
+    Map(HashMap<String, String>),
 
-    // Escape all of the existing special characters.
-    let pattern = SPECIAL_CHARS_REGEX.replace_all(format, r"\$1");
+    /// The values of the given variables in the same order.
+    Fields(&'static [&'static str], Vec<&'a str>),
 
-    // Name our capture groups based on their name in the specified log format.
-    let captures = NGINX_VARIABLE_REGEX.replace_all(&pattern, r"(?P<$1>.*)");
-    Ok(Regex::new(&captures)?)
+    /// The variables of a line that was forwarded through syslog along with the hostname that
+    /// sent it.
+    Syslog(&'a str, Box<Variables<'a>>),
 }
 
-// List the available variables based on the supplied log format.
-pub(crate) fn available_variables(format: &str) -> Result<String> {
-    Ok(format_to_pattern(format)?
-        .capture_names()
-        .filter_map(|c| match c {
-            Some(n) => {
-                // Make some adjustments based on the schema.
-                if n == "status" {
-                    return Some(String::from(super::STATUS_TYPE));
-                } else if n == "body_bytes_sent" {
-                    return Some(String::from(super::BYTES_SENT));
-                } else if n == "request" {
-                    return Some(String::from(super::REQUEST_PATH));
-                }
-                Some(n.to_string())
-            }
-            None => None,
+impl Variables<'_> {
+    /// Return the value of the given variable if it was present on the line.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        match self {
+            Variables::Captures(c) => c.name(name).map(|m| m.as_str()),
+            Variables::Map(m) => m.get(name).map(String::as_str),
+            Variables::Fields(names, values) => names
+                .iter()
+                .position(|n| *n == name)
+                .and_then(|i| values.get(i).copied()),
+            Variables::Syslog(hostname, _) if name == "hostname" => Some(hostname),
+            Variables::Syslog(_, v) => v.get(name),
+        }
+    }
+
+    /// Parse the time of the request as seconds since the Unix epoch using the most precise time
+    /// variable that is available.
+    pub fn timestamp(&self) -> Option<i64> {
+        if let Some(msec) = self.get("msec") {
+            return msec.parse::<f64>().ok().map(|m| m as i64);
+        }
+        if let Some(iso) = self.get("time_iso8601") {
+            return DateTime::parse_from_rfc3339(iso)
+                .ok()
+                .map(|t| t.timestamp());
+        }
+        if let Some(t) = self.get("time_local") {
+            return DateTime::parse_from_str(t, TIME_LOCAL_FORMAT)
+                .ok()
+                .map(|t| t.timestamp());
+        }
+        self.get("time").and_then(|t| {
+            NaiveDateTime::parse_from_str(t, ERROR_TIME_FORMAT)
+                .ok()
+                .map(time::local_timestamp)
         })
-        .collect::<Vec<String>>()
-        .join(", "))
+    }
+}
+
+/// Describes how each line of an access log is broken up into variables.
+pub enum LogParser {
+    /// A regular expression generated from an NGINX log_format string.
+    Pattern(Regex),
+
+    /// The combined format, or the common format when false, which is split by hand since it is
+    /// by far the most used and several times faster to parse that way than with a regex.
+    Combined(bool),
+
+    /// One JSON object per line. The optional mapping renames JSON keys to the NGINX variables
+    /// they were written from, otherwise the keys themselves are used as variable names.
+    Json(Option<HashMap<String, String>>),
+
+    /// The NGINX error log rather than an access log.
+    Error,
+
+    /// The W3C extended log format written by CloudFront and IIS. The variables are named by the
+    /// `#Fields` header of each file and are not known until it has been read.
+    W3c(Vec<String>),
+
+    /// Labeled tab-separated values where each field is written as label:value. The variables
+    /// are named by the labels of each line.
+    Ltsv,
+
+    /// Space separated key=value pairs whose values can be quoted. The variables are named by the
+    /// keys of each line.
+    Logfmt,
+}
+
+impl fmt::Display for LogParser {
+    // Describe what a line has to look like to be parsed, such as the generated regex.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogParser::Pattern(p) => write!(f, "the regular expression {}", p.as_str()),
+            LogParser::Combined(true) => write!(f, "the log format {}", LOG_FORMAT_COMBINED),
+            LogParser::Combined(false) => write!(f, "the log format {}", LOG_FORMAT_COMMON),
+            LogParser::Json(_) => write!(f, "a JSON object"),
+            LogParser::Error => write!(
+                f,
+                "the error log regular expression {}",
+                ERROR_LINE_REGEX.as_str()
+            ),
+            LogParser::W3c(fields) => write!(f, "the W3C fields {}", fields.join(" ")),
+            LogParser::Ltsv => write!(f, "tab separated label:value fields"),
+            LogParser::Logfmt => write!(f, "space separated key=value pairs"),
+        }
+    }
+}
+
+impl LogParser {
+    /// Create a parser for the given format name or log_format string. A log_format that produces
+    /// JSON objects (as is common with `escape=json`) is detected automatically.
+    pub fn new(format: &str) -> Result<LogParser> {
+        if format == JSON {
+            return Ok(LogParser::Json(None));
+        }
+        if format == ERROR_LOG {
+            return Ok(LogParser::Error);
+        }
+        if format == ALB {
+            return Ok(LogParser::Pattern(ALB_REGEX.clone()));
+        }
+        if format == W3C {
+            return Ok(LogParser::W3c(Vec::new()));
+        }
+        if format == LTSV {
+            return Ok(LogParser::Ltsv);
+        }
+        if format == LOGFMT {
+            return Ok(LogParser::Logfmt);
+        }
+        if let Some(pattern) = format.strip_prefix(REGEX_PREFIX) {
+            let regex = Regex::new(pattern)?;
+            if regex.capture_names().flatten().next().is_none() {
+                return Err(anyhow!(
+                    "the regex has no named capture groups: {}",
+                    pattern
+                ));
+            }
+            return Ok(LogParser::Pattern(regex));
+        }
+
+        let format = resolve_format(format);
+        if format == LOG_FORMAT_COMBINED || format == LOG_FORMAT_COMMON {
+            return Ok(LogParser::Combined(format == LOG_FORMAT_COMBINED));
+        }
+        if format.trim_start().starts_with('{') {
+            let mapping = JSON_TEMPLATE_REGEX
+                .captures_iter(format)
+                .map(|c| {
+                    let variable = c.get(2).or_else(|| c.get(3)).unwrap().as_str();
+                    (c[1].to_string(), variable.to_string())
+                })
+                .collect::<HashMap<String, String>>();
+            debug!("json log format mapping: {:?}", mapping);
+            return Ok(LogParser::Json(Some(mapping)));
+        }
+
+        Ok(LogParser::Pattern(format_to_pattern(format)?))
+    }
+
+    /// Parse a single line returning None if it does not match the format.
+    pub fn parse<'a>(&self, line: &'a str) -> Option<Variables<'a>> {
+        match self {
+            LogParser::Pattern(p) => p.captures(line).map(Variables::Captures),
+            LogParser::Combined(full) => {
+                split_combined(line, *full).map(|v| Variables::Fields(COMBINED_VARIABLES, v))
+            }
+            LogParser::Json(mapping) => {
+                let object = match serde_json::from_str::<serde_json::Value>(line) {
+                    Ok(serde_json::Value::Object(o)) => o,
+                    _ => return None,
+                };
+
+                let mut variables = HashMap::with_capacity(object.len());
+                for (key, value) in object {
+                    let name = match mapping {
+                        Some(m) => match m.get(&key) {
+                            Some(v) => v.clone(),
+                            None => continue,
+                        },
+                        None => key,
+                    };
+                    let value = match value {
+                        serde_json::Value::String(s) => s,
+                        serde_json::Value::Null => String::new(),
+                        v => v.to_string(),
+                    };
+                    variables.insert(name, value);
+                }
+
+                Some(Variables::Map(variables))
+            }
+            LogParser::Error => parse_error_line(line).map(Variables::Map),
+            LogParser::W3c(fields) => {
+                if line.starts_with('#') {
+                    return None;
+                }
+
+                // CloudFront separates the values with tabs and IIS with spaces.
+                let values: Vec<&str> = if line.contains('\t') {
+                    line.split('\t').collect()
+                } else {
+                    line.split(' ').collect()
+                };
+                if fields.is_empty() || values.len() != fields.len() {
+                    return None;
+                }
+
+                let mut variables = fields
+                    .iter()
+                    .cloned()
+                    .zip(values.into_iter().map(String::from))
+                    .collect::<HashMap<String, String>>();
+                // The date and time are separate fields that are always in UTC.
+                if let (Some(date), Some(time)) = (variables.get("date"), variables.get("time")) {
+                    let iso = format!("{}T{}Z", date, time);
+                    variables.insert(String::from("time_iso8601"), iso);
+                }
+
+                Some(Variables::Map(variables))
+            }
+            LogParser::Ltsv => {
+                let mut variables = HashMap::new();
+                for field in line.split('\t') {
+                    let (label, value) = field.split_once(':')?;
+                    let name = match LTSV_LABELS.iter().find(|(l, _)| *l == label) {
+                        Some((_, variable)) => variable,
+                        None => label,
+                    };
+                    // The time is usually written like $time_local but in brackets.
+                    let value = match name {
+                        "time_local" => value.trim_start_matches('[').trim_end_matches(']'),
+                        _ => value,
+                    };
+                    variables.insert(name.to_string(), value.to_string());
+                }
+
+                Some(Variables::Map(variables))
+            }
+            LogParser::Logfmt => parse_logfmt_line(line).map(Variables::Map),
+        }
+    }
+
+    /// Return a parser for a file given all of its lines when the format is described by a header
+    /// in the file itself. The last `#Fields` header is used.
+    pub fn with_header(&self, lines: &[String]) -> Option<LogParser> {
+        match self {
+            LogParser::W3c(_) => {
+                let header = lines
+                    .iter()
+                    .take_while(|l| l.starts_with('#'))
+                    .filter_map(|l| l.strip_prefix("#Fields:"))
+                    .last()?;
+                let fields = header
+                    .split_whitespace()
+                    .map(
+                        |f| match W3C_VARIABLES.iter().find(|(name, _)| *name == f) {
+                            Some((_, variable)) => variable.to_string(),
+                            None => f
+                                .chars()
+                                .map(|c| {
+                                    if c.is_ascii_alphanumeric() {
+                                        c.to_ascii_lowercase()
+                                    } else {
+                                        '_'
+                                    }
+                                })
+                                .collect::<String>()
+                                .trim_end_matches('_')
+                                .to_string(),
+                        },
+                    )
+                    .collect::<Vec<String>>();
+                debug!("w3c fields: {:?}", fields);
+                Some(LogParser::W3c(fields))
+            }
+            _ => None,
+        }
+    }
+
+    /// The columns that can be stored for this format, if they are known ahead of time. This
+    /// includes the fields that are derived from the variables.
+    pub fn columns(&self) -> Option<Vec<String>> {
+        let variables = self.variables()?;
+        let mut columns: Vec<String> = Vec::with_capacity(variables.len());
+        for n in &variables {
+            // Make some adjustments based on the schema.
+            let names = match n.as_str() {
+                "status" => vec![super::STATUS_TYPE],
+                "body_bytes_sent" => vec![super::BYTES_SENT],
+                "request" => vec![
+                    super::REQUEST_METHOD,
+                    super::REQUEST_PATH,
+                    super::REQUEST_PROTOCOL,
+                    super::SCAN_TYPE,
+                ],
+                "request_uri" => vec!["request_uri", super::SCAN_TYPE],
+                "http_user_agent" => vec![
+                    "http_user_agent",
+                    super::UA_BROWSER,
+                    super::UA_OS,
+                    super::UA_DEVICE,
+                    super::UA_NAME,
+                    super::IS_BOT,
+                    super::BOT_NAME,
+                    super::TOOL_NAME,
+                ],
+                "http_referer" => vec!["http_referer", super::REFERER_DOMAIN],
+                "remote_addr" => vec!["remote_addr", super::REMOTE_NET],
+                "server_name" => vec!["server_name", super::HOST],
+                "http_host" => vec!["http_host", super::HOST],
+                "upstream_response_time" => vec![
+                    "upstream_response_time",
+                    super::UPSTREAM_TIME_SUM,
+                    super::UPSTREAM_TIME_MAX,
+                    super::UPSTREAM_TIME_LAST,
+                ],
+                n => vec![n],
+            };
+            for name in names {
+                if !columns.iter().any(|c| c == name) {
+                    columns.push(String::from(name));
+                }
+            }
+        }
+
+        if variables
+            .iter()
+            .any(|v| v == "msec" || v == "time_iso8601" || v == "time_local" || v == "time")
+        {
+            columns.push(String::from(super::TIMESTAMP));
+        }
+        columns.push(String::from(super::SOURCE_FILE));
+
+        Some(columns)
+    }
+
+    /// The variables this parser can produce, if they are known ahead of time.
+    pub(crate) fn variables(&self) -> Option<Vec<String>> {
+        match self {
+            LogParser::Pattern(p) => Some(p.capture_names().flatten().map(String::from).collect()),
+            LogParser::Combined(full) => {
+                let n = if *full { COMBINED_VARIABLES.len() } else { 6 };
+                Some(
+                    COMBINED_VARIABLES[..n]
+                        .iter()
+                        .map(|v| v.to_string())
+                        .collect(),
+                )
+            }
+            LogParser::Json(Some(m)) => {
+                let mut variables = m.values().cloned().collect::<Vec<String>>();
+                variables.sort();
+                Some(variables)
+            }
+            LogParser::Json(None) | LogParser::Ltsv | LogParser::Logfmt => None,
+            LogParser::Error => Some(ERROR_LOG_VARIABLES.iter().map(|v| v.to_string()).collect()),
+            LogParser::W3c(fields) if fields.is_empty() => None,
+            LogParser::W3c(fields) => {
+                let mut variables = fields.clone();
+                if fields.iter().any(|f| f == "date") && fields.iter().any(|f| f == "time") {
+                    variables.push(String::from("time_iso8601"));
+                }
+                Some(variables)
+            }
+        }
+    }
+}
+
+// Break a logfmt line such as `method=GET path=/ msg="not found"` into its variables. A key
+// without a value is kept with an empty value.
+fn parse_logfmt_line(line: &str) -> Option<HashMap<String, String>> {
+    let mut variables = HashMap::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=' && !c.is_whitespace()) {
+            key.push(c);
+        }
+        let mut value = String::new();
+        if chars.next_if_eq(&'=').is_some() {
+            if chars.next_if_eq(&'"').is_some() {
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => value.push(chars.next()?),
+                        c => value.push(c),
+                    }
+                }
+            } else {
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    value.push(c);
+                }
+            }
+        }
+        if key.is_empty() {
+            return None;
+        }
+
+        let name = match LOGFMT_KEYS.iter().find(|(k, _)| *k == key) {
+            Some((_, variable)) => variable.to_string(),
+            None => key,
+        };
+        variables.insert(name, value);
+    }
+
+    // Plain text has no pairs at all.
+    if variables.values().all(String::is_empty) {
+        return None;
+    }
+    Some(variables)
+}
+
+// Break an error log line into its variables. The context that follows the message, such as the
+// client and upstream, is split off into variables of its own.
+fn parse_error_line(line: &str) -> Option<HashMap<String, String>> {
+    let c = ERROR_LINE_REGEX.captures(line)?;
+    let mut variables = HashMap::with_capacity(ERROR_LOG_VARIABLES.len());
+    for name in &["time", "level", "pid", "tid", "connection"] {
+        if let Some(m) = c.name(name) {
+            variables.insert(name.to_string(), m.as_str().to_string());
+        }
+    }
+
+    let message = &c["message"];
+    let mut end = message.len();
+    for context in ERROR_CONTEXT_REGEX.captures_iter(message) {
+        end = end.min(context.get(0).unwrap().start());
+        let value = context.get(2).or_else(|| context.get(3)).unwrap().as_str();
+        variables.insert(context[1].to_string(), value.to_string());
+    }
+    variables.insert(String::from("message"), message[..end].to_string());
+
+    Some(variables)
+}
+
+/// Try every built-in format on a sample of lines and return the name of the one that matches the
+/// most of them along with the fraction that matched. When several match equally well the one
+/// with the most variables wins since the presets are prefixes of each other.
+pub fn detect_format(lines: &[String]) -> Option<(String, f64)> {
+    let mut candidates = PRESETS
+        .iter()
+        .filter_map(|(name, _)| Some((*name, LogParser::new(name).ok()?)))
+        .collect::<Vec<(&str, LogParser)>>();
+    candidates.sort_by_key(|(_, p)| std::cmp::Reverse(p.variables().map_or(0, |v| v.len())));
+    for name in &[ALB, JSON, LTSV, LOGFMT, ERROR_LOG] {
+        candidates.push((name, LogParser::new(name).ok()?));
+    }
+    if let Some(p) = LogParser::W3c(Vec::new()).with_header(lines) {
+        candidates.insert(0, (W3C, p));
+    }
+
+    let lines = lines
+        .iter()
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect::<Vec<&String>>();
+    if lines.is_empty() {
+        return None;
+    }
+
+    let mut best = None;
+    let mut best_rate = 0.0;
+    for (name, parser) in &candidates {
+        // Almost any line can be read as LTSV or logfmt so those only count when they have a status.
+        let matched = lines
+            .iter()
+            .filter_map(|l| parser.parse(l))
+            .filter(|v| {
+                !matches!(parser, LogParser::Ltsv | LogParser::Logfmt) || v.get("status").is_some()
+            })
+            .count();
+        let rate = matched as f64 / lines.len() as f64;
+        debug!(
+            "format {} matched {} of {} lines",
+            name,
+            matched,
+            lines.len()
+        );
+        if rate > best_rate {
+            best = Some(name.to_string());
+            best_rate = rate;
+        }
+    }
+
+    best.map(|name| (name, best_rate))
+}
+
+// Resolve the name of a predefined format to its log_format string.
+fn resolve_format(format: &str) -> &str {
+    PRESETS
+        .iter()
+        .find(|(name, _)| *name == format)
+        .map_or(format, |(_, f)| f)
+}
+
+// Split a line of the combined or common format into the values of its variables. Quotes within
+// the values are escaped by NGINX so the quoted ones end at the next quote.
+fn split_combined(line: &str, full: bool) -> Option<Vec<&str>> {
+    let (remote_addr, rest) = line.split_once(" - ")?;
+    let (remote_user, rest) = rest.split_once(" [")?;
+    let (time_local, rest) = rest.split_once("] \"")?;
+    let (request, rest) = rest.split_once("\" ")?;
+    let (status, rest) = rest.split_once(' ')?;
+    if !full {
+        return Some(vec![
+            remote_addr,
+            remote_user,
+            time_local,
+            request,
+            status,
+            rest,
+        ]);
+    }
+    let (body_bytes_sent, rest) = rest.split_once(" \"")?;
+    let (http_referer, rest) = rest.split_once("\" \"")?;
+    let http_user_agent = rest.strip_suffix('"')?;
+
+    Some(vec![
+        remote_addr,
+        remote_user,
+        time_local,
+        request,
+        status,
+        body_bytes_sent,
+        http_referer,
+        http_user_agent,
+    ])
+}
+
+// The narrowest pattern that matches a variable given the text around it. A value between quotes
+// ends at the next quote that is not escaped, one between brackets at the closing bracket and one
+// that cannot contain spaces at the next space. Anything else could contain whatever follows it.
+fn variable_pattern(name: &str, before: &str, after: &str) -> &'static str {
+    let spaceless = !name.starts_with("upstream_")
+        && (ColumnType::of(name) != ColumnType::Text || WORD_VARIABLES.contains(&name));
+    match after.chars().next() {
+        Some('"') if before.ends_with('"') => r#"(?:[^"\\]|\\.)*"#,
+        Some(']') if before.ends_with('[') => r"[^\]]*",
+        Some(' ') if spaceless => r"\S*",
+        _ => ".*",
+    }
+}
+
+pub fn format_to_pattern(format: &str) -> Result<Regex> {
+    let format = resolve_format(format);
+    let mut pattern = String::from("^");
+    let mut names: Vec<&str> = Vec::new();
+    let mut last = 0;
+
+    for c in NGINX_VARIABLE_REGEX.captures_iter(format) {
+        let whole = c.get(0).unwrap();
+        let name = c.get(1).or_else(|| c.get(2)).unwrap().as_str();
+
+        // Everything between two variables is literal text that must be escaped.
+        pattern.push_str(&regex::escape(&format[last..whole.start()]));
+
+        // Name our capture groups based on their name in the specified log format. A variable
+        // may appear more than once but a regex cannot reuse a group name, so only the first
+        // occurrence is captured.
+        let class = variable_pattern(name, &format[last..whole.start()], &format[whole.end()..]);
+        if names.contains(&name) {
+            pattern.push_str(&format!("(?:{})", class));
+        } else {
+            pattern.push_str(&format!("(?P<{}>{})", name, class));
+            names.push(name);
+        }
+        last = whole.end();
+    }
+    pattern.push_str(&regex::escape(&format[last..]));
+    pattern.push('$');
+
+    Ok(Regex::new(&pattern)?)
+}
+
+// Split the contents of an NGINX configuration file into tokens. Comments are dropped, quoted
+// strings are unquoted and the special characters `;`, `{` and `}` are their own tokens.
+fn tokenize_conf(contents: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = contents.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            ';' | '{' | '}' => tokens.push(c.to_string()),
+            '"' | '\'' => {
+                let mut token = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => {
+                            if let Some(escaped) = chars.next() {
+                                if escaped != c {
+                                    token.push('\\');
+                                }
+                                token.push(escaped);
+                            }
+                        }
+                        Some(n) if n == c => break,
+                        Some(n) => token.push(n),
+                        None => return Err(anyhow!("unterminated string in configuration")),
+                    }
+                }
+                tokens.push(token);
+            }
+            c if c.is_whitespace() => {}
+            _ => {
+                let mut token = c.to_string();
+                while let Some(&n) = chars.peek() {
+                    if n.is_whitespace() || n == ';' || n == '{' || n == '}' {
+                        break;
+                    }
+                    token.push(n);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+// Walk the tokens of a configuration file collecting every log_format directive and following
+// any include directives. Relative include paths are resolved against the directory of the main
+// configuration file just like NGINX does.
+fn collect_log_formats(
+    contents: &str,
+    conf_dir: &Path,
+    formats: &mut HashMap<String, String>,
+) -> Result<()> {
+    let tokens = tokenize_conf(contents)?;
+    let mut directive: Vec<String> = Vec::new();
+
+    for token in tokens {
+        match token.as_str() {
+            ";" => {
+                match directive.first().map(String::as_str) {
+                    Some("log_format") if directive.len() > 2 => {
+                        // Skip the optional escape parameter and join the remaining strings.
+                        let format = directive[2..]
+                            .iter()
+                            .filter(|t| !t.starts_with("escape="))
+                            .map(String::as_str)
+                            .collect::<String>();
+                        debug!("found log_format {}: {}", directive[1], format);
+                        formats.insert(directive[1].clone(), format);
+                    }
+                    Some("include") if directive.len() == 2 => {
+                        let pattern = conf_dir.join(&directive[1]);
+                        let pattern = pattern.to_string_lossy();
+                        for path in glob::glob(&pattern)? {
+                            let path = path?;
+                            debug!("following include: {}", path.display());
+                            let contents = fs::read_to_string(&path)
+                                .with_context(|| format!("reading {}", path.display()))?;
+                            collect_log_formats(&contents, conf_dir, formats)?;
+                        }
+                    }
+                    _ => {}
+                }
+                directive.clear();
+            }
+            "{" | "}" => directive.clear(),
+            _ => directive.push(token),
+        }
+    }
+
+    Ok(())
+}
+
+/// Read an NGINX configuration file, along with anything it includes, and return all of the named
+/// log formats that it defines.
+pub fn log_formats_from_conf(path: &Path) -> Result<HashMap<String, String>> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let conf_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut formats = HashMap::new();
+    collect_log_formats(&contents, conf_dir, &mut formats)?;
+    Ok(formats)
+}
+
+// List the available variables based on the supplied log format.
+pub fn available_variables(format: &str) -> Result<String> {
+    let parser = LogParser::new(format)?;
+    match parser.columns() {
+        Some(c) => Ok(c.join(", ")),
+        None => Ok(format!(
+            "{}, {}, {}",
+            match parser {
+                LogParser::W3c(_) => "the fields named by the #Fields header",
+                LogParser::Ltsv => "any label present in the lines",
+                LogParser::Logfmt => "any key present in the lines",
+                _ => "any key present in the JSON objects",
+            },
+            super::TIMESTAMP,
+            super::SOURCE_FILE
+        )),
+    }
 }
 
 #[cfg(test)]
@@ -55,4 +981,263 @@ mod tests {
         let pattern = format_to_pattern(LOG_FORMAT_COMBINED).unwrap();
         assert!(pattern.captures(line).is_some());
     }
+
+    #[test]
+    fn combined_is_split() {
+        let line = r#"172.17.0.1 - - [06/Jun/2020:23:16:43 +0000] "GET /a\x22b HTTP/1.1" 403 153 "-" "curl/7.54.0 (x)""#;
+        let vars = LogParser::new("combined").unwrap().parse(line).unwrap();
+        assert_eq!(vars.get("time_local"), Some("06/Jun/2020:23:16:43 +0000"));
+        assert_eq!(vars.get("request"), Some(r"GET /a\x22b HTTP/1.1"));
+        assert_eq!(vars.get("body_bytes_sent"), Some("153"));
+        assert_eq!(vars.get("http_user_agent"), Some("curl/7.54.0 (x)"));
+
+        // The generated regex matches the same way.
+        let c = format_to_pattern(LOG_FORMAT_COMBINED)
+            .unwrap()
+            .captures(line)
+            .unwrap();
+        for name in COMBINED_VARIABLES {
+            assert_eq!(vars.get(name), c.name(name).map(|m| m.as_str()));
+        }
+
+        assert!(LogParser::new("combined")
+            .unwrap()
+            .parse(r#"172.17.0.1 - - [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 403 153"#)
+            .is_none());
+        assert!(LogParser::new("common").unwrap().parse("garbage").is_none());
+    }
+
+    #[test]
+    fn regex_format() {
+        let parser = LogParser::new(r"regex:^(?P<remote_addr>\S+) (?P<status>\d+)$").unwrap();
+        let vars = parser.parse("10.0.0.1 503").unwrap();
+        assert_eq!(vars.get("remote_addr"), Some("10.0.0.1"));
+        assert_eq!(vars.get("status"), Some("503"));
+        assert!(parser.parse("10.0.0.1 -").is_none());
+
+        assert!(LogParser::new(r"regex:^(\S+)$").is_err());
+        assert!(LogParser::new(r"regex:^(?P<a>").is_err());
+    }
+
+    #[test]
+    fn ltsv_log() {
+        let line = "host:10.0.0.1\ttime:[06/Jun/2020:23:16:43 +0000]\treq:GET /a HTTP/1.1\tstatus:200\tsize:512\tcache:HIT";
+        let parser = LogParser::new(LTSV).unwrap();
+        let vars = parser.parse(line).unwrap();
+        assert_eq!(vars.get("remote_addr"), Some("10.0.0.1"));
+        assert_eq!(vars.get("request"), Some("GET /a HTTP/1.1"));
+        assert_eq!(vars.get("body_bytes_sent"), Some("512"));
+        assert_eq!(vars.get("cache"), Some("HIT"));
+        assert_eq!(vars.timestamp(), Some(1591485403));
+        assert!(parser.parse("not ltsv").is_none());
+    }
+
+    #[test]
+    fn logfmt_log() {
+        let line = r#"ts=2020-06-06T23:16:43Z ip=10.0.0.1 method=GET path=/a status=404 msg="not \"found\"" cached"#;
+        let parser = LogParser::new(LOGFMT).unwrap();
+        let vars = parser.parse(line).unwrap();
+        assert_eq!(vars.get("remote_addr"), Some("10.0.0.1"));
+        assert_eq!(vars.get("request_uri"), Some("/a"));
+        assert_eq!(vars.get("status"), Some("404"));
+        assert_eq!(vars.get("msg"), Some(r#"not "found""#));
+        assert_eq!(vars.get("cached"), Some(""));
+        assert_eq!(vars.timestamp(), Some(1591485403));
+        assert!(parser.parse("just some text").is_none());
+        assert!(parser.parse(r#"msg="unterminated"#).is_none());
+    }
+
+    #[test]
+    fn formats_are_detected() {
+        let lines = vec![
+            String::from(
+                r#"172.17.0.1 - - [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 403 153 "-" "curl/7.54.0""#,
+            ),
+            String::from("garbage"),
+        ];
+        assert_eq!(detect_format(&lines), Some((String::from("combined"), 0.5)));
+
+        let lines = vec![String::from(r#"{"status": 200}"#)];
+        assert_eq!(detect_format(&lines), Some((String::from(JSON), 1.0)));
+        let lines = vec![String::from("status=200 path=/")];
+        assert_eq!(detect_format(&lines), Some((String::from(LOGFMT), 1.0)));
+        assert_eq!(detect_format(&[]), None);
+    }
+
+    #[test]
+    fn presets_match() {
+        let line = r#"172.17.0.1 - - [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 403 153"#;
+        let parser = LogParser::new("common").unwrap();
+        assert_eq!(parser.parse(line).unwrap().get("status"), Some("403"));
+
+        let line = r#"172.17.0.1 - - [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 200 153 "-" "curl/7.54.0" rt=0.004 uct="0.001" uht="0.003" urt="0.003""#;
+        let parser = LogParser::new("upstream_time").unwrap();
+        let vars = parser.parse(line).unwrap();
+        assert_eq!(vars.get("request_time"), Some("0.004"));
+        assert_eq!(vars.get("upstream_response_time"), Some("0.003"));
+
+        let line = r#"www.example.com:443 172.17.0.1 - - [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 200 612 "-" "curl/7.54.0""#;
+        let parser = LogParser::new("vhost_combined").unwrap();
+        let vars = parser.parse(line).unwrap();
+        assert_eq!(vars.get("server_name"), Some("www.example.com"));
+        assert_eq!(vars.get("bytes_sent"), Some("612"));
+
+        let line = r#"172.17.0.1 - - [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 200 153 "-" "curl/7.54.0" 78 389"#;
+        let parser = LogParser::new("combinedio").unwrap();
+        let vars = parser.parse(line).unwrap();
+        assert_eq!(vars.get("request_length"), Some("78"));
+        assert_eq!(vars.get("bytes_sent"), Some("389"));
+    }
+
+    #[test]
+    fn custom_format_matches() {
+        let format = r#"$remote_addr [$time_local] "$request" $status rt=$request_time urt=${upstream_response_time}s"#;
+        let line = r#"10.0.0.1 [06/Jun/2020:23:16:43 +0000] "GET /api?a=1 HTTP/1.1" 200 rt=0.123 urt=0.120s"#;
+        let pattern = format_to_pattern(format).unwrap();
+        let c = pattern.captures(line).unwrap();
+        assert_eq!(&c["request"], "GET /api?a=1 HTTP/1.1");
+        assert_eq!(&c["request_time"], "0.123");
+        assert_eq!(&c["upstream_response_time"], "0.120");
+    }
+
+    #[test]
+    fn typed_values() {
+        use rusqlite::types::{ToSqlOutput, Value, ValueRef};
+
+        let value = |t: ColumnType, raw| match t.value(raw).to_sql().unwrap() {
+            ToSqlOutput::Owned(v) => v,
+            ToSqlOutput::Borrowed(ValueRef::Text(t)) => {
+                Value::Text(String::from_utf8(t.to_vec()).unwrap())
+            }
+            o => panic!("unexpected output: {:?}", o),
+        };
+
+        assert_eq!(ColumnType::of("request_time"), ColumnType::Real);
+        assert_eq!(value(ColumnType::Real, Some("0.25")), Value::Real(0.25));
+        assert_eq!(value(ColumnType::Real, Some("-")), Value::Null);
+        assert_eq!(
+            value(ColumnType::Real, Some("0.1, 0.2")),
+            Value::Text(String::from("0.1, 0.2"))
+        );
+        assert_eq!(value(ColumnType::Integer, Some("404")), Value::Integer(404));
+        assert_eq!(
+            value(ColumnType::Text, Some("-")),
+            Value::Text(String::from("-"))
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        let parser = LogParser::new(JSON).unwrap();
+        let vars = parser
+            .parse(r#"{"time_local": "06/Jun/2020:23:16:43 +0000"}"#)
+            .unwrap();
+        assert_eq!(vars.timestamp(), Some(1591485403));
+        let vars = parser
+            .parse(r#"{"time_iso8601": "2020-06-06T16:16:43-07:00"}"#)
+            .unwrap();
+        assert_eq!(vars.timestamp(), Some(1591485403));
+        let vars = parser.parse(r#"{"msec": "1591485403.123"}"#).unwrap();
+        assert_eq!(vars.timestamp(), Some(1591485403));
+        let vars = parser.parse(r#"{"status": "200"}"#).unwrap();
+        assert_eq!(vars.timestamp(), None);
+    }
+
+    #[test]
+    fn json_lines() {
+        let line = r#"{"status": 502, "request": "GET / HTTP/1.1", "request_time": 1.5}"#;
+        let parser = LogParser::new(JSON).unwrap();
+        let vars = parser.parse(line).unwrap();
+        assert_eq!(vars.get("status"), Some("502"));
+        assert_eq!(vars.get("request_time"), Some("1.5"));
+        assert!(parser.parse("not json").is_none());
+
+        let template = r#"{"addr":"$remote_addr","code":$status}"#;
+        let parser = LogParser::new(template).unwrap();
+        let vars = parser
+            .parse(r#"{"addr":"10.0.0.1","code":200,"other":"x"}"#)
+            .unwrap();
+        assert_eq!(vars.get("remote_addr"), Some("10.0.0.1"));
+        assert_eq!(vars.get("status"), Some("200"));
+        assert_eq!(vars.get("other"), None);
+    }
+
+    #[test]
+    fn alb_log() {
+        let line = r#"https 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.086 0.048 0.037 200 200 0 57 "GET https://www.example.com:443/ HTTP/1.1" "curl/7.46.0" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2 arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/my-targets/73e2d6bc24d8a067 "Root=1-58337281-1d84f3d73c47ec4e58577259" "www.example.com" "arn:aws:acm:us-east-2:123456789012:certificate/12345678-1234-1234-1234-123456789012" 1 2018-07-02T22:22:48.364000Z "authenticate,forward" "-" "-" "10.0.0.1:80" "200" "-" "-""#;
+        let parser = LogParser::new(ALB).unwrap();
+        let vars = parser.parse(line).unwrap();
+        assert_eq!(vars.get("remote_addr"), Some("192.168.131.39"));
+        assert_eq!(vars.get("upstream_response_time"), Some("0.048"));
+        assert_eq!(vars.get("status"), Some("200"));
+        assert_eq!(vars.get("bytes_sent"), Some("57"));
+        assert_eq!(vars.get("http_user_agent"), Some("curl/7.46.0"));
+        assert_eq!(vars.get("host"), Some("www.example.com"));
+        assert_eq!(vars.timestamp(), Some(1530570180));
+    }
+
+    #[test]
+    fn w3c_log() {
+        let lines = vec![
+            String::from("#Version: 1.0"),
+            String::from("#Fields: date time x-edge-location sc-bytes c-ip cs-method cs(Host) cs-uri-stem sc-status cs(User-Agent) time-taken"),
+            String::from("2019-12-04\t21:02:31\tLAX1\t392\t192.0.2.100\tGET\td111111abcdef8.cloudfront.net\t/index.html\t200\tMozilla/5.0\t0.001"),
+        ];
+        let parser = LogParser::new(W3C).unwrap();
+        assert!(parser.parse(&lines[2]).is_none());
+
+        let parser = parser.with_header(&lines).unwrap();
+        assert!(parser.parse(&lines[0]).is_none());
+        let vars = parser.parse(&lines[2]).unwrap();
+        assert_eq!(vars.get("remote_addr"), Some("192.0.2.100"));
+        assert_eq!(vars.get("request_uri"), Some("/index.html"));
+        assert_eq!(vars.get("x_edge_location"), Some("LAX1"));
+        assert_eq!(vars.get("time_taken"), Some("0.001"));
+        assert_eq!(vars.timestamp(), Some(1575493351));
+        assert!(parser.columns().unwrap().contains(&String::from("ts")));
+    }
+
+    #[test]
+    fn error_log() {
+        let line = r#"2020/06/06 23:16:43 [error] 7#7: *3 connect() failed (111: Connection refused) while connecting to upstream, client: 172.17.0.1, server: localhost, request: "GET /api HTTP/1.1", upstream: "http://127.0.0.1:8080/api", host: "localhost:8080""#;
+        let parser = LogParser::new(ERROR_LOG).unwrap();
+        let vars = parser.parse(line).unwrap();
+        assert_eq!(vars.get("level"), Some("error"));
+        assert_eq!(vars.get("pid"), Some("7"));
+        assert_eq!(vars.get("connection"), Some("3"));
+        assert_eq!(
+            vars.get("message"),
+            Some("connect() failed (111: Connection refused) while connecting to upstream")
+        );
+        assert_eq!(vars.get("client"), Some("172.17.0.1"));
+        assert_eq!(vars.get("request"), Some("GET /api HTTP/1.1"));
+        assert_eq!(vars.get("upstream"), Some("http://127.0.0.1:8080/api"));
+        assert!(vars.timestamp().is_some());
+
+        let line = "2020/06/06 23:16:43 [notice] 1#1: signal process started";
+        let vars = parser.parse(line).unwrap();
+        assert_eq!(vars.get("message"), Some("signal process started"));
+        assert_eq!(vars.get("client"), None);
+        assert!(parser.parse("not an error log line").is_none());
+    }
+
+    #[test]
+    fn conf_log_formats() {
+        let conf = r#"
+http {
+    # The main format.
+    log_format  main  '$remote_addr - $remote_user [$time_local] "$request" '
+                      '$status $body_bytes_sent';
+    log_format json escape=json '{"addr":"$remote_addr"}';
+    access_log /var/log/nginx/access.log main;
+}
+"#;
+        let mut formats = HashMap::new();
+        collect_log_formats(conf, Path::new("."), &mut formats).unwrap();
+        assert_eq!(
+            formats["main"],
+            r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent"#
+        );
+        assert_eq!(formats["json"], r#"{"addr":"$remote_addr"}"#);
+    }
 }
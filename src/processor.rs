@@ -1,52 +1,151 @@
-use std::fmt::Debug;
-use std::io::{self, Write};
+use std::fmt::{self, Debug};
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use crossterm::cursor::RestorePosition;
 use crossterm::execute;
-use log::debug;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use rusqlite::backup::Backup;
 use rusqlite::types::{ToSql, Value};
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OpenFlags};
 use tabwriter::TabWriter;
 
+use super::nginx::{ColumnType, LogParser};
 use super::Options;
+use super::{alert, anomaly, dns, functions, influx, statsd, time};
+
+// The most records that are inserted in a single transaction.
+const BATCH_SIZE: usize = 100_000;
 
 /// The main processing engine for all of the statistics.
-pub(crate) struct Processor {
+pub struct Processor {
     columns: String,
     conn: Connection,
-    pub(crate) fields: Vec<String>,
+    pub fields: Vec<String>,
+    pub output: OutputFormat,
     placeholders: String,
     queries: Vec<String>,
+    output_file: Option<(PathBuf, bool)>,
+    influx_url: Option<(String, Option<String>)>,
+    resolve: bool,
+    derived: Vec<Derived>,
+    max_memory: Option<u64>,
 }
 
 impl Processor {
-    /// Given the fields to keep track of and the respective queries, return a new Processor.
-    fn new(fields: Vec<String>, queries: Vec<String>) -> Result<Processor> {
+    /// Given the fields to keep track of and the respective queries, return a new Processor. The
+    /// records are kept in memory unless a database file is given.
+    pub fn new(
+        fields: Vec<String>,
+        queries: Vec<String>,
+        db: Option<&Path>,
+        output: OutputFormat,
+    ) -> Result<Processor> {
+        let conn = match db {
+            Some(path) => {
+                debug!("opening database {}", path.display());
+                Connection::open(path)?
+            }
+            None => Connection::open_in_memory()?,
+        };
+        functions::register(&conn)?;
+
         Ok(Processor {
-            columns: fields.join(", "),
-            conn: Connection::open_in_memory()?,
+            columns: fields
+                .iter()
+                .map(|f| quote_identifier(f))
+                .collect::<Vec<String>>()
+                .join(", "),
+            conn,
             fields: fields.clone(),
+            output,
             placeholders: fields
                 .iter()
                 .map(|f| format!(":{}", f))
                 .collect::<Vec<String>>()
                 .join(", "),
             queries,
+            output_file: None,
+            influx_url: None,
+            resolve: false,
+            derived: Vec::new(),
+            max_memory: None,
         })
     }
 
-    /// After establishing a new connection, create the table and indexes we need.
-    fn initialize(&self) -> Result<()> {
-        let create_stmt = format!("CREATE TABLE log ({})", self.columns);
+    /// Write the results to a file instead of standard output, either adding to the end of it or
+    /// replacing what it held.
+    pub fn set_output_file(&mut self, path: &Path, append: bool) {
+        self.output_file = Some((path.to_path_buf(), append));
+    }
+
+    /// After establishing a new connection, create the table and indexes we need. A database that
+    /// was created by a previous run is reused, adding any columns that it does not have yet.
+    pub fn initialize(&self) -> Result<()> {
+        // A query over an existing database may not need any particular columns.
+        if self.fields.is_empty() {
+            return Ok(());
+        }
+
+        let definitions = self
+            .fields
+            .iter()
+            .map(|f| {
+                format!(
+                    "{} {}",
+                    quote_identifier(f),
+                    ColumnType::of(f).declaration()
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+        let create_stmt = format!("CREATE TABLE IF NOT EXISTS log ({})", definitions);
         debug!("create table statement: {}", create_stmt);
         self.conn.execute(&create_stmt, params![])?;
 
-        for (i, field) in self.fields.iter().enumerate() {
+        let existing = self
+            .conn
+            .prepare("SELECT name FROM pragma_table_info('log')")?
+            .query_map(params![], |r| r.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        for field in self.fields.iter().filter(|f| !existing.contains(f)) {
+            let alter_stmt = format!(
+                "ALTER TABLE log ADD COLUMN {} {}",
+                quote_identifier(field),
+                ColumnType::of(field).declaration()
+            );
+            debug!("alter table statement: {}", alter_stmt);
+            self.conn.execute(&alter_stmt, params![])?;
+        }
+
+        // The expression of a derived column that already exists cannot be changed.
+        for d in self.derived.iter().filter(|d| !existing.contains(&d.name)) {
+            let alter_stmt = format!(
+                "ALTER TABLE log ADD COLUMN {} AS ({}) VIRTUAL",
+                quote_identifier(&d.name),
+                d.expression
+            );
+            debug!("alter table statement: {}", alter_stmt);
+            self.conn
+                .execute(&alter_stmt, params![])
+                .with_context(|| format!("invalid expression for {}", d.name))?;
+        }
+
+        for field in self
+            .fields
+            .iter()
+            .chain(self.derived.iter().map(|d| &d.name))
+        {
             let index_stmt = format!(
-                "CREATE INDEX log_idx{i} on log ({field})",
-                i = i,
-                field = field
+                "CREATE INDEX IF NOT EXISTS {index} on log ({field})",
+                index = quote_identifier(&format!("log_{}_idx", field)),
+                field = quote_identifier(field)
             );
             debug!("create index statement: {}", index_stmt);
             self.conn.execute(&index_stmt, params![])?;
@@ -55,11 +154,60 @@ impl Processor {
         Ok(())
     }
 
-    /// Insert all of the given records into the database.
-    pub(crate) fn process(
-        &self,
-        records: Vec<Vec<(String, Box<dyn ToSql + Send + Sync>)>>,
-    ) -> Result<()> {
+    /// Keep at most about this many bytes of the database in memory. An in-memory database cannot
+    /// grow past it so this only makes sense for one backed by a file.
+    pub fn limit_memory(&self, bytes: u64) -> Result<()> {
+        // A negative cache size is in KiB rather than in pages.
+        let kib = (bytes / 1024).max(1);
+        self.conn.execute_batch(&format!(
+            "PRAGMA cache_size = -{}; PRAGMA temp_store = FILE;",
+            kib
+        ))?;
+        Ok(())
+    }
+
+    /// Move the records of an in-memory database to a temporary file by spill_to_disk once they
+    /// take up more than this many bytes.
+    pub fn set_max_memory(&mut self, bytes: u64) {
+        self.max_memory = Some(bytes);
+    }
+
+    /// Move the records to a temporary file that is removed when done if they take up more memory
+    /// than was given to set_max_memory, keeping only about that much of them in memory from then
+    /// on. Returns whether they were moved.
+    pub fn spill_to_disk(&mut self) -> Result<bool> {
+        let bytes = match self.max_memory {
+            Some(b) => b,
+            None => return Ok(false),
+        };
+        let pages: i64 = self
+            .conn
+            .query_row("PRAGMA page_count", params![], |r| r.get(0))?;
+        let page_size: i64 = self
+            .conn
+            .query_row("PRAGMA page_size", params![], |r| r.get(0))?;
+        if (pages * page_size) as u64 <= bytes {
+            return Ok(false);
+        }
+
+        // SQLite writes an unnamed database to a temporary file once its cache is full.
+        debug!(
+            "moving the {} bytes of records in memory to a temporary file",
+            pages * page_size
+        );
+        let mut disk = Connection::open("")?;
+        functions::register(&disk)?;
+        Backup::new(&self.conn, &mut disk)?.run_to_completion(1024, Duration::ZERO, None)?;
+        self.conn = disk;
+        self.max_memory = None;
+        self.limit_memory(bytes)?;
+        Ok(true)
+    }
+
+    /// Insert all of the given records into the database. The inserts are grouped into
+    /// transactions of at most BATCH_SIZE records since committing each insert on its own is very
+    /// slow.
+    pub fn process(&self, records: Vec<Vec<(String, Box<dyn ToSql + Send + Sync>)>>) -> Result<()> {
         let insert_stmt = format!(
             "INSERT INTO LOG ({columns}) VALUES ({placeholders})",
             columns = self.columns,
@@ -67,68 +215,162 @@ impl Processor {
         );
         debug!("insert records statement: {}", insert_stmt);
 
-        let mut stmt = self.conn.prepare_cached(&insert_stmt)?;
-        for record in records {
-            stmt.execute(
-                &*record
-                    .iter()
-                    .map(|r| (r.0.as_str(), &r.1 as &dyn ToSql))
-                    .collect::<Vec<(&str, &dyn ToSql)>>(),
-            )?;
+        for batch in records.chunks(BATCH_SIZE) {
+            let tx = self.conn.unchecked_transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(&insert_stmt)?;
+                for record in batch {
+                    stmt.execute(
+                        &*record
+                            .iter()
+                            .map(|r| (r.0.as_str(), &r.1 as &dyn ToSql))
+                            .collect::<Vec<(&str, &dyn ToSql)>>(),
+                    )?;
+                }
+            }
+            tx.commit()?;
         }
 
         Ok(())
     }
 
-    /// Run the queries as specified by the user.
-    pub(crate) fn report(&self, save_cursor: bool) -> Result<()> {
-        for query in &self.queries {
-            debug!("report query: {}", query);
+    /// Remove every record that happened before the given Unix timestamp, returning how many were
+    /// removed.
+    pub fn delete_before(&self, ts: i64) -> Result<usize> {
+        let delete_stmt = format!("DELETE FROM log WHERE {} < ?", super::TIMESTAMP);
+        debug!("delete records statement: {}", delete_stmt);
+        Ok(self
+            .conn
+            .prepare_cached(&delete_stmt)?
+            .execute(params![ts])?)
+    }
 
-            let mut stmt = self.conn.prepare_cached(query)?;
-            let columns = stmt
-                .column_names()
-                .iter()
-                .map(|c| c.to_string())
-                .collect::<Vec<String>>();
-            let col_count = stmt.column_count();
-            let rows = stmt.query_map(params![], |r| {
+    /// Whether there are no records.
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(!self
+            .conn
+            .prepare_cached("SELECT 1 FROM log LIMIT 1")?
+            .exists(params![])?)
+    }
+
+    /// Remove every record, returning how many were removed.
+    pub fn clear(&self) -> Result<usize> {
+        debug!("removing every record");
+        Ok(self.conn.execute("DELETE FROM log", params![])?)
+    }
+
+    /// Remove every record that happened more than the given duration before the newest one, along
+    /// with the records without a time, returning how many were removed.
+    pub fn keep_last(&self, duration: Duration) -> Result<usize> {
+        let delete_stmt = format!(
+            "DELETE FROM log WHERE {ts} IS NULL OR {ts} < (SELECT MAX({ts}) FROM log) - ?",
+            ts = super::TIMESTAMP
+        );
+        debug!("delete records statement: {}", delete_stmt);
+        Ok(self
+            .conn
+            .prepare_cached(&delete_stmt)?
+            .execute(params![duration.as_secs() as i64])?)
+    }
+
+    /// Run a single query returning its column names and every row.
+    pub fn query(&self, query: &str) -> Result<QueryResult> {
+        debug!("report query: {}", query);
+
+        let mut stmt = self.conn.prepare_cached(query)?;
+        let columns = stmt
+            .column_names()
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<String>>();
+        let col_count = stmt.column_count();
+        let rows = stmt
+            .query_map(params![], |r| {
                 let mut row = Vec::with_capacity(col_count);
                 for i in 0..col_count {
                     row.push(r.get_ref(i)?.into());
                 }
+                Ok(row)
+            })?
+            .collect::<rusqlite::Result<Vec<Vec<Value>>>>()?;
 
-                let columns = columns.clone();
-                Ok(QueryResult { columns, row })
-            })?;
+        Ok(QueryResult { columns, rows })
+    }
 
-            let stdout = io::stdout();
-            let mut tw = TabWriter::new(stdout.lock());
-            let mut wrote_headers = false;
-            for r in rows {
-                let r = r?;
+    /// Push the results to an InfluxDB write endpoint instead of writing them out.
+    pub fn set_influx_url(&mut self, url: &str, token: Option<&str>) {
+        self.influx_url = Some((url.to_string(), token.map(str::to_string)));
+    }
 
-                if !wrote_headers {
-                    writeln!(&mut tw, "{}", r.columns.join("\t"))?;
-                    wrote_headers = true;
-                }
+    /// Add columns computed from the stored fields when the table is created.
+    pub fn set_derived(&mut self, derived: &[Derived]) {
+        self.derived = derived.to_vec();
+    }
+
+    /// Show the names of the client addresses next to them in the results that are written.
+    pub fn set_resolve(&mut self, resolve: bool) {
+        self.resolve = resolve;
+    }
+
+    /// Run the queries as specified by the user.
+    pub fn report(&self, save_cursor: bool) -> Result<()> {
+        self.write(&self.results()?, save_cursor)
+    }
+
+    /// Run the queries as specified by the user returning their results.
+    pub fn results(&self) -> Result<Vec<QueryResult>> {
+        self.queries.iter().map(|q| self.query(q)).collect()
+    }
+
+    /// Write out the results of the queries in the chosen output format.
+    pub fn write(&self, results: &[QueryResult], save_cursor: bool) -> Result<()> {
+        let resolved;
+        let results = if self.resolve {
+            resolved = dns::add_hostnames(results);
+            &resolved
+        } else {
+            results
+        };
 
-                for val in r.row {
-                    match val {
-                        Value::Null => write!(&mut tw, "null\t")?,
-                        Value::Integer(i) => write!(&mut tw, "{}\t", i)?,
-                        Value::Real(r) => write!(&mut tw, "{:.2}\t", r)?,
-                        Value::Text(t) => write!(&mut tw, "{}\t", t)?,
-                        Value::Blob(b) => write!(&mut tw, "{}\t", String::from_utf8(b)?)?,
-                    }
+        // A write to InfluxDB that fails while tailing is only logged so that the next interval
+        // can try again.
+        if let Some((url, token)) = &self.influx_url {
+            let lines = influx::lines(results)?;
+            return match influx::push(url, token.as_deref(), &lines) {
+                Err(e) if save_cursor => {
+                    warn!("{}", e);
+                    Ok(())
                 }
-                writeln!(&mut tw)?;
+                r => r,
+            };
+        }
+
+        if let Some((path, append)) = &self.output_file {
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(*append)
+                .truncate(!*append)
+                .open(path)?;
+            let mut out = BufWriter::new(file);
+            // Each snapshot written while tailing says when it was taken.
+            if save_cursor {
+                write_snapshot(&mut out, self.output, results)?;
+            } else {
+                write_results(&mut out, self.output, results)?;
             }
-            tw.flush()?;
+            return Ok(out.flush()?);
         }
 
-        // Restore our original cursor position only in tail mode.
-        if save_cursor {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        write_results(&mut out, self.output, results)?;
+        out.flush()?;
+        drop(out);
+
+        // Restore our original cursor position only in tail mode. Points are a stream that is
+        // never redrawn.
+        if save_cursor && self.output != OutputFormat::Influx {
             execute!(io::stdout(), RestorePosition)?;
         }
 
@@ -136,14 +378,471 @@ impl Processor {
     }
 }
 
-/// This represents a generic query result with column names and a row as a result.
+/// A column computed from the stored fields with an SQLite expression. It is written as
+/// name=expression, such as "is_api=request_path LIKE '/api/%'".
+#[derive(Debug, Clone, PartialEq)]
+pub struct Derived {
+    pub name: String,
+    pub expression: String,
+}
+
+// The names in an expression outside of its string literals.
+static LITERAL_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"'(?:[^']|'')*'").unwrap());
+static NAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"[A-Za-z_][A-Za-z0-9_]*").unwrap());
+
+impl Derived {
+    /// The names the expression refers to, which includes keywords and functions as well as
+    /// fields.
+    pub fn names(&self) -> Vec<String> {
+        let expression = LITERAL_REGEX.replace_all(&self.expression, "''");
+        NAME_REGEX
+            .find_iter(&expression)
+            .map(|m| m.as_str().to_string())
+            .collect()
+    }
+}
+
+impl FromStr for Derived {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, expression) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected name=expression, got {}", s))?;
+        let name = name.trim();
+        if NAME_REGEX.find(name).is_none_or(|m| m.as_str() != name) {
+            return Err(anyhow!("invalid column name {:?}", name));
+        }
+        if expression.trim().is_empty() {
+            return Err(anyhow!("missing an expression for {}", name));
+        }
+        Ok(Derived {
+            name: name.to_string(),
+            expression: expression.trim().to_string(),
+        })
+    }
+}
+
+/// The ways in which query results can be written out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Tab aligned columns with a header row.
+    Table,
+
+    /// A JSON array holding an object for each row.
+    Json,
+
+    /// InfluxDB line protocol with a point for each row.
+    Influx,
+
+    /// A line for each column of every row, which suits long values better than a table.
+    Vertical,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "influx" => Ok(OutputFormat::Influx),
+            "vertical" => Ok(OutputFormat::Vertical),
+            _ => Err(anyhow!("unknown output format: {}", s)),
+        }
+    }
+}
+
+/// The columns to sort the results of the default queries by, each either ascending or
+/// descending. It is written like `5XX desc,count` where the direction defaults to descending.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderBy(Vec<(String, bool)>);
+
+impl OrderBy {
+    /// Sort by a single column in descending order.
+    pub fn column(name: &str) -> OrderBy {
+        OrderBy(vec![(name.to_string(), true)])
+    }
+
+    /// The column that is sorted by first.
+    pub fn first(&self) -> &str {
+        &self.0[0].0
+    }
+}
+
+impl FromStr for OrderBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let columns = s
+            .split(',')
+            .map(|part| {
+                let mut words = part.split_whitespace();
+                let column = words
+                    .next()
+                    .ok_or_else(|| anyhow!("missing a column to order by in {:?}", s))?;
+                let descending = match words.next().map(str::to_ascii_lowercase).as_deref() {
+                    None | Some("desc") => true,
+                    Some("asc") => false,
+                    Some(d) => return Err(anyhow!("unknown sort direction: {}", d)),
+                };
+                if let Some(extra) = words.next() {
+                    return Err(anyhow!("unexpected {:?} after {}", extra, column));
+                }
+                Ok((column.to_string(), descending))
+            })
+            .collect::<Result<Vec<(String, bool)>>>()?;
+
+        Ok(OrderBy(columns))
+    }
+}
+
+impl fmt::Display for OrderBy {
+    // Write the terms of an ORDER BY clause. The columns are quoted since names like 2XX are not
+    // valid identifiers otherwise.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (column, descending)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(
+                f,
+                "\"{}\" {}",
+                column.replace('"', "\"\""),
+                if *descending { "DESC" } else { "ASC" }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// This represents a generic query result with column names and the rows it returned.
 #[derive(Debug)]
-pub(crate) struct QueryResult {
-    columns: Vec<String>,
-    row: Vec<Value>,
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl QueryResult {
+    /// Turn every row into a JSON object keyed by the column names.
+    pub fn to_json(&self) -> Vec<serde_json::Map<String, serde_json::Value>> {
+        self.rows
+            .iter()
+            .map(|row| {
+                self.columns
+                    .iter()
+                    .cloned()
+                    .zip(row.iter().map(|val| match val {
+                        Value::Null => serde_json::Value::Null,
+                        Value::Integer(i) => (*i).into(),
+                        Value::Real(r) => (*r).into(),
+                        Value::Text(t) => t.clone().into(),
+                        Value::Blob(b) => String::from_utf8_lossy(b).into(),
+                    }))
+                    .collect::<serde_json::Map<String, serde_json::Value>>()
+            })
+            .collect()
+    }
 }
 
-pub(crate) fn generate_processor(
+/// Format a single value the way it is shown in a table.
+pub fn format_value(val: &Value) -> String {
+    match val {
+        Value::Null => String::from("null"),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(r) => format!("{:.2}", r),
+        Value::Text(t) => t.clone(),
+        Value::Blob(b) => String::from_utf8_lossy(b).into_owned(),
+    }
+}
+
+fn write_table<W: Write>(out: W, result: &QueryResult) -> Result<()> {
+    let mut tw = TabWriter::new(out);
+
+    if !result.rows.is_empty() {
+        writeln!(&mut tw, "{}", result.columns.join("\t"))?;
+    }
+
+    for row in &result.rows {
+        for val in row {
+            write!(&mut tw, "{}\t", format_value(val))?;
+        }
+        writeln!(&mut tw)?;
+    }
+
+    Ok(tw.flush()?)
+}
+
+// Write each row as a numbered header followed by a line for each column, with the names aligned
+// on the right like the \G terminator of the MySQL client.
+fn write_vertical<W: Write>(mut out: W, result: &QueryResult) -> Result<()> {
+    let width = result
+        .columns
+        .iter()
+        .map(|c| c.chars().count())
+        .max()
+        .unwrap_or(0);
+    for (i, row) in result.rows.iter().enumerate() {
+        writeln!(out, "{0} {1}. row {0}", "*".repeat(27), i + 1)?;
+        for (column, val) in result.columns.iter().zip(row) {
+            writeln!(
+                out,
+                "{:>width$}: {}",
+                column,
+                format_value(val),
+                width = width
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn write_json<W: Write>(mut out: W, result: &QueryResult) -> Result<()> {
+    serde_json::to_writer(&mut out, &result.to_json())?;
+    Ok(writeln!(out)?)
+}
+
+// Write the results one after another. The points of every result are given the same time so
+// that the fields of a group from different queries are merged into one point.
+fn write_results<W: Write>(
+    mut out: W,
+    output: OutputFormat,
+    results: &[QueryResult],
+) -> Result<()> {
+    match output {
+        OutputFormat::Table => {
+            for result in results {
+                write_table(&mut out, result)?;
+            }
+        }
+        OutputFormat::Json => {
+            for result in results {
+                write_json(&mut out, result)?;
+            }
+        }
+        OutputFormat::Vertical => {
+            for result in results {
+                write_vertical(&mut out, result)?;
+            }
+        }
+        OutputFormat::Influx => out.write_all(influx::lines(results)?.as_bytes())?,
+    }
+    Ok(())
+}
+
+// Write the results headed by the current time, which is a single JSON object holding the results
+// of every query when writing JSON.
+fn write_snapshot<W: Write>(
+    mut out: W,
+    output: OutputFormat,
+    results: &[QueryResult],
+) -> Result<()> {
+    let time = time::now().format("%Y-%m-%d %H:%M:%S %z").to_string();
+    match output {
+        OutputFormat::Table | OutputFormat::Vertical => {
+            writeln!(out, "{}", time)?;
+            write_results(&mut out, output, results)?;
+            Ok(writeln!(out)?)
+        }
+        OutputFormat::Json => {
+            let snapshot = serde_json::json!({
+                "time": time,
+                "results": results.iter().map(QueryResult::to_json).collect::<Vec<_>>(),
+            });
+            serde_json::to_writer(&mut out, &snapshot)?;
+            Ok(writeln!(out)?)
+        }
+        // Every point already carries the time.
+        OutputFormat::Influx => Ok(out.write_all(influx::lines(results)?.as_bytes())?),
+    }
+}
+
+/// Estimate how many records a count stands for when only a sample of the lines was kept.
+pub fn scaled_count(opts: &Options, count: &str) -> String {
+    match opts.sample {
+        Some(rate) => format!("CAST(ROUND({} / {}) AS INTEGER)", count, rate),
+        None => count.to_string(),
+    }
+}
+
+/// The default query giving an overview of every request. When tailing it also reports the rate
+/// of requests and bytes over the last interval, measured by the time of each record.
+pub fn summary_query(opts: &Options) -> String {
+    let rates = if opts.follow {
+        format!(
+            ",
+ROUND(COUNT(CASE WHEN {ts} > (SELECT MAX({ts}) FROM log) - {interval} THEN 1 END) * 1.0 / {interval}, 2) AS req_per_sec,
+ROUND(TOTAL(CASE WHEN {ts} > (SELECT MAX({ts}) FROM log) - {interval} THEN bytes_sent END) / {interval}, 2) AS bytes_per_sec",
+            ts = super::TIMESTAMP,
+            interval = opts.interval.max(1)
+        )
+    } else {
+        String::new()
+    };
+
+    format!(
+        "SELECT {count} AS count,
+AVG(bytes_sent) as avg_bytes_sent,
+{status_counts}{rates}
+FROM log
+ORDER BY {order_by}
+LIMIT {limit};",
+        count = scaled_count(opts, "COUNT(1)"),
+        status_counts = status_counts(opts),
+        rates = rates,
+        order_by = opts.order_by,
+        limit = opts.limit
+    )
+}
+
+/// The number of requests with each class of status code as the 2XX to 5XX columns.
+pub(crate) fn status_counts(opts: &Options) -> String {
+    (2..=5)
+        .map(|class| {
+            let count = format!("COUNT(CASE WHEN status_type = {} THEN 1 END)", class);
+            format!("{} AS '{}XX'", scaled_count(opts, &count), class)
+        })
+        .collect::<Vec<String>>()
+        .join(",\n")
+}
+
+// The columns of the detailed query besides the grouped field.
+const DETAILED_COLUMNS: &[&str] = &[
+    "count",
+    "avg_bytes_sent",
+    "2XX",
+    "3XX",
+    "4XX",
+    "5XX",
+    "error_rate",
+    "pct_of_total",
+];
+
+// The columns the detailed query gets when the format logs how long each request took.
+const LATENCY_COLUMNS: &[&str] = &["avg_request_time", "max_request_time"];
+
+/// Parse a size in bytes with an optional K, M or G suffix, such as 512M, in powers of 1024.
+pub(crate) fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let upper = s.to_ascii_uppercase();
+    let digits = upper.trim_end_matches('B').trim_end_matches('I');
+    let (number, shift) = match digits.char_indices().last() {
+        Some((i, 'K')) => (&digits[..i], 10),
+        Some((i, 'M')) => (&digits[..i], 20),
+        Some((i, 'G')) => (&digits[..i], 30),
+        Some((i, 'T')) => (&digits[..i], 40),
+        _ => (digits, 0),
+    };
+    let number = number
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| anyhow!("invalid size {}, expected a number such as 512M or 2G", s))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("the size {} is too large", s))
+}
+
+/// Parse a percentage greater than 0 and at most 100 with an optional % sign, such as 99.9%.
+pub(crate) fn parse_percent(s: &str) -> Result<f64> {
+    let number = s.trim().trim_end_matches('%').trim();
+    match number.parse::<f64>() {
+        Ok(p) if p > 0.0 && p <= 100.0 => Ok(p),
+        _ => Err(anyhow!(
+            "invalid percentage {}, expected a number above 0 and up to 100 such as 99.9",
+            s
+        )),
+    }
+}
+
+/// Quote a field name so that it can be used as a column in a query whatever it contains.
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Make sure that the --having expression only refers to the columns of the detailed query.
+pub fn check_having(opts: &Options) -> Result<()> {
+    let having = match &opts.having {
+        Some(h) => h,
+        None => return Ok(()),
+    };
+    let mut columns = DETAILED_COLUMNS.to_vec();
+    if logs_request_time(opts)? {
+        columns.extend(LATENCY_COLUMNS);
+    }
+    for field in having.fields() {
+        if field != opts.group_by && !columns.contains(&field) {
+            return Err(anyhow!(
+                "unknown column {} in --having, expected {} or one of {}",
+                field,
+                opts.group_by,
+                columns.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The default query breaking the requests down by the given field, along with the average and
+/// longest request time of each group when the format logs it.
+pub fn detailed_query(opts: &Options, group_by: &str, order_by: &OrderBy) -> Result<String> {
+    let latency = if logs_request_time(opts)? {
+        ",
+AVG(request_time) AS avg_request_time,
+MAX(request_time) AS max_request_time"
+    } else {
+        ""
+    };
+    Ok(format!(
+        "SELECT {group_by},
+{count} AS count,
+AVG(bytes_sent) AS avg_bytes_sent,
+{status_counts},
+ROUND(COUNT(CASE WHEN status_type = 5 THEN 1 END) * 1.0 / COUNT(1), 4) AS error_rate,
+ROUND(100.0 * COUNT(1) / (SELECT COUNT(1) FROM log), 2) AS pct_of_total{latency}
+FROM log
+GROUP BY {group_by}
+HAVING {having}
+ORDER BY {order_by}
+LIMIT {limit};",
+        group_by = quote_identifier(group_by),
+        count = scaled_count(opts, "COUNT(1)"),
+        status_counts = status_counts(opts),
+        having = opts
+            .having
+            .as_ref()
+            .map_or_else(|| String::from("1"), |h| h.to_sql()),
+        latency = latency,
+        order_by = order_by,
+        limit = opts.limit
+    ))
+}
+
+/// Whether a previous run already stored records in the database file.
+pub(crate) fn has_stored_records(db: &Path) -> Result<bool> {
+    if !db.exists() {
+        return Ok(false);
+    }
+    let conn = Connection::open_with_flags(db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let exists = conn
+        .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'log'")?
+        .exists(params![])?;
+    Ok(exists)
+}
+
+// Whether the format is known to log how long each request took.
+pub(crate) fn logs_request_time(opts: &Options) -> Result<bool> {
+    Ok(LogParser::new(&opts.format)?
+        .columns()
+        .is_some_and(|c| c.iter().any(|f| f == "request_time")))
+}
+
+// The average and longest request time overall, since the detailed query already has them for
+// each group. As points they land in the same series as the rows of the default queries.
+const LATENCY_QUERY: &str =
+    "SELECT AVG(request_time) AS avg_request_time, MAX(request_time) AS max_request_time
+FROM log;";
+
+pub fn generate_processor(
     opts: &Options,
     fields: Option<Vec<String>>,
     queries: Option<Vec<String>>,
@@ -162,46 +861,200 @@ pub(crate) fn generate_processor(
         }
     }
 
-    let default_summary_query = format!(
-        "SELECT count(1) AS count,
-AVG(bytes_sent) as avg_bytes_sent,
-COUNT(CASE WHEN status_type = 2 THEN 1 END) AS '2XX',
-COUNT(CASE WHEN status_type = 3 THEN 1 END) AS '3XX',
-COUNT(CASE WHEN status_type = 4 THEN 1 END) AS '4XX',
-COUNT(CASE WHEN status_type = 5 THEN 1 END) AS '5XX'
-FROM log
-ORDER BY {order_by} DESC
-LIMIT {limit};",
-        order_by = opts.order_by,
-        limit = opts.limit
-    );
+    // Records are stored with the Unix timestamp of the most precise time variable the format has,
+    // which is indexed like every other column, so that times can be compared and bucketed without
+    // parsing them in SQL. The retention window, --last and the rates of the default summary when
+    // tailing need it in any case.
+    let has_time = LogParser::new(&opts.format)?
+        .columns()
+        .is_some_and(|c| c.iter().any(|f| f == super::TIMESTAMP));
+    let needs_time = (has_time && !log_fields.is_empty())
+        || opts.window.is_some()
+        || opts.last.is_some()
+        || (opts.follow && queries.is_none());
+    if needs_time && !log_fields.iter().any(|f| f == super::TIMESTAMP) {
+        log_fields.push(String::from(super::TIMESTAMP));
+    }
+    // Alerts need the status and size of every request and list the groups with the most.
+    if !opts.alert.is_empty() {
+        for field in alert::FIELDS.iter().chain(&[opts.group_by.as_str()]) {
+            if !log_fields.iter().any(|f| f == field) {
+                log_fields.push(field.to_string());
+            }
+        }
+    }
 
-    let default_detailed_query = format!(
-        "SELECT {group_by},
-COUNT(1) AS count,
-AVG(bytes_sent) AS avg_bytes_sent,
-COUNT(CASE WHEN status_type = 2 THEN 1 END) AS '2XX',
-COUNT(CASE WHEN status_type = 3 THEN 1 END) AS '3XX',
-COUNT(CASE WHEN status_type = 4 THEN 1 END) AS '4XX',
-COUNT(CASE WHEN status_type = 5 THEN 1 END) AS '5XX'
-FROM log
-GROUP BY {group_by}
-HAVING {having_opt}
-ORDER BY {order_by} DESC
-LIMIT {limit};",
-        group_by = opts.group_by,
-        having_opt = opts.having,
-        order_by = opts.order_by,
-        limit = opts.limit
-    );
+    // Dashboards fed from InfluxDB want to know how long requests took as well when the format
+    // logs it.
+    let latency =
+        opts.output == OutputFormat::Influx && queries.is_none() && logs_request_time(opts)?;
+    // StatsD gets the size and status of the new requests each interval and how long they took.
+    // So does looking for unusual intervals.
+    if opts.anomaly.is_some() {
+        for field in anomaly::FIELDS {
+            if !log_fields.iter().any(|f| f == field) {
+                log_fields.push(field.to_string());
+            }
+        }
+    }
+    if opts.statsd.is_some() {
+        for field in statsd::FIELDS {
+            if !log_fields.iter().any(|f| f == field) {
+                log_fields.push(field.to_string());
+            }
+        }
+    }
+    // So do StatsD and the default detailed query, which covers the percentiles shown next to
+    // its groups when tailing and InfluxDB.
+    if (opts.statsd.is_some() || queries.is_none())
+        && logs_request_time(opts)?
+        && !log_fields.iter().any(|f| f == "request_time")
+    {
+        log_fields.push(String::from("request_time"));
+    }
 
     let log_queries = match queries {
         Some(q) => q,
-        None => vec![default_summary_query, default_detailed_query],
+        None => {
+            let mut q = vec![
+                summary_query(opts),
+                detailed_query(opts, &opts.group_by, &opts.order_by)?,
+            ];
+            if latency {
+                q.push(String::from(LATENCY_QUERY));
+            }
+            q
+        }
     };
 
-    let p = Processor::new(log_fields, log_queries)?;
+    // Derived columns are computed by SQLite from the fields their expressions refer to, which need
+    // to be stored instead.
+    if !opts.derive.is_empty() {
+        let columns = LogParser::new(&opts.format)?.columns().unwrap_or_default();
+        log_fields.retain(|f| !opts.derive.iter().any(|d| d.name == *f));
+        for name in opts.derive.iter().flat_map(Derived::names) {
+            if columns.contains(&name) && !log_fields.contains(&name) {
+                log_fields.push(name);
+            }
+        }
+    }
+
+    let mut p = Processor::new(log_fields, log_queries, opts.db.as_deref(), opts.output)?;
+    if let Some(path) = &opts.output_file {
+        p.set_output_file(path, opts.append);
+    }
+    p.set_resolve(opts.resolve);
+    if let Some(url) = &opts.influx_url {
+        p.set_influx_url(url, opts.influx_token.as_deref());
+    }
+    // The records are only moved to a temporary file once they grow too large for memory.
+    match (&opts.db, opts.max_memory) {
+        (Some(_), Some(bytes)) => p.limit_memory(bytes)?,
+        (None, Some(bytes)) => p.set_max_memory(bytes),
+        _ => {}
+    }
+    p.set_derived(&opts.derive);
     p.initialize()?;
 
     Ok(p)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn order_by_is_parsed() {
+        let order_by = "5XX desc, count asc,avg_bytes_sent"
+            .parse::<OrderBy>()
+            .unwrap();
+        assert_eq!(order_by.first(), "5XX");
+        assert_eq!(
+            order_by.to_string(),
+            r#""5XX" DESC, "count" ASC, "avg_bytes_sent" DESC"#
+        );
+        assert_eq!(OrderBy::column("count").to_string(), r#""count" DESC"#);
+
+        assert!("count up".parse::<OrderBy>().is_err());
+        assert!("count,".parse::<OrderBy>().is_err());
+        assert!("count desc 2XX".parse::<OrderBy>().is_err());
+    }
+
+    #[test]
+    fn derived_columns_are_parsed() {
+        let d = "is_api = request_path LIKE '/api/%' AND status_type = 2"
+            .parse::<Derived>()
+            .unwrap();
+        assert_eq!(d.name, "is_api");
+        assert_eq!(
+            d.expression,
+            "request_path LIKE '/api/%' AND status_type = 2"
+        );
+        assert_eq!(
+            d.names(),
+            vec!["request_path", "LIKE", "AND", "status_type"]
+        );
+        assert!("2xx=status_type = 2".parse::<Derived>().is_err());
+        assert!("is_api".parse::<Derived>().is_err());
+        assert!("is_api=".parse::<Derived>().is_err());
+    }
+
+    #[test]
+    fn sizes_are_parsed() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size("2GiB").unwrap(), 2 << 30);
+        assert_eq!(parse_size("64kb").unwrap(), 64 << 10);
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn percentages_are_parsed() {
+        assert_eq!(parse_percent("99.9").unwrap(), 99.9);
+        assert_eq!(parse_percent(" 99.95% ").unwrap(), 99.95);
+        assert_eq!(parse_percent("100").unwrap(), 100.0);
+        assert!(parse_percent("100.1").is_err());
+        assert!(parse_percent("0%").is_err());
+        assert!(parse_percent("high").is_err());
+    }
+
+    #[test]
+    fn records_are_moved_to_disk_when_memory_runs_out() {
+        let fields = vec![String::from("request_path")];
+        let mut processor = Processor::new(fields, Vec::new(), None, OutputFormat::Table).unwrap();
+        processor.initialize().unwrap();
+        processor.set_max_memory(64 * 1024);
+        let records = |n| {
+            (0..n)
+                .map(|i| {
+                    vec![(
+                        String::from(":request_path"),
+                        Box::new(format!("/{}", i)) as Box<dyn ToSql + Send + Sync>,
+                    )]
+                })
+                .collect()
+        };
+        processor.process(records(10)).unwrap();
+        assert!(!processor.spill_to_disk().unwrap());
+
+        processor.process(records(10_000)).unwrap();
+        assert!(processor.spill_to_disk().unwrap());
+        let file: String = processor
+            .conn
+            .query_row("PRAGMA database_list", params![], |r| r.get(2))
+            .unwrap();
+        assert_eq!(file, "");
+        let cache: i64 = processor
+            .conn
+            .query_row("PRAGMA cache_size", params![], |r| r.get(0))
+            .unwrap();
+        assert_eq!(cache, -64);
+        // The records and functions are still there, and they are only moved once.
+        let result = processor
+            .query("SELECT COUNT(1) FROM log WHERE regexp('^/1', request_path)")
+            .unwrap();
+        assert_eq!(result.rows[0][0], Value::Integer(1 + 1 + 10 + 100 + 1000));
+        assert!(!processor.spill_to_disk().unwrap());
+    }
+}
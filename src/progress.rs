@@ -0,0 +1,79 @@
+use std::fs;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use super::{journal, s3, STDIN};
+
+/// Shows how far along reading the access logs is on standard error when it is a terminal. The
+/// progress is measured in bytes read from the files, which are compressed or not, along with the
+/// rate at which lines are parsed.
+pub(crate) struct Progress {
+    bar: ProgressBar,
+    lines: AtomicU64,
+    start: Instant,
+}
+
+impl Progress {
+    pub(crate) fn new(access_logs: &[String]) -> Progress {
+        // Only local files have a known size.
+        let total = access_logs
+            .iter()
+            .filter(|l| *l != STDIN && journal::unit(l).is_none() && !s3::is_object(l))
+            .filter_map(|l| fs::metadata(l).ok())
+            .map(|m| m.len())
+            .sum::<u64>();
+
+        let bar = if atty::isnt(atty::Stream::Stderr) {
+            ProgressBar::hidden()
+        } else if total > 0 {
+            ProgressBar::new(total).with_style(
+                ProgressStyle::with_template(
+                    "{bar:40} {bytes}/{total_bytes} ({binary_bytes_per_sec}, {eta}) {msg}",
+                )
+                .expect("the progress template is valid"),
+            )
+        } else {
+            ProgressBar::new_spinner()
+        };
+
+        Progress {
+            bar,
+            lines: AtomicU64::new(0),
+            start: Instant::now(),
+        }
+    }
+
+    /// A progress that is never shown, for reading that is not part of the parsing.
+    pub(crate) fn hidden() -> Progress {
+        Progress {
+            bar: ProgressBar::hidden(),
+            lines: AtomicU64::new(0),
+            start: Instant::now(),
+        }
+    }
+
+    /// Count the bytes read from a file towards the progress.
+    pub(crate) fn wrap<R: Read>(&self, reader: R) -> impl Read {
+        self.bar.wrap_read(reader)
+    }
+
+    /// Count lines that were parsed.
+    pub(crate) fn parsed(&self, lines: usize) {
+        let lines = self.lines.fetch_add(lines as u64, Ordering::Relaxed) + lines as u64;
+        let elapsed = self.start.elapsed().as_secs_f64().max(0.001);
+        self.bar.set_message(format!(
+            "{} lines, {:.0} lines/s",
+            lines,
+            lines as f64 / elapsed
+        ));
+        self.bar.tick();
+    }
+
+    /// Remove the progress bar so that the results can be written.
+    pub(crate) fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use rusqlite::types::Value;
+
+use super::processor::{logs_request_time, quote_identifier, OutputFormat, Processor, QueryResult};
+use super::sketch::TDigest;
+use super::Options;
+
+/// The columns holding the estimated percentiles of the request time of each group.
+pub(crate) const COLUMNS: &[&str] = &["p95_ms", "p99_ms"];
+const PERCENTILES: &[f64] = &[0.95, 0.99];
+
+/// Whether the percentiles are shown, which is for the groups of the default report when tailing
+/// into a table and the format logs the request time.
+pub(crate) fn enabled(opts: &Options, custom_queries: bool) -> Result<bool> {
+    Ok(opts.follow
+        && !custom_queries
+        && !opts.approximate
+        && matches!(opts.output, OutputFormat::Table | OutputFormat::Vertical)
+        && logs_request_time(opts)?)
+}
+
+/// Keeps a t-digest of the request time of each group of the detailed query while tailing so
+/// that its percentiles can be shown every interval without sorting every request time again.
+/// Only the records that arrived since the previous interval are read from the database.
+#[derive(Default)]
+pub(crate) struct Quantiles {
+    group_by: String,
+    last_row: i64,
+    digests: HashMap<String, TDigest>,
+}
+
+impl Quantiles {
+    /// Forget every request time added so far. A digest cannot take single times out again, so
+    /// after records are removed the digests are built again from the ones that are left.
+    pub(crate) fn reset(&mut self) {
+        *self = Quantiles::default();
+    }
+
+    /// Add the records that arrived since the last call to the digests and then add a column for
+    /// each percentile in milliseconds to the result, whose first column is the group.
+    pub(crate) fn add_columns(
+        &mut self,
+        processor: &Processor,
+        result: &mut QueryResult,
+    ) -> Result<()> {
+        // Start over from the first record when the groups change, such as when regrouping in the
+        // dashboard.
+        let group_by = result.columns.first().cloned().unwrap_or_default();
+        if group_by != self.group_by {
+            *self = Quantiles {
+                group_by,
+                ..Quantiles::default()
+            };
+        }
+
+        let new = processor.query(&format!(
+            "SELECT rowid, {group_by}, request_time FROM log
+WHERE rowid > {last_row} AND request_time IS NOT NULL",
+            group_by = quote_identifier(&self.group_by),
+            last_row = self.last_row
+        ))?;
+        for row in &new.rows {
+            if let Value::Integer(rowid) = row[0] {
+                self.last_row = self.last_row.max(rowid);
+            }
+            let time = match row[2] {
+                Value::Real(t) => t,
+                Value::Integer(t) => t as f64,
+                _ => continue,
+            };
+            self.digests.entry(group(&row[1])).or_default().insert(time);
+        }
+
+        result.columns.extend(COLUMNS.iter().map(|c| c.to_string()));
+        for row in &mut result.rows {
+            let mut digest = self.digests.get_mut(&group(&row[0]));
+            for p in PERCENTILES {
+                let ms = digest
+                    .as_mut()
+                    .and_then(|d| d.quantile(*p))
+                    .map(|t| (t * 1000.0).round() as i64);
+                row.push(ms.map_or(Value::Null, Value::Integer));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn group(value: &Value) -> String {
+    match value {
+        Value::Text(t) => t.clone(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(r) => r.to_string(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::ToSql;
+
+    use super::*;
+
+    #[test]
+    fn removed_records_are_forgotten() {
+        let fields = ["request_path", "request_time", "ts"]
+            .iter()
+            .map(|f| f.to_string())
+            .collect::<Vec<String>>();
+        let processor = Processor::new(fields, Vec::new(), None, OutputFormat::Table).unwrap();
+        processor.initialize().unwrap();
+        // Slow requests at first and then only fast ones.
+        let records = (0..100)
+            .map(|i| {
+                let (time, ts) = if i < 50 { (10.0, 0) } else { (0.1, 100) };
+                vec![
+                    (
+                        String::from(":request_path"),
+                        Box::new("/") as Box<dyn ToSql + Send + Sync>,
+                    ),
+                    (String::from(":request_time"), Box::new(time)),
+                    (String::from(":ts"), Box::new(ts)),
+                ]
+            })
+            .collect();
+        processor.process(records).unwrap();
+
+        let result = || QueryResult {
+            columns: vec![String::from("request_path")],
+            rows: vec![vec![Value::Text(String::from("/"))]],
+        };
+        let mut quantiles = Quantiles::default();
+        let mut before = result();
+        quantiles.add_columns(&processor, &mut before).unwrap();
+        assert_eq!(before.rows[0][2], Value::Integer(10000));
+
+        assert_eq!(processor.delete_before(50).unwrap(), 50);
+        quantiles.reset();
+        let mut after = result();
+        quantiles.add_columns(&processor, &mut after).unwrap();
+        assert_eq!(
+            after.rows[0][1..],
+            [Value::Integer(100), Value::Integer(100)]
+        );
+    }
+}
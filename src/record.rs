@@ -0,0 +1,519 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use rusqlite::types::{ToSql, ToSqlOutput, Value};
+
+use super::nginx::{ColumnType, Variables};
+use super::{enrich, time};
+use super::{
+    Options, ASN, AS_ORG, BOT_NAME, BYTES_SENT, CITY, COUNTRY, HOST, IS_BOT, REFERER_DOMAIN,
+    REMOTE_NET, REQUEST_METHOD, REQUEST_PATH, REQUEST_PROTOCOL, SCAN_TYPE, SOURCE_FILE,
+    STATUS_TYPE, TIMESTAMP, TOOL_NAME, UA_BROWSER, UA_DEVICE, UA_NAME, UA_OS, UPSTREAM_TIME_LAST,
+    UPSTREAM_TIME_MAX, UPSTREAM_TIME_SUM,
+};
+
+// We know that this pattern will compile.
+static UUID_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}$")
+        .unwrap()
+});
+
+// Remove the query string from a path and replace the segments that are identifiers with a
+// placeholder so that requests for the same endpoint are grouped together.
+fn normalize_path(path: &str) -> String {
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    path.split('/')
+        .map(|segment| {
+            if !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()) {
+                ":id"
+            } else if UUID_REGEX.is_match(segment) {
+                ":uuid"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+// The path of the request. A --format-regex can capture it as request_path directly, otherwise it
+// is in $request_uri or else in $request.
+fn target_path<'a>(vars: &'a Variables) -> &'a str {
+    let path = match vars.get(REQUEST_PATH).or_else(|| vars.get("request_uri")) {
+        Some(path) => path,
+        None => split_request(vars.get("request").unwrap_or("")).1,
+    };
+    strip_origin(path)
+}
+
+// Reduce a request target in absolute form, such as https://example.com/a?b=c which load
+// balancers and proxies log, to just its path.
+fn strip_origin(target: &str) -> &str {
+    match target.find("://") {
+        Some(i) if target[..i].bytes().all(|b| b.is_ascii_alphabetic()) => {
+            let rest = &target[i + 3..];
+            rest.find('/').map_or("/", |j| &rest[j..])
+        }
+        _ => target,
+    }
+}
+
+// The site a request was for. Without $host itself this is what NGINX would have logged for it:
+// the Host header lowercased and without its port, or else the name of the server.
+fn vhost(vars: &Variables) -> Option<String> {
+    if let Some(host) = vars.get(HOST) {
+        return Some(host.to_string());
+    }
+    vars.get("http_host").and_then(referer_domain).or_else(|| {
+        vars.get("server_name")
+            .filter(|n| !n.is_empty() && *n != "-")
+            .map(String::from)
+    })
+}
+
+// The lowercased host of a referring URL such as https://User@Example.com:8080/a, which is
+// example.com, or nothing for a missing referrer.
+fn referer_domain(referer: &str) -> Option<String> {
+    let rest = match referer.find("://") {
+        Some(i) => &referer[i + 3..],
+        None => referer,
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let host = authority.rsplit('@').next().unwrap_or("");
+    let host = match host.rfind(':') {
+        Some(i) if !host.ends_with(']') => &host[..i],
+        _ => host,
+    };
+    if host.is_empty() || host == "-" {
+        None
+    } else {
+        Some(host.to_ascii_lowercase())
+    }
+}
+
+// The time of each attempt in an upstream timing such as `0.5, 1.0 : 0.25`. NGINX separates the
+// servers it tried with commas and internal redirects to another group with colons. Attempts that
+// did not get that far are written as a dash and skipped.
+fn upstream_times(raw: &str) -> Vec<f64> {
+    raw.split([',', ':'])
+        .filter_map(|t| t.trim().parse::<f64>().ok())
+        .collect()
+}
+
+/// The prefix lengths that client addresses are reduced to for the remote_net field. It is written
+/// as the IPv4 length optionally followed by the IPv6 one, such as 24 or 16,48.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Prefixes {
+    pub v4: u8,
+    pub v6: u8,
+}
+
+impl FromStr for Prefixes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |part: &str, max: u8| match part.trim().parse::<u8>() {
+            Ok(len) if len <= max => Ok(len),
+            _ => Err(anyhow!(
+                "invalid prefix length {}, expected 0 to {}",
+                part,
+                max
+            )),
+        };
+        let mut parts = s.splitn(2, ',');
+        let v4 = parse(parts.next().unwrap_or(""), 32)?;
+        let v6 = match parts.next() {
+            Some(part) => parse(part, 128)?,
+            None => 64,
+        };
+        Ok(Prefixes { v4, v6 })
+    }
+}
+
+/// The status codes to keep written as a comma separated list of codes and classes, such as 5xx or
+/// 401,403.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statuses(Vec<(u16, u16)>);
+
+impl Statuses {
+    /// Whether the status is one of these.
+    pub fn contains(&self, status: u16) -> bool {
+        self.0.iter().any(|(lo, hi)| (*lo..=*hi).contains(&status))
+    }
+}
+
+impl FromStr for Statuses {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ranges = s
+            .split(',')
+            .map(|part| {
+                let part = part.trim().to_ascii_lowercase();
+                let invalid = || {
+                    anyhow!(
+                        "invalid status {}, expected a code such as 404 or a class such as 5xx",
+                        part
+                    )
+                };
+                match part.strip_suffix("xx") {
+                    Some(class) => {
+                        let class = class
+                            .parse::<u16>()
+                            .ok()
+                            .filter(|c| (1..=5).contains(c))
+                            .ok_or_else(invalid)?;
+                        Ok((class * 100, class * 100 + 99))
+                    }
+                    None => {
+                        let code = part
+                            .parse::<u16>()
+                            .ok()
+                            .filter(|c| (100..=599).contains(c))
+                            .ok_or_else(invalid)?;
+                        Ok((code, code))
+                    }
+                }
+            })
+            .collect::<Result<Vec<(u16, u16)>, Self::Err>>()?;
+        Ok(Statuses(ranges))
+    }
+}
+
+/// A network such as 10.0.0.0/8 or 2001:db8::/32. A plain address is a network of just itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Network {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl Network {
+    /// Whether the address is part of this network.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        addr.is_ipv4() == self.addr.is_ipv4() && mask(addr, self.len) == self.addr
+    }
+}
+
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr = addr.trim().parse::<IpAddr>().map_err(|_| {
+            anyhow!(
+                "invalid network {}, expected an address such as 10.0.0.0/8",
+                s
+            )
+        })?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len.map(|l| l.trim().parse::<u8>()) {
+            Some(Ok(len)) if len <= max => len,
+            Some(_) => {
+                return Err(anyhow!(
+                    "invalid prefix length in {}, expected 0 to {}",
+                    s,
+                    max
+                ))
+            }
+            None => max,
+        };
+        Ok(Network {
+            addr: mask(addr, len),
+            len,
+        })
+    }
+}
+
+/// Clear the bits of an address after the first len of them.
+pub(crate) fn mask(addr: IpAddr, len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(a) => {
+            let bits = u32::from(a) & u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+            IpAddr::V4(bits.into())
+        }
+        IpAddr::V6(a) => {
+            let bits = u128::from(a) & u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+            IpAddr::V6(bits.into())
+        }
+    }
+}
+
+// The network a client address belongs to written in CIDR notation, such as 203.0.113.0/24.
+fn remote_net(addr: &str, prefixes: Prefixes) -> Option<String> {
+    let addr = addr.parse::<IpAddr>().ok()?;
+    let len = match addr {
+        IpAddr::V4(_) => prefixes.v4,
+        IpAddr::V6(_) => prefixes.v6,
+    };
+    Some(format!("{}/{}", mask(addr, len), len))
+}
+
+// Split a request line such as `GET /index.html HTTP/1.1` into its method, path and protocol. A
+// request that does not have all three parts is treated as just a path.
+fn split_request(request: &str) -> (&str, &str, &str) {
+    let mut parts = request.splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(protocol)) => (method, path, protocol),
+        (Some(method), Some(path), None) => (method, path, ""),
+        _ => ("", request, ""),
+    }
+}
+
+/// Decide whether the record for a line should be stored at all based on the filters given.
+pub fn keep(opts: &Options, vars: &Variables, source: &str) -> bool {
+    if let Some(statuses) = &opts.status {
+        let status = vars.get("status").and_then(|s| s.parse::<u16>().ok());
+        if !status.is_some_and(|s| statuses.contains(s)) {
+            return false;
+        }
+    }
+    if !opts.exclude_path.is_empty() {
+        let path = target_path(vars);
+        if opts.exclude_path.iter().any(|r| r.is_match(path)) {
+            return false;
+        }
+    }
+    if !opts.exclude_ip.is_empty() {
+        let addr = vars
+            .get("remote_addr")
+            .and_then(|a| a.parse::<IpAddr>().ok());
+        if addr.is_some_and(|a| opts.exclude_ip.iter().any(|n| n.contains(a))) {
+            return false;
+        }
+    }
+    if !opts.exclude_ua.is_empty() {
+        let ua = vars.get("http_user_agent").unwrap_or("");
+        if opts.exclude_ua.iter().any(|r| r.is_match(ua)) {
+            return false;
+        }
+    }
+
+    if opts.since.is_some() || opts.until.is_some() {
+        let ts = match vars.timestamp() {
+            Some(ts) => ts,
+            None => return false,
+        };
+        if opts.since.is_some_and(|since| ts < since.timestamp())
+            || opts.until.is_some_and(|until| ts > until.timestamp())
+        {
+            return false;
+        }
+    }
+
+    if let Some(filter) = &opts.filter {
+        let lookup = |field: &str| match field_value(opts, field, vars, source).to_sql() {
+            Ok(ToSqlOutput::Borrowed(v)) => v.into(),
+            Ok(ToSqlOutput::Owned(v)) => v,
+            _ => Value::Null,
+        };
+        if !filter.evaluate(&lookup) {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Compute the value to store for a field from the variables of a single line. Most fields are
+/// the variables themselves but some are derived from one or more variables.
+pub fn field_value(
+    opts: &Options,
+    field: &str,
+    vars: &Variables,
+    source: &str,
+) -> Box<dyn ToSql + Send + Sync> {
+    match field {
+        STATUS_TYPE => {
+            let status = vars.get("status").unwrap_or("");
+            Box::new(status.parse::<u16>().unwrap_or(0) / 100)
+        }
+        BYTES_SENT => {
+            let bytes_sent = vars
+                .get("body_bytes_sent")
+                .or_else(|| vars.get("bytes_sent"))
+                .unwrap_or("");
+            Box::new(bytes_sent.parse::<i64>().unwrap_or(0))
+        }
+        SOURCE_FILE => Box::new(source.to_string()),
+        TIMESTAMP => Box::new(vars.timestamp()),
+        REQUEST_PATH => {
+            let path = target_path(vars);
+            if opts.normalize_paths {
+                Box::new(normalize_path(path))
+            } else {
+                Box::new(path.to_string())
+            }
+        }
+        REQUEST_METHOD => match vars.get("request_method") {
+            Some(method) => Box::new(method.to_string()),
+            None => {
+                let (method, _, _) = split_request(vars.get("request").unwrap_or(""));
+                Box::new(method.to_string())
+            }
+        },
+        REQUEST_PROTOCOL => match vars.get("server_protocol") {
+            Some(protocol) => Box::new(protocol.to_string()),
+            None => {
+                let (_, _, protocol) = split_request(vars.get("request").unwrap_or(""));
+                Box::new(protocol.to_string())
+            }
+        },
+        COUNTRY => Box::new(vars.get("remote_addr").and_then(enrich::country)),
+        CITY => Box::new(vars.get("remote_addr").and_then(enrich::city)),
+        ASN => Box::new(vars.get("remote_addr").and_then(enrich::asn)),
+        AS_ORG => Box::new(vars.get("remote_addr").and_then(enrich::as_org)),
+        UA_BROWSER => Box::new(vars.get("http_user_agent").and_then(enrich::ua_browser)),
+        UA_OS => Box::new(vars.get("http_user_agent").and_then(enrich::ua_os)),
+        UA_DEVICE => Box::new(vars.get("http_user_agent").and_then(enrich::ua_device)),
+        UA_NAME => Box::new(vars.get("http_user_agent").and_then(enrich::ua_name)),
+        IS_BOT => Box::new(
+            vars.get("http_user_agent")
+                .and_then(enrich::bot_name)
+                .is_some(),
+        ),
+        BOT_NAME => Box::new(vars.get("http_user_agent").and_then(enrich::bot_name)),
+        TOOL_NAME => Box::new(vars.get("http_user_agent").and_then(enrich::tool_name)),
+        SCAN_TYPE => Box::new(enrich::scan_type(target_path(vars))),
+        REFERER_DOMAIN => Box::new(vars.get("http_referer").and_then(referer_domain)),
+        HOST => Box::new(vhost(vars)),
+        REMOTE_NET => Box::new(
+            vars.get("remote_addr")
+                .and_then(|a| remote_net(a, opts.cidr)),
+        ),
+        UPSTREAM_TIME_SUM | UPSTREAM_TIME_MAX | UPSTREAM_TIME_LAST => {
+            let times = upstream_times(vars.get("upstream_response_time").unwrap_or(""));
+            let value = match field {
+                _ if times.is_empty() => None,
+                UPSTREAM_TIME_SUM => Some(times.iter().sum()),
+                UPSTREAM_TIME_MAX => times.iter().copied().reduce(f64::max),
+                _ => times.last().copied(),
+            };
+            Box::new(value)
+        }
+        "time_local" | "time_iso8601" if opts.timezone.is_some() => Box::new(
+            vars.get(field)
+                .zip(opts.timezone.as_ref())
+                .map(|(t, zone)| time::convert(zone, field, t)),
+        ),
+        _ => ColumnType::of(field).value(vars.get(field)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nginx::{LogParser, REGEX_PREFIX};
+
+    #[test]
+    fn request_is_split() {
+        assert_eq!(
+            split_request("GET /a?b=c HTTP/1.1"),
+            ("GET", "/a?b=c", "HTTP/1.1")
+        );
+        assert_eq!(split_request("GET /"), ("GET", "/", ""));
+        assert_eq!(split_request("garbage"), ("", "garbage", ""));
+
+        assert_eq!(strip_origin("https://example.com:443/a?b=c"), "/a?b=c");
+        assert_eq!(strip_origin("http://example.com"), "/");
+        assert_eq!(
+            strip_origin("/a?next=http://example.com/"),
+            "/a?next=http://example.com/"
+        );
+    }
+
+    #[test]
+    fn captured_paths_are_used() {
+        let format = format!(r"{}^(?P<method>\S+) (?P<request_path>\S+)$", REGEX_PREFIX);
+        let parser = LogParser::new(&format).unwrap();
+        let vars = parser.parse("GET https://example.com/a?b=c").unwrap();
+        assert_eq!(target_path(&vars), "/a?b=c");
+
+        let parser = LogParser::new("$request_uri").unwrap();
+        assert_eq!(target_path(&parser.parse("/b").unwrap()), "/b");
+    }
+
+    #[test]
+    fn referer_domains() {
+        assert_eq!(
+            referer_domain("https://www.Google.com/search?q=nginx").as_deref(),
+            Some("www.google.com")
+        );
+        assert_eq!(
+            referer_domain("http://user@example.com:8080").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(referer_domain("-"), None);
+        assert_eq!(referer_domain(""), None);
+    }
+
+    #[test]
+    fn upstream_times_are_split() {
+        assert_eq!(upstream_times("0.012"), vec![0.012]);
+        assert_eq!(upstream_times("0.5, 1.0 : 0.25"), vec![0.5, 1.0, 0.25]);
+        assert_eq!(upstream_times("-, 0.3"), vec![0.3]);
+        assert!(upstream_times("-").is_empty());
+    }
+
+    #[test]
+    fn networks() {
+        let prefixes = "24".parse::<Prefixes>().unwrap();
+        assert_eq!(prefixes, Prefixes { v4: 24, v6: 64 });
+        assert_eq!(
+            remote_net("203.0.113.77", prefixes).as_deref(),
+            Some("203.0.113.0/24")
+        );
+        assert_eq!(
+            remote_net("2001:db8:1:2:3::4", prefixes).as_deref(),
+            Some("2001:db8:1:2::/64")
+        );
+        assert_eq!(
+            remote_net("10.1.2.3", "0,128".parse().unwrap()).as_deref(),
+            Some("0.0.0.0/0")
+        );
+        assert_eq!(remote_net("-", prefixes), None);
+        assert!("33".parse::<Prefixes>().is_err());
+        assert!("24,129".parse::<Prefixes>().is_err());
+    }
+
+    #[test]
+    fn statuses_are_matched() {
+        let statuses = "5xx, 401,403".parse::<Statuses>().unwrap();
+        assert!(statuses.contains(503));
+        assert!(statuses.contains(403));
+        assert!(!statuses.contains(404));
+        assert!("6xx".parse::<Statuses>().is_err());
+        assert!("40".parse::<Statuses>().is_err());
+    }
+
+    #[test]
+    fn networks_contain_addresses() {
+        let net = "10.1.0.0/16".parse::<Network>().unwrap();
+        assert!(net.contains("10.1.200.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(!net.contains("::a01:1".parse().unwrap()));
+
+        let host = "2001:db8::1".parse::<Network>().unwrap();
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("example.com".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn paths_are_normalized() {
+        assert_eq!(normalize_path("/users/123?page=2"), "/users/:id");
+        assert_eq!(
+            normalize_path("/orders/3f2504e0-4f89-11d3-9a0c-0305e82c3301/items/7"),
+            "/orders/:uuid/items/:id"
+        );
+        assert_eq!(normalize_path("/v2/static/app.js"), "/v2/static/app.js");
+        assert_eq!(normalize_path("/"), "/");
+    }
+}
@@ -0,0 +1,294 @@
+use std::env;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use linefeed::{Completer, Completion, Interface, Prompter, ReadResult, Signal, Terminal};
+use log::{debug, warn};
+
+use super::processor::Processor;
+
+const PROMPT: &str = "topngx> ";
+const CONTINUATION: &str = "   ...> ";
+
+// Where the statements of previous sessions are kept, relative to the home directory, and how
+// many of them.
+const HISTORY_FILE: &str = ".topngx_history";
+const HISTORY_SIZE: usize = 1000;
+
+// What separates the words that are completed, so that log.request_path or COUNT(remote_addr
+// complete just the name.
+const WORD_BREAK: &str = " \t\n\"'`(),.;=<>!|*+-/%";
+
+// The words that tab completes besides the names of the columns.
+const KEYWORDS: &[&str] = &[
+    "SELECT", "FROM", "WHERE", "GROUP", "BY", "HAVING", "ORDER", "LIMIT", "DISTINCT", "AS", "AND",
+    "OR", "NOT", "IN", "LIKE", "GLOB", "IS", "NULL", "CASE", "WHEN", "THEN", "ELSE", "END", "ASC",
+    "DESC", "COUNT", "AVG", "SUM", "TOTAL", "MIN", "MAX", "ROUND", "datetime", "log",
+];
+
+const HELP: &str = "Statements end with a semicolon and run against the log table.
+.fields  list the columns of the log table
+.help    show this message
+.quit    leave, as does Ctrl-D";
+
+// What reading a line from the terminal ended with.
+enum Input {
+    Line(String),
+    Interrupt,
+    Eof,
+}
+
+// Completes the names of the columns and the keywords.
+struct Words(Vec<String>);
+
+impl<T: Terminal> Completer<T> for Words {
+    fn complete(
+        &self,
+        word: &str,
+        _prompter: &Prompter<T>,
+        _start: usize,
+        _end: usize,
+    ) -> Option<Vec<Completion>> {
+        Some(
+            completions(&self.0, word)
+                .into_iter()
+                .map(Completion::simple)
+                .collect(),
+        )
+    }
+}
+
+// The words starting with the prefix, ignoring case, written in the case of the prefix so that
+// the editor can extend what was typed with the part they share.
+fn completions(words: &[String], prefix: &str) -> Vec<String> {
+    let lower = prefix.to_lowercase();
+    let upper = prefix.chars().any(char::is_uppercase) && !prefix.chars().any(char::is_lowercase);
+    words
+        .iter()
+        .filter(|w| w.to_lowercase().starts_with(&lower))
+        .map(|w| {
+            let rest = w.chars().skip(prefix.chars().count()).collect::<String>();
+            match (prefix.is_empty(), upper) {
+                (true, _) => w.clone(),
+                (false, true) => format!("{}{}", prefix, rest.to_uppercase()),
+                (false, false) => format!("{}{}", prefix, rest.to_lowercase()),
+            }
+        })
+        .collect()
+}
+
+// Set up the line editor with completion of the words and a history as long as the one that is
+// kept between sessions.
+fn editor<T: Terminal>(interface: Interface<T>, words: Vec<String>) -> Interface<T> {
+    interface.set_completer(Arc::new(Words(words)));
+    interface.set_report_signal(Signal::Interrupt, true);
+    interface.set_history_size(HISTORY_SIZE);
+    interface.lock_reader().set_word_break_chars(WORD_BREAK);
+    interface
+}
+
+fn read_line<T: Terminal>(interface: &Interface<T>, prompt: &str) -> Result<Input> {
+    interface.set_prompt(prompt)?;
+    Ok(match interface.read_line()? {
+        ReadResult::Input(line) => Input::Line(line),
+        ReadResult::Signal(_) => Input::Interrupt,
+        ReadResult::Eof => Input::Eof,
+    })
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+// Losing the history is not worth ending the session with an error over.
+fn save_history<T: Terminal>(interface: &Interface<T>, path: &Path) {
+    if let Err(e) = interface.save_history(path) {
+        warn!("could not save the history to {}: {}", path.display(), e);
+    }
+}
+
+// Run a statement and write its results, only reporting an error so that the session goes on.
+fn execute_statement(processor: &Processor, statement: &str) {
+    debug!("repl statement: {}", statement);
+    let written = processor
+        .query(statement)
+        .and_then(|result| processor.write(&[result], false));
+    if let Err(e) = written {
+        eprintln!("error: {:#}", e);
+    }
+}
+
+// Handle a command starting with a dot, returning false when the session should end.
+fn dot_command(processor: &Processor, command: &str) -> bool {
+    match command {
+        ".quit" | ".exit" => return false,
+        ".help" => println!("{}", HELP),
+        ".fields" => {
+            execute_statement(processor, "SELECT name, type FROM pragma_table_info('log')")
+        }
+        _ => eprintln!("unknown command {}, see .help", command),
+    }
+    true
+}
+
+// Read statements until the input ends or .quit, running each one once it ends with a semicolon.
+fn session<R, H>(processor: &Processor, mut read: R, mut remember: H) -> Result<()>
+where
+    R: FnMut(&str) -> Result<Input>,
+    H: FnMut(&str),
+{
+    let mut statement = String::new();
+    loop {
+        let prompt = if statement.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION
+        };
+        let line = match read(prompt)? {
+            Input::Line(l) => l,
+            Input::Interrupt => {
+                statement.clear();
+                continue;
+            }
+            Input::Eof => break,
+        };
+
+        let trimmed = line.trim();
+        if statement.is_empty() && trimmed.starts_with('.') {
+            remember(trimmed);
+            if !dot_command(processor, trimmed) {
+                break;
+            }
+            continue;
+        }
+        if !trimmed.is_empty() {
+            if !statement.is_empty() {
+                statement.push(' ');
+            }
+            statement.push_str(trimmed);
+        }
+        if statement.ends_with(';') {
+            remember(&statement);
+            execute_statement(processor, &statement);
+            statement.clear();
+        }
+    }
+    // A statement without its semicolon at the end of the input is run as well.
+    if !statement.is_empty() {
+        execute_statement(processor, &statement);
+    }
+    Ok(())
+}
+
+/// Read SQL statements from standard input and show their results until it ends. A terminal gets
+/// a prompt with history, which is kept between sessions, and tab completion of the column names.
+pub(crate) fn run(processor: &Processor) -> Result<()> {
+    if !(atty::is(atty::Stream::Stdin) && atty::is(atty::Stream::Stdout)) {
+        let mut lines = io::stdin().lock().lines();
+        let read = |_: &str| -> Result<Input> {
+            match lines.next() {
+                Some(line) => Ok(Input::Line(line?)),
+                None => Ok(Input::Eof),
+            }
+        };
+        return session(processor, read, |_| {});
+    }
+
+    let mut words = processor
+        .query("SELECT name FROM pragma_table_info('log')")?
+        .rows
+        .iter()
+        .filter_map(|r| match &r[0] {
+            rusqlite::types::Value::Text(t) => Some(t.clone()),
+            _ => None,
+        })
+        .collect::<Vec<String>>();
+    words.extend(KEYWORDS.iter().map(|k| k.to_string()));
+
+    let interface = editor(Interface::new("topngx")?, words);
+    let history = history_path();
+    if let Some(path) = &history {
+        // There is no history yet on the first run.
+        let _ = interface.load_history(path);
+    }
+    println!("Enter SQL statements ending with a semicolon, or .help for help.");
+    session(
+        processor,
+        |prompt| read_line(&interface, prompt),
+        |statement| interface.add_history_unique(statement.to_string()),
+    )?;
+    if let Some(path) = &history {
+        save_history(&interface, path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use linefeed::memory::MemoryTerminal;
+    use linefeed::terminal::Size;
+
+    use super::super::OutputFormat;
+    use super::*;
+
+    fn words() -> Vec<String> {
+        ["request_path", "request_time", "remote_addr", "SELECT"]
+            .iter()
+            .map(|w| w.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn words_are_completed() {
+        let words = words();
+        assert_eq!(
+            completions(&words, "req"),
+            vec!["request_path", "request_time"]
+        );
+        assert_eq!(completions(&words, "REM"), vec!["REMOTE_ADDR"]);
+        assert_eq!(completions(&words, "sel"), vec!["select"]);
+        assert_eq!(completions(&words, "x"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn statements_are_edited_in_a_narrow_terminal() {
+        let processor = Processor::new(
+            vec![String::from("request_path")],
+            Vec::new(),
+            None,
+            OutputFormat::Table,
+        )
+        .unwrap();
+        let term = MemoryTerminal::with_size(Size {
+            lines: 5,
+            columns: 12,
+        });
+        term.push_input("sel\t'日本語' AS wide, req\t\n");
+        term.resize(Size {
+            lines: 5,
+            columns: 8,
+        });
+        term.push_input("FROM log;\n.quit\n");
+        let interface = editor(Interface::with_term("topngx", term).unwrap(), words());
+        session(
+            &processor,
+            |prompt| read_line(&interface, prompt),
+            |statement| interface.add_history_unique(statement.to_string()),
+        )
+        .unwrap();
+
+        // The two completions of req are listed rather than one of them being picked.
+        let path = env::temp_dir().join(format!("topngx-history-{}", std::process::id()));
+        save_history(&interface, &path);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "select '日本語' AS wide, request_ FROM log;\n.quit\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        // A history that cannot be written is only reported.
+        save_history(&interface, Path::new("/nonexistent/.topngx_history"));
+    }
+}
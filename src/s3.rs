@@ -0,0 +1,145 @@
+use std::io::{self, ErrorKind, Read};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+
+// Objects are named by URLs with this scheme.
+const SCHEME: &str = "s3://";
+
+/// Whether a source is an S3 object.
+pub(crate) fn is_object(source: &str) -> bool {
+    source.starts_with(SCHEME)
+}
+
+// Start the AWS CLI with its standard output and error piped back. The credentials and region are
+// picked up from the environment the same way as for any other use of the CLI.
+fn spawn_aws(args: &[&str]) -> Result<Child> {
+    let mut cmd = Command::new("aws");
+    cmd.arg("s3")
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    debug!("running {:?}", cmd);
+
+    cmd.spawn().map_err(|e| match e.kind() {
+        ErrorKind::NotFound => anyhow!("reading from S3 needs the AWS CLI but aws was not found"),
+        _ => anyhow!("failed to run the aws command: {}", e),
+    })
+}
+
+// Run the AWS CLI returning what it wrote to standard output.
+fn aws(args: &[&str]) -> Result<Vec<u8>> {
+    let output = spawn_aws(args)?.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "aws s3 {} failed with {}: {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// List the URLs of every object under the given s3://bucket/prefix URL.
+pub(crate) fn list(url: &str) -> Result<Vec<String>> {
+    let bucket = url
+        .strip_prefix(SCHEME)
+        .and_then(|u| u.split('/').next())
+        .filter(|b| !b.is_empty())
+        .ok_or_else(|| anyhow!("invalid S3 URL: {}", url))?;
+
+    let listing = aws(&["ls", "--recursive", url])?;
+    let objects = parse_listing(&String::from_utf8_lossy(&listing))
+        .into_iter()
+        .map(|key| format!("{}{}/{}", SCHEME, bucket, key))
+        .collect::<Vec<String>>();
+    debug!("{} objects under {}", objects.len(), url);
+
+    Ok(objects)
+}
+
+// Pull the keys out of the output of `aws s3 ls --recursive`, where each line is the date, time,
+// size and key of an object.
+fn parse_listing(listing: &str) -> Vec<&str> {
+    listing
+        .lines()
+        .filter_map(|line| {
+            let mut rest = line.trim_start();
+            for _ in 0..3 {
+                let end = rest.find(char::is_whitespace)?;
+                rest = rest[end..].trim_start();
+            }
+            // Folders created from the console are empty objects ending with a slash.
+            Some(rest).filter(|key| !key.is_empty() && !key.ends_with('/'))
+        })
+        .collect()
+}
+
+/// The contents of an object as they are downloaded by the AWS CLI. Reaching the end fails if
+/// the download did.
+pub(crate) struct Download {
+    object: String,
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for Download {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                let mut stderr = String::new();
+                if let Some(mut e) = self.child.stderr.take() {
+                    e.read_to_string(&mut stderr)?;
+                }
+                return Err(io::Error::other(format!(
+                    "aws s3 cp {} failed with {}: {}",
+                    self.object,
+                    status,
+                    stderr.trim()
+                )));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        // Stop a download that is no longer read. It has already exited when it was read to the
+        // end, so there is nothing to do if this fails.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Start downloading a single object.
+pub(crate) fn fetch(object: &str) -> Result<Download> {
+    let mut child = spawn_aws(&["cp", "--quiet", object, "-"])?;
+    let stdout = child.stdout.take().expect("stdout should be piped");
+    Ok(Download {
+        object: object.to_string(),
+        child,
+        stdout,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing_is_parsed() {
+        let listing = "2020-06-06 23:16:43          0 logs/\n\
+                       2020-06-06 23:16:43      12345 logs/alb/2020/06/06/a.log.gz\n\
+                       2020-06-06 23:16:44        678 logs/with space.log\n";
+        assert_eq!(
+            parse_listing(listing),
+            vec!["logs/alb/2020/06/06/a.log.gz", "logs/with space.log"]
+        );
+    }
+}
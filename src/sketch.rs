@@ -0,0 +1,294 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+
+// Hash a value the same way on every run so that sketches are deterministic.
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A HyperLogLog sketch estimating the number of distinct values it has seen using a fixed amount
+/// of memory. With the default precision the standard error is about 0.8%.
+#[derive(Debug, Clone)]
+pub(crate) struct HyperLogLog {
+    precision: u32,
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog::new(14)
+    }
+}
+
+impl HyperLogLog {
+    /// Create a sketch with 2^precision registers.
+    pub(crate) fn new(precision: u32) -> HyperLogLog {
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    /// Record a value.
+    pub(crate) fn insert<T: Hash + ?Sized>(&mut self, value: &T) {
+        let h = hash(value);
+        let index = (h >> (64 - self.precision)) as usize;
+        // The rank is the position of the first set bit in the remaining bits. A sentinel bit
+        // bounds the rank when every remaining bit is zero.
+        let rest = (h << self.precision) | (1 << (self.precision - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    /// Estimate the number of distinct values that have been recorded.
+    pub(crate) fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self
+            .registers
+            .iter()
+            .map(|r| 2f64.powi(-i32::from(*r)))
+            .sum::<f64>();
+        let estimate = alpha * m * m / sum;
+
+        // Use linear counting while there are still empty registers for small cardinalities.
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+/// The Space-Saving algorithm finding the most frequent values with a fixed number of counters.
+/// When every counter is taken the least frequent value is replaced and its count inherited, so
+/// counts are overestimates by at most the recorded error. Any value occurring more than n / capacity
+/// times out of n is guaranteed to be kept.
+#[derive(Debug)]
+pub(crate) struct SpaceSaving {
+    capacity: usize,
+    counters: HashMap<String, (u64, u64)>,
+    // The counters ordered by count so the smallest can be found quickly.
+    order: BTreeSet<(u64, String)>,
+}
+
+impl SpaceSaving {
+    pub(crate) fn new(capacity: usize) -> SpaceSaving {
+        SpaceSaving {
+            capacity: capacity.max(1),
+            counters: HashMap::with_capacity(capacity),
+            order: BTreeSet::new(),
+        }
+    }
+
+    /// Count an occurrence of a value.
+    pub(crate) fn insert(&mut self, value: &str) {
+        let (count, error) = match self.counters.get(value) {
+            Some(&(count, error)) => {
+                self.order.remove(&(count, value.to_string()));
+                (count + 1, error)
+            }
+            None if self.counters.len() < self.capacity => (1, 0),
+            None => {
+                let (min, evicted) = self.order.pop_first().expect("there is a counter");
+                self.counters.remove(&evicted);
+                (min + 1, min)
+            }
+        };
+        self.counters.insert(value.to_string(), (count, error));
+        self.order.insert((count, value.to_string()));
+    }
+
+    /// The most frequent values with their estimated counts and the most each could be
+    /// overestimated by, from the most frequent.
+    pub(crate) fn top(&self, k: usize) -> Vec<(&str, u64, u64)> {
+        self.order
+            .iter()
+            .rev()
+            .take(k)
+            .map(|(count, value)| (value.as_str(), *count, self.counters[value].1))
+            .collect()
+    }
+}
+
+/// A merging t-digest summarizing a distribution with a bounded number of weighted centroids so
+/// that its quantiles can be estimated without keeping every value. Centroids are kept small near
+/// the tails, which makes high percentiles such as the 99th accurate.
+pub(crate) struct TDigest {
+    compression: f64,
+    centroids: Vec<(f64, f64)>,
+    buffer: Vec<f64>,
+    min: f64,
+    max: f64,
+}
+
+impl Default for TDigest {
+    fn default() -> TDigest {
+        TDigest::new(100.0)
+    }
+}
+
+impl TDigest {
+    /// A larger compression keeps more centroids, which is more accurate and takes more memory.
+    pub(crate) fn new(compression: f64) -> TDigest {
+        TDigest {
+            compression,
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Add a value, which is buffered until there are enough of them to merge.
+    pub(crate) fn insert(&mut self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.buffer.push(value);
+        if self.buffer.len() >= 10 * self.compression as usize {
+            self.merge();
+        }
+    }
+
+    // Merge the buffered values into the centroids. Neighbouring centroids are combined as long as
+    // the result spans at most one unit of the arcsine scale function, which allows large ones in
+    // the middle of the distribution and small ones at its ends.
+    fn merge(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut points = self.buffer.drain(..).map(|v| (v, 1.0)).collect::<Vec<_>>();
+        points.append(&mut self.centroids);
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let total = points.iter().map(|p| p.1).sum::<f64>();
+        let scale =
+            |q: f64| self.compression / (2.0 * std::f64::consts::PI) * (2.0 * q - 1.0).asin();
+        let mut merged = Vec::new();
+        let mut before = 0.0;
+        let mut current = points[0];
+        for &(mean, weight) in &points[1..] {
+            let start = scale(before / total);
+            let end = scale(((before + current.1 + weight) / total).min(1.0));
+            if end - start <= 1.0 {
+                let combined = current.1 + weight;
+                current = (current.0 + (mean - current.0) * weight / combined, combined);
+            } else {
+                before += current.1;
+                merged.push(current);
+                current = (mean, weight);
+            }
+        }
+        merged.push(current);
+        self.centroids = merged;
+    }
+
+    /// Estimate the value at quantile q, between 0 and 1, by interpolating between the centres of
+    /// the centroids around it.
+    pub(crate) fn quantile(&mut self, q: f64) -> Option<f64> {
+        self.merge();
+        if self.centroids.is_empty() {
+            return None;
+        }
+        let total = self.centroids.iter().map(|c| c.1).sum::<f64>();
+        let target = q.clamp(0.0, 1.0) * total;
+
+        // Each centroid is taken to be centred on the middle of the weight it covers, with the
+        // extreme values at the very ends.
+        let mut previous = (self.min, 0.0);
+        let mut before = 0.0;
+        for &(mean, weight) in &self.centroids {
+            let centre = before + weight / 2.0;
+            if target < centre {
+                let fraction = (target - previous.1) / (centre - previous.1);
+                return Some(previous.0 + (mean - previous.0) * fraction);
+            }
+            previous = (mean, centre);
+            before += weight;
+        }
+        let fraction = (target - previous.1) / (total - previous.1).max(f64::MIN_POSITIVE);
+        Some(previous.0 + (self.max - previous.0) * fraction.min(1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hyperloglog_estimates() {
+        let mut hll = HyperLogLog::default();
+        assert_eq!(hll.estimate(), 0.0);
+
+        for i in 0..100_000 {
+            hll.insert(&format!("10.0.{}.{}", i / 256, i % 256));
+            // Duplicates do not change the estimate.
+            hll.insert(&format!("10.0.{}.{}", i / 256, i % 256));
+        }
+        let error = (hll.estimate() - 100_000.0).abs() / 100_000.0;
+        assert!(error < 0.03, "error too large: {}", error);
+    }
+
+    #[test]
+    fn space_saving_keeps_heavy_hitters() {
+        let mut ss = SpaceSaving::new(10);
+        for i in 0..10_000 {
+            ss.insert("/");
+            if i % 2 == 0 {
+                ss.insert("/api");
+            }
+            ss.insert(&format!("/rare/{}", i));
+        }
+
+        let top = ss.top(2);
+        assert_eq!(top[0].0, "/");
+        assert_eq!(top[1].0, "/api");
+        for (_, count, error) in top {
+            assert!(count >= error);
+        }
+        assert_eq!(ss.top(100).len(), 10);
+    }
+
+    #[test]
+    fn tdigest_quantiles() {
+        let mut digest = TDigest::default();
+        assert_eq!(digest.quantile(0.5), None);
+
+        // A skewed latency distribution, inserted out of order.
+        let mut values = (0..100_000)
+            .map(|i| ((i * 7919) % 100_000) as f64 / 100_000.0)
+            .map(|x| x * x)
+            .collect::<Vec<f64>>();
+        for v in &values {
+            digest.insert(*v);
+        }
+        assert!(digest.centroids.len() < 1000);
+        values.sort_by(f64::total_cmp);
+        for q in [0.5, 0.95, 0.99] {
+            let exact = values[(q * values.len() as f64) as usize];
+            let estimate = digest.quantile(q).unwrap();
+            assert!(
+                (estimate - exact).abs() < 0.01,
+                "q{}: {} vs {}",
+                q,
+                estimate,
+                exact
+            );
+        }
+        assert_eq!(digest.quantile(0.0), Some(0.0));
+        assert!(digest.quantile(1.0).unwrap() <= values[values.len() - 1]);
+
+        let mut single = TDigest::default();
+        single.insert(0.25);
+        assert_eq!(single.quantile(0.99), Some(0.25));
+    }
+}
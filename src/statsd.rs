@@ -0,0 +1,122 @@
+use std::net::UdpSocket;
+
+use anyhow::Result;
+use log::{debug, warn};
+use rusqlite::types::Value;
+
+use super::processor::Processor;
+use super::{BYTES_SENT, STATUS_TYPE};
+
+/// The fields that need to be stored to compute the metrics.
+pub(crate) const FIELDS: &[&str] = &[STATUS_TYPE, BYTES_SENT];
+
+// The percentiles of the request time that are sent.
+const PERCENTILES: &[u64] = &[50, 90, 99];
+
+/// Sends the metrics of the records that arrived since the previous interval to a StatsD server.
+pub(crate) struct Statsd<'a> {
+    socket: UdpSocket,
+    prefix: &'a str,
+    latency: bool,
+    last_row: i64,
+}
+
+impl<'a> Statsd<'a> {
+    /// Connect to the server at host:port, naming every metric after the prefix. The percentiles
+    /// of the request time are only sent when it is stored.
+    pub(crate) fn connect(addr: &str, prefix: &'a str, latency: bool) -> Result<Statsd<'a>> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        Ok(Statsd {
+            socket,
+            prefix,
+            latency,
+            last_row: 0,
+        })
+    }
+
+    /// Start again from the first record after the stored ones were removed, since SQLite numbers
+    /// the rows of an empty table from one again.
+    pub(crate) fn reset(&mut self) {
+        self.last_row = 0;
+    }
+
+    /// Send the request count and the count of each class of status as counters, since they add
+    /// up over the intervals, the average size as a gauge, and the request time percentiles as
+    /// timers in milliseconds. A failed send is only logged so that the next interval can try
+    /// again.
+    pub(crate) fn send(&mut self, processor: &Processor) -> Result<()> {
+        let query = format!(
+            "SELECT MAX(rowid), COUNT(1), AVG({bytes_sent}),
+COUNT(CASE WHEN {status_type} = 2 THEN 1 END),
+COUNT(CASE WHEN {status_type} = 3 THEN 1 END),
+COUNT(CASE WHEN {status_type} = 4 THEN 1 END),
+COUNT(CASE WHEN {status_type} = 5 THEN 1 END)
+FROM log
+WHERE rowid > {last_row}",
+            bytes_sent = BYTES_SENT,
+            status_type = STATUS_TYPE,
+            last_row = self.last_row
+        );
+        let result = processor.query(&query)?;
+        let row = match result.rows.first() {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        let first_row = self.last_row;
+        if let Value::Integer(last_row) = row[0] {
+            self.last_row = last_row;
+        }
+
+        let integer = |v: &Value| match v {
+            Value::Integer(i) => *i,
+            _ => 0,
+        };
+        let mut metrics = vec![format!("{}.requests:{}|c", self.prefix, integer(&row[1]))];
+        for (class, value) in (2..=5).zip(&row[3..]) {
+            metrics.push(format!(
+                "{}.status.{}xx:{}|c",
+                self.prefix,
+                class,
+                integer(value)
+            ));
+        }
+        if let Value::Real(avg) = row[2] {
+            metrics.push(format!("{}.bytes_sent.avg:{}|g", self.prefix, avg));
+        }
+
+        if self.latency {
+            let times = processor.query(&format!(
+                "SELECT request_time FROM log
+WHERE rowid > {} AND rowid <= {} AND request_time IS NOT NULL
+ORDER BY request_time",
+                first_row, self.last_row
+            ))?;
+            let times = times
+                .rows
+                .iter()
+                .filter_map(|r| match r[0] {
+                    Value::Real(t) => Some(t),
+                    Value::Integer(t) => Some(t as f64),
+                    _ => None,
+                })
+                .collect::<Vec<f64>>();
+            // Use the nearest rank method: the smallest time whose rank is at least p% of them.
+            for p in PERCENTILES.iter().filter(|_| !times.is_empty()) {
+                let rank = (times.len() as u64 * p).div_ceil(100).max(1);
+                metrics.push(format!(
+                    "{}.request_time.p{}:{}|ms",
+                    self.prefix,
+                    p,
+                    times[rank as usize - 1] * 1000.0
+                ));
+            }
+        }
+
+        debug!("sending statsd metrics: {:?}", metrics);
+        if let Err(e) = self.socket.send(metrics.join("\n").as_bytes()) {
+            warn!("sending the metrics to statsd failed: {}", e);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use log::{debug, info};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use super::input::lossy_line;
+
+// How long the sockets wait for data before checking whether they should stop.
+const POLL: Duration = Duration::from_millis(100);
+
+// An RFC 5424 header: `<PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA`.
+static RFC5424_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^<\d{1,3}>\d{1,2} \S+ (?P<host>\S+) \S+ \S+ \S+ (?:-|(?:\[(?:[^\]\\]|\\.)*\])+) ?\x{feff}?",
+    )
+    .unwrap()
+});
+
+// An RFC 3164 header such as `<190>Jun  6 23:16:43 web1 nginx: ` along with the variant using an
+// RFC 3339 timestamp that rsyslog writes to files. The priority is usually dropped when written
+// to a file.
+static RFC3164_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^(?:<\d{1,3}>)?(?:[A-Z][a-z]{2} [ \d]\d \d{2}:\d{2}:\d{2}|\d{4}-\d{2}-\d{2}T\S+) (?P<host>\S+) (?:[^\s:]+: )?",
+    )
+    .unwrap()
+});
+
+/// Split a line forwarded through syslog into the hostname that sent it and the original
+/// message. None is returned when the line does not start with a syslog header.
+pub(crate) fn strip_header(line: &str) -> Option<(&str, &str)> {
+    RFC5424_REGEX
+        .captures(line)
+        .or_else(|| RFC3164_REGEX.captures(line))
+        .map(|c| {
+            let header = c.get(0).unwrap();
+            (c.name("host").unwrap().as_str(), &line[header.end()..])
+        })
+}
+
+// Whether the receiving threads have been told to stop, which happens when the sending half of
+// the stop channel is dropped.
+fn stopped(stop_rx: &Receiver<()>) -> bool {
+    matches!(stop_rx.try_recv(), Err(TryRecvError::Disconnected))
+}
+
+/// Act as a syslog server on the given address, sending each message received over UDP or TCP
+/// along with the address it was received on. Messages sent over TCP are separated by newlines.
+pub(crate) fn listen(
+    addr: SocketAddr,
+    tx: Sender<(String, String)>,
+    stop_rx: Receiver<()>,
+) -> Result<Vec<thread::JoinHandle<Result<()>>>> {
+    let source = format!("syslog://{}", addr);
+    let udp = UdpSocket::bind(addr)?;
+    udp.set_read_timeout(Some(POLL))?;
+    let tcp = TcpListener::bind(addr)?;
+    tcp.set_nonblocking(true)?;
+    info!("listening for syslog messages on {}", addr);
+
+    let (udp_source, udp_tx, udp_stop_rx) = (source.clone(), tx.clone(), stop_rx.clone());
+    let udp_handle = thread::spawn(move || -> Result<()> {
+        let mut buf = vec![0; 65536];
+        while !stopped(&udp_stop_rx) {
+            let n = match udp.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            };
+            for line in String::from_utf8_lossy(&buf[..n]).lines() {
+                debug!("syslog received over udp: {}", line);
+                udp_tx.send((udp_source.clone(), line.to_string()))?;
+            }
+        }
+        Ok(())
+    });
+
+    let tcp_handle = thread::spawn(move || -> Result<()> {
+        let mut connections = Vec::new();
+        while !stopped(&stop_rx) {
+            match tcp.accept() {
+                Ok((stream, peer)) => {
+                    debug!("syslog connection from {}", peer);
+                    let (source, tx, stop_rx) = (source.clone(), tx.clone(), stop_rx.clone());
+                    connections.push(thread::spawn(move || {
+                        read_connection(stream, &source, &tx, &stop_rx)
+                    }));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        for connection in connections {
+            connection
+                .join()
+                .expect("the syslog connection thread should not have panicked")?;
+        }
+        Ok(())
+    });
+
+    Ok(vec![udp_handle, tcp_handle])
+}
+
+// Send each line received over a TCP connection until it is closed.
+fn read_connection(
+    stream: TcpStream,
+    source: &str,
+    tx: &Sender<(String, String)>,
+    stop_rx: &Receiver<()>,
+) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(POLL))?;
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+
+    while !stopped(stop_rx) {
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => {
+                let message = lossy_line(&line);
+                debug!("syslog received over tcp: {}", message);
+                tx.send((source.to_string(), message))?;
+                line.clear();
+            }
+            // A partial line stays in the buffer until the rest of it arrives.
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_are_stripped() {
+        let message = r#"172.17.0.1 - - [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 200 153 "-" "curl/7.54.0""#;
+
+        let line = format!("<190>Jun  6 23:16:43 web1 nginx: {}", message);
+        assert_eq!(strip_header(&line), Some(("web1", message)));
+
+        let line = format!("Jun 16 23:16:43 web2 nginx[123]: {}", message);
+        assert_eq!(strip_header(&line), Some(("web2", message)));
+
+        let line = format!("2020-06-06T23:16:43+00:00 web3 nginx: {}", message);
+        assert_eq!(strip_header(&line), Some(("web3", message)));
+
+        let line = format!(
+            "<190>1 2020-06-06T23:16:43.003Z web4 nginx 7 - [origin ip=\"10.0.0.1\"] {}",
+            message
+        );
+        assert_eq!(strip_header(&line), Some(("web4", message)));
+
+        let line = format!("<190>1 2020-06-06T23:16:43Z web5 nginx - - - {}", message);
+        assert_eq!(strip_header(&line), Some(("web5", message)));
+
+        assert_eq!(strip_header(message), None);
+    }
+}
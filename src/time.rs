@@ -0,0 +1,325 @@
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Days, FixedOffset, Local, NaiveDate, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use once_cell::sync::OnceCell;
+
+use super::nginx::TIME_LOCAL_FORMAT;
+
+// The zone given with --timezone. It is set once at startup.
+static ZONE: OnceCell<Zone> = OnceCell::new();
+
+/// A time zone that times are shown and bucketed in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Zone {
+    /// A fixed offset from UTC, which UTC itself is.
+    Fixed(FixedOffset),
+
+    /// The zone of this machine.
+    Local,
+
+    /// A zone of the IANA database such as America/New_York.
+    Named(Tz),
+}
+
+impl Zone {
+    /// The offset from UTC in this zone at the given Unix timestamp.
+    pub fn offset(&self, ts: i64) -> FixedOffset {
+        match self {
+            Zone::Fixed(offset) => *offset,
+            Zone::Local => DateTime::from_timestamp(ts, 0)
+                .map_or(Utc.fix(), |t| t.with_timezone(&Local).offset().fix()),
+            Zone::Named(tz) => DateTime::from_timestamp(ts, 0)
+                .map_or(Utc.fix(), |t| t.with_timezone(tz).offset().fix()),
+        }
+    }
+
+    /// The Unix timestamp of a wall clock time in this zone.
+    pub fn timestamp(&self, t: NaiveDateTime) -> i64 {
+        let wall = t.and_utc().timestamp();
+        match self {
+            Zone::Fixed(offset) => wall - offset.local_minus_utc() as i64,
+            // A time that is skipped when the clocks go forward is taken as the wall clock time
+            // in UTC.
+            Zone::Local => Local
+                .from_local_datetime(&t)
+                .earliest()
+                .map_or(wall, |t| t.timestamp()),
+            Zone::Named(tz) => tz
+                .from_local_datetime(&t)
+                .earliest()
+                .map_or(wall, |t| t.timestamp()),
+        }
+    }
+}
+
+impl FromStr for Zone {
+    type Err = anyhow::Error;
+
+    /// Parse UTC, local, an offset such as +05:30 or -0800 or the name of a zone.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.to_ascii_lowercase().as_str() {
+            "utc" | "gmt" | "z" => return Ok(Zone::Fixed(Utc.fix())),
+            "local" => return Ok(Zone::Local),
+            _ => {}
+        }
+
+        if s.starts_with(['+', '-']) {
+            // The hours and optionally the minutes, with or without a colon between them.
+            let digits = s[1..].replacen(':', "", 1);
+            let valid = (digits.len() == 2 || digits.len() == 4)
+                && digits.chars().all(|c| c.is_ascii_digit());
+            let seconds = if valid {
+                let minutes = digits.get(2..).and_then(|m| m.parse::<i32>().ok());
+                digits[..2].parse::<i32>().unwrap_or(0) * 3600 + minutes.unwrap_or(0) * 60
+            } else {
+                i32::MAX
+            };
+            let seconds = if s.starts_with('-') {
+                -seconds
+            } else {
+                seconds
+            };
+            return FixedOffset::east_opt(seconds)
+                .map(Zone::Fixed)
+                .ok_or_else(|| anyhow!("invalid offset {}, expected one like +05:30", s));
+        }
+
+        match s.parse::<Tz>() {
+            Ok(tz) => Ok(Zone::Named(tz)),
+            Err(_) => Err(anyhow!(
+                "unknown time zone {}, expected UTC, local, an offset like +05:30 or a name like \
+                 America/New_York",
+                s
+            )),
+        }
+    }
+}
+
+/// Show and bucket every time in the given zone from now on.
+pub(crate) fn set_zone(zone: Zone) {
+    let _ = ZONE.set(zone);
+}
+
+/// The number of seconds to add to a Unix timestamp to get the wall clock time in the zone given
+/// with --timezone, which is UTC when there is none.
+pub(crate) fn utc_offset(ts: i64) -> i32 {
+    ZONE.get().map_or(0, |z| z.offset(ts).local_minus_utc())
+}
+
+/// The current time in the zone given with --timezone, or in the zone of this machine when there is
+/// none.
+pub(crate) fn now() -> DateTime<FixedOffset> {
+    match ZONE.get() {
+        Some(zone) => {
+            let now = Utc::now();
+            now.with_timezone(&zone.offset(now.timestamp()))
+        }
+        None => Local::now().fixed_offset(),
+    }
+}
+
+/// The Unix timestamp of a wall clock time in the zone given with --timezone, or in the zone of
+/// this machine when there is none.
+pub(crate) fn local_timestamp(t: NaiveDateTime) -> i64 {
+    ZONE.get().unwrap_or(&Zone::Local).timestamp(t)
+}
+
+/// Rewrite a $time_local or $time_iso8601 value in the given zone, keeping its layout. Values that
+/// cannot be parsed are kept as they are.
+pub(crate) fn convert(zone: &Zone, variable: &str, value: &str) -> String {
+    let converted = match variable {
+        "time_iso8601" => DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(|t| t.with_timezone(&zone.offset(t.timestamp())).to_rfc3339()),
+        _ => DateTime::parse_from_str(value, TIME_LOCAL_FORMAT)
+            .ok()
+            .map(|t| {
+                t.with_timezone(&zone.offset(t.timestamp()))
+                    .format(TIME_LOCAL_FORMAT)
+                    .to_string()
+            }),
+    };
+    converted.unwrap_or_else(|| value.to_string())
+}
+
+/// A point in time given on the command line. It is only turned into a timestamp once the zone
+/// given with --timezone is known, since the days and wall clock times are in that zone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Time {
+    /// This long before now.
+    Ago(Duration),
+
+    /// The midnight this many days before the coming one.
+    DaysAgo(u64),
+
+    /// A wall clock time without an offset.
+    Wall(NaiveDateTime),
+
+    /// A Unix timestamp.
+    At(i64),
+}
+
+impl FromStr for Time {
+    type Err = anyhow::Error;
+
+    /// Parse a duration like 30m or 2h which is taken relative to now, one of now, today or
+    /// yesterday, or an absolute time as RFC 3339, in the $time_local layout or as
+    /// `YYYY-MM-DD HH:MM:SS` or `YYYY-MM-DD`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(d) = humantime::parse_duration(s) {
+            return Ok(Time::Ago(d));
+        }
+        match s.to_ascii_lowercase().as_str() {
+            "now" => return Ok(Time::Ago(Duration::ZERO)),
+            "today" => return Ok(Time::DaysAgo(0)),
+            "yesterday" => return Ok(Time::DaysAgo(1)),
+            _ => {}
+        }
+
+        if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Time::At(t.timestamp()));
+        }
+        if let Ok(t) = DateTime::parse_from_str(s, TIME_LOCAL_FORMAT) {
+            return Ok(Time::At(t.timestamp()));
+        }
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S") {
+            return Ok(Time::Wall(t));
+        }
+        if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            return Ok(Time::Wall(d.and_hms_opt(0, 0, 0).unwrap()));
+        }
+
+        Err(anyhow!("unrecognized time: {}", s))
+    }
+}
+
+impl Time {
+    /// The Unix timestamp of this time, with the days and wall clock times in the zone given with
+    /// --timezone or in UTC when there is none.
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp_in(ZONE.get())
+    }
+
+    fn timestamp_in(&self, zone: Option<&Zone>) -> i64 {
+        let utc = Zone::Fixed(Utc.fix());
+        let zone = zone.unwrap_or(&utc);
+        match *self {
+            Time::Ago(d) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                now.saturating_sub(d).as_secs() as i64
+            }
+            Time::DaysAgo(days) => {
+                let now = Utc::now();
+                let today = now
+                    .with_timezone(&zone.offset(now.timestamp()))
+                    .date_naive();
+                let day = today.checked_sub_days(Days::new(days)).unwrap_or(today);
+                zone.timestamp(day.and_hms_opt(0, 0, 0).unwrap())
+            }
+            Time::Wall(t) => zone.timestamp(t),
+            Time::At(ts) => ts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(s: &str) -> Result<i64> {
+        s.parse::<Time>().map(|t| t.timestamp_in(None))
+    }
+
+    #[test]
+    fn absolute_times() {
+        assert_eq!(timestamp("2020-06-06T23:16:43Z").unwrap(), 1591485403);
+        assert_eq!(timestamp("06/Jun/2020:16:16:43 -0700").unwrap(), 1591485403);
+        assert_eq!(timestamp("2020-06-06 23:16:43").unwrap(), 1591485403);
+        assert_eq!(timestamp("2020-06-06").unwrap(), 1591401600);
+        assert!(timestamp("yesterday-ish").is_err());
+    }
+
+    #[test]
+    fn zones() {
+        assert_eq!("UTC".parse::<Zone>().unwrap(), Zone::Fixed(Utc.fix()));
+        assert_eq!("local".parse::<Zone>().unwrap(), Zone::Local);
+        let ist = FixedOffset::east_opt(5 * 3600 + 1800).unwrap();
+        assert_eq!("+05:30".parse::<Zone>().unwrap(), Zone::Fixed(ist));
+        assert_eq!("+0530".parse::<Zone>().unwrap(), Zone::Fixed(ist));
+        assert_eq!(
+            "-08".parse::<Zone>().unwrap(),
+            Zone::Fixed(FixedOffset::west_opt(8 * 3600).unwrap())
+        );
+        assert!("+5:3".parse::<Zone>().is_err());
+        assert!("../../etc/passwd".parse::<Zone>().is_err());
+        assert!("Mars/Olympus_Mons".parse::<Zone>().is_err());
+
+        let zone = Zone::Fixed(ist);
+        assert_eq!(
+            convert(&zone, "time_local", "06/Jun/2020:16:16:43 -0700"),
+            "07/Jun/2020:04:46:43 +0530"
+        );
+        assert_eq!(
+            convert(&zone, "time_iso8601", "2020-06-06T23:16:43+00:00"),
+            "2020-06-07T04:46:43+05:30"
+        );
+        assert_eq!(convert(&zone, "time_local", "-"), "-");
+    }
+
+    #[test]
+    fn relative_times() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let t = timestamp("30m").unwrap();
+        assert!((now - 1800 - t).abs() <= 1);
+        assert!((now - timestamp("now").unwrap()).abs() <= 1);
+
+        let today = timestamp("today").unwrap();
+        assert_eq!(today % 86400, 0);
+        assert!(today <= now && now - today < 86400);
+        assert_eq!(timestamp("Yesterday").unwrap(), today - 86400);
+
+        // The days start at midnight in the zone given with --timezone.
+        let ist = Zone::Fixed(FixedOffset::east_opt(5 * 3600 + 1800).unwrap());
+        let today = Time::DaysAgo(0).timestamp_in(Some(&ist));
+        assert_eq!((today + 5 * 3600 + 1800) % 86400, 0);
+        assert!(today <= now && now - today < 86400);
+        assert_eq!(Time::DaysAgo(1).timestamp_in(Some(&ist)), today - 86400);
+        let wall = "2020-06-06 23:16:43".parse::<Time>().unwrap();
+        assert_eq!(wall.timestamp_in(Some(&ist)), 1591485403 - 5 * 3600 - 1800);
+    }
+
+    #[test]
+    fn named_zones() {
+        let zone = "America/New_York".parse::<Zone>().unwrap();
+        let hours = |ts| zone.offset(ts).local_minus_utc() / 3600;
+        assert_eq!(hours(1591485403), -4); // 2020-06-06
+        assert_eq!(hours(1577836800), -5); // 2020-01-01
+        assert_eq!(hours(4086547200), -4); // 2099-07-01
+
+        let t = NaiveDate::from_ymd_opt(2020, 6, 6)
+            .unwrap()
+            .and_hms_opt(19, 16, 43)
+            .unwrap();
+        assert_eq!(zone.timestamp(t), 1591485403);
+        // 02:30 was skipped on 2020-03-08 when the clocks went forward.
+        let skipped = NaiveDate::from_ymd_opt(2020, 3, 8)
+            .unwrap()
+            .and_hms_opt(2, 30, 0)
+            .unwrap();
+        assert_eq!(zone.timestamp(skipped), 1583634600);
+        assert_eq!(
+            convert(&zone, "time_iso8601", "2020-06-06T23:16:43+00:00"),
+            "2020-06-06T19:16:43-04:00"
+        );
+    }
+}
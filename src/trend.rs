@@ -0,0 +1,76 @@
+use std::collections::{HashMap, VecDeque};
+
+use rusqlite::types::Value;
+
+use super::processor::QueryResult;
+
+/// The name of the column holding the sparkline of each group.
+pub(crate) const TREND: &str = "trend";
+
+// How many intervals of history are drawn.
+const WIDTH: usize = 10;
+
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Remembers how many requests each group of the detailed query got during the last few
+/// intervals while tailing so that they can be drawn as a sparkline.
+#[derive(Default)]
+pub(crate) struct Trends {
+    group_by: String,
+    counts: HashMap<String, i64>,
+    history: HashMap<String, VecDeque<u64>>,
+}
+
+impl Trends {
+    /// Add a trend column to the result, first recording the latest counts of its groups when a
+    /// new interval has passed. The first column of the result is the group and it must have a
+    /// count column.
+    pub(crate) fn add_column(&mut self, result: &mut QueryResult, record: bool) {
+        let count = match result.columns.iter().position(|c| c == "count") {
+            Some(i) => i,
+            None => return,
+        };
+        // Start over when the groups change, such as when regrouping in the dashboard.
+        let group_by = result.columns.first().cloned().unwrap_or_default();
+        if group_by != self.group_by {
+            self.group_by = group_by;
+            self.counts.clear();
+            self.history.clear();
+        }
+
+        result.columns.push(String::from(TREND));
+        for row in &mut result.rows {
+            let group = match &row[0] {
+                Value::Text(t) => t.clone(),
+                Value::Integer(i) => i.to_string(),
+                Value::Real(r) => r.to_string(),
+                _ => String::new(),
+            };
+            let total = match row[count] {
+                Value::Integer(c) => c,
+                _ => 0,
+            };
+
+            // The counts only ever cover everything seen so far, so the change since the last
+            // interval is what was added. It can shrink when records fall out of the window.
+            let history = self.history.entry(group.clone()).or_default();
+            if record {
+                if let Some(previous) = self.counts.insert(group, total) {
+                    if history.len() == WIDTH {
+                        history.pop_front();
+                    }
+                    history.push_back(total.saturating_sub(previous).max(0) as u64);
+                }
+            }
+            row.push(Value::Text(sparkline(history)));
+        }
+    }
+}
+
+fn sparkline(history: &VecDeque<u64>) -> String {
+    let max = history.iter().copied().max().unwrap_or(0).max(1);
+    history
+        .iter()
+        .map(|&n| BARS[(n * (BARS.len() as u64 - 1) / max) as usize])
+        .collect()
+}
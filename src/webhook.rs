@@ -0,0 +1,47 @@
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde_json::json;
+
+use super::processor::{format_value, QueryResult};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Post the triggered alerts along with the groups that had the most requests to a webhook. The
+/// payload has a text field so that it can be sent to a Slack incoming webhook as is. The request
+/// is made on its own thread so that a slow endpoint does not hold up tailing, and a failure is
+/// only logged.
+pub(crate) fn notify(
+    url: &str,
+    alerts: &[String],
+    offenders: &QueryResult,
+) -> thread::JoinHandle<()> {
+    let mut text = alerts.join("\n");
+    if !offenders.rows.is_empty() {
+        text.push_str("\ntop offenders:");
+        for row in &offenders.rows {
+            let columns = offenders
+                .columns
+                .iter()
+                .zip(row)
+                .map(|(c, v)| format!("{}: {}", c, format_value(v)))
+                .collect::<Vec<String>>();
+            text.push_str(&format!("\n{}", columns.join(", ")));
+        }
+    }
+    let payload = json!({
+        "text": text,
+        "alerts": alerts,
+        "offenders": offenders.to_json(),
+    });
+
+    let url = url.to_string();
+    thread::spawn(move || {
+        debug!("posting alert to {}", url);
+        let response = ureq::post(&url).timeout(TIMEOUT).send_json(payload);
+        if let Err(e) = response {
+            warn!("sending the alert to {} failed: {}", url, e);
+        }
+    })
+}